@@ -0,0 +1,483 @@
+mod parts;
+mod playlist;
+mod thumbnail;
+
+pub use playlist::Playlists;
+pub use thumbnail::create_thumbnail;
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, ensure};
+use chrono::Utc;
+use reqwest::Url;
+use tokio::{io::AsyncWriteExt, sync::watch};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    download::{self, DownloadOptions, download},
+    ffmpeg::SplitLimits,
+    info::{DownloadInfo, Sidecar, write_sidecar},
+    state::{self, JobState},
+    template::{TemplateValues, Templates},
+    twitch::{
+        cdn::{is_muted_segment, muted_ranges},
+        structs::{ClipInfo, VideoInfo},
+    },
+    util::format_timestamp,
+    youtube::{
+        UploadOptions, UploadedVideo, VideoDetail, VideoMetadata, VideoSource, sanitize_tags,
+        set_thumbnail, upload_video,
+    },
+};
+
+/// Amount of streamed video data waiting to be uploaded to YouTube when using `--stream-upload`
+const STREAM_UPLOAD_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// Options of the `archive` / `monitor` commands
+pub struct ArchiveOptions {
+    pub download: DownloadOptions,
+    pub stream_upload: bool,
+    pub cleanup: bool,
+    pub metadata: VideoMetadata,
+    pub templates: Templates,
+    pub upload: UploadOptions,
+    /// Videos exceeding these limits are split into multiple uploads
+    pub split: SplitLimits,
+    pub playlists: Playlists,
+    /// Where `<VOD_ID>.info.json` / `<CLIP_SLUG>.info.json` sidecar files are saved
+    pub info_dir: PathBuf,
+}
+
+/// Downloads a VOD and uploads it to YouTube
+///
+/// Returns every uploaded video, which are multiple parts when the VOD had to be split. Nothing
+/// is returned when cancelled before the first upload is finished.
+///
+/// With [`ArchiveOptions::stream_upload`], segments are uploaded while they are being downloaded
+/// instead of going through the disk first
+///
+/// Every step is recorded into [`DownloadOptions::state`], including failures. The VOD is claimed
+/// first, and skipped when another process is archiving it already.
+///
+/// # Errors
+/// Errors when the download, splitting or upload fails
+///
+/// # Panics
+/// Will panic if the downloaded video has no parent directory
+pub async fn download_and_archive(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    temp_download_dir: &Path,
+    options: &ArchiveOptions,
+    video_info: &VideoInfo,
+    vod_id: u64,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+) -> Result<Vec<UploadedVideo>> {
+    let store = options.download.state.as_deref();
+    let _claim = match store {
+        Some(store) => {
+            let Some(claim) = store.claim(vod_id).await? else {
+                info!("VOD {vod_id} is already archived or being archived, skipping it");
+                return Ok(Vec::new());
+            };
+            Some(claim)
+        }
+        None => None,
+    };
+    let result = if options.stream_upload {
+        stream_archive(ct, client, options, video_info, vod_id, access_token)
+            .await
+            .map(|u| u.into_iter().collect())
+    } else {
+        download_and_archive_file(
+            ct,
+            client,
+            temp_download_dir,
+            options,
+            video_info,
+            vod_id,
+            access_token,
+        )
+        .await
+    };
+    let uploaded = match result {
+        Ok(uploaded) => uploaded,
+        Err(e) => {
+            let failed = JobState::Failed {
+                error: format!("{e:#}"),
+            };
+            if let Err(e) = state::record(store, vod_id, failed).await {
+                warn!("Unable to record the failure: {e:#}");
+            }
+            return Err(e);
+        }
+    };
+
+    // Parts uploaded before a cancellation are added once every part is uploaded
+    if !uploaded.is_empty() && !ct.is_cancelled() {
+        let youtube_ids = uploaded.iter().map(|v| v.id.clone()).collect();
+        state::record(store, vod_id, JobState::Uploaded { youtube_ids }).await?;
+
+        playlist::add_to_playlists(client, access_token, video_info, &uploaded, options).await;
+    }
+
+    Ok(uploaded)
+}
+
+/// Downloads a clip and uploads it to YouTube
+///
+/// Clips are always downloaded to disk first and are not recorded into the archive history
+///
+/// # Errors
+/// Errors when the download or upload fails
+///
+/// # Panics
+/// Will panic if the downloaded clip has no parent directory
+pub async fn archive_clip(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    temp_download_dir: &Path,
+    options: &ArchiveOptions,
+    clip: &ClipInfo,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+) -> Result<Vec<UploadedVideo>> {
+    let video_info = clip.to_video_info();
+    let video = download::download_clip(
+        ct.clone(),
+        client.clone(),
+        temp_download_dir,
+        &options.download,
+        clip,
+    )
+    .await?;
+
+    if ct.is_cancelled() {
+        return Ok(Vec::new());
+    }
+
+    let thumbnail = create_thumbnail(
+        &video_info,
+        video.path.as_os_str(),
+        Duration::from_secs(video_info.length_seconds),
+        &options.metadata,
+    )
+    .await;
+    let uploaded = parts::archive_file(
+        ct,
+        client,
+        &video_info,
+        &video,
+        thumbnail.as_deref(),
+        access_token,
+        options,
+    )
+    .await?;
+
+    if !uploaded.is_empty() && !ct.is_cancelled() {
+        playlist::add_to_playlists(client, access_token, &video_info, &uploaded, options).await;
+        save_sidecar(
+            options,
+            &video_info.id,
+            Sidecar {
+                video: &video_info,
+                download: &video.info,
+                chapters: &video.chapters,
+                muted_ranges: &video.muted_ranges,
+                youtube: &uploaded,
+                thumbnail: thumbnail.as_deref(),
+            },
+        )
+        .await;
+    }
+
+    if options.cleanup && !ct.is_cancelled() {
+        info!("Cleaning up processing remnants");
+        tokio::fs::remove_dir_all(video.path.parent().unwrap())
+            .await
+            .context("Cleaning up processing remnants")?;
+    }
+
+    Ok(uploaded)
+}
+
+/// Downloads a VOD to disk, then uploads it as one or multiple parts
+async fn download_and_archive_file(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    temp_download_dir: &Path,
+    options: &ArchiveOptions,
+    video_info: &VideoInfo,
+    vod_id: u64,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+) -> Result<Vec<UploadedVideo>> {
+    let video = download(
+        ct.clone(),
+        client.clone(),
+        temp_download_dir,
+        &options.download,
+        vod_id,
+    )
+    .await?;
+
+    if ct.is_cancelled() {
+        return Ok(Vec::new());
+    }
+
+    info!("Final file path: {:?}", video.path);
+    state::record(
+        options.download.state.as_deref(),
+        vod_id,
+        JobState::Uploading,
+    )
+    .await?;
+
+    let thumbnail = create_thumbnail(
+        video_info,
+        video.path.as_os_str(),
+        Duration::from_secs(video_info.length_seconds),
+        &options.metadata,
+    )
+    .await;
+    let uploaded = parts::archive_file(
+        ct,
+        client,
+        video_info,
+        &video,
+        thumbnail.as_deref(),
+        access_token,
+        options,
+    )
+    .await?;
+
+    if !ct.is_cancelled() {
+        save_sidecar(
+            options,
+            &video_info.id,
+            Sidecar {
+                video: video_info,
+                download: &video.info,
+                chapters: &video.chapters,
+                muted_ranges: &video.muted_ranges,
+                youtube: &uploaded,
+                thumbnail: thumbnail.as_deref(),
+            },
+        )
+        .await;
+    }
+
+    // Keep the video and its upload session around so the upload can be resumed
+    if options.cleanup && !ct.is_cancelled() {
+        info!("Cleaning up processing remnants");
+        tokio::fs::remove_dir_all(video.path.parent().unwrap())
+            .await
+            .context("Cleaning up processing remnants")?;
+    }
+
+    Ok(uploaded)
+}
+
+/// Pipes VOD segments straight from Twitch into a YouTube upload
+///
+/// Nothing touches the disk, so only [`STREAM_UPLOAD_BUFFER_SIZE`] and the segments being
+/// downloaded in parallel are kept in memory
+async fn stream_archive(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    options: &ArchiveOptions,
+    video_info: &VideoInfo,
+    vod_id: u64,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+) -> Result<Option<UploadedVideo>> {
+    // Splitting needs the whole video on disk
+    ensure!(
+        Duration::from_secs(video_info.length_seconds) <= options.split.max_duration,
+        "VOD is longer than the maximum part duration of {} and cannot be split when using `--stream-upload`",
+        format_timestamp(options.split.max_duration)
+    );
+
+    let started_at = Utc::now();
+    let (variant, media) = download::fetch_media(vod_id, &options.download).await?;
+    let media_url = Url::from_str(&variant.uri).context("Parsing VOD media URL")?;
+
+    // The description is sent before any segment is downloaded,
+    // so sections that might get unmuted are still listed
+    let muted_ranges = muted_ranges(media.segments.iter().map(|s| (s, is_muted_segment(s))));
+    let chapters = download::fetch_chapters(vod_id).await;
+    let values = TemplateValues::new(video_info, &chapters, &muted_ranges);
+    let detail = ArchiveDetail::new(video_info, &values, options);
+    // ffmpeg reads the frame straight from the media playlist
+    let thumbnail = thumbnail::create_thumbnail(
+        video_info,
+        media_url.as_str().as_ref(),
+        Duration::from_secs(video_info.length_seconds),
+        &options.metadata,
+    )
+    .await;
+
+    state::record(
+        options.download.state.as_deref(),
+        vod_id,
+        JobState::Uploading,
+    )
+    .await?;
+
+    let (mut writer, reader) = tokio::io::duplex(STREAM_UPLOAD_BUFFER_SIZE);
+    // Cancelled when the download fails so that a partial video is not finalized on YouTube
+    let upload_ct = ct.child_token();
+
+    let stream = async {
+        let result = download::stream_segments(
+            ct,
+            client,
+            &options.download,
+            &media_url,
+            &media.segments,
+            &mut writer,
+        )
+        .await;
+
+        match result {
+            Ok(streamed) if !ct.is_cancelled() => writer
+                .shutdown()
+                .await
+                .context("Finishing the streamed video")
+                .map(|()| streamed),
+            Ok(streamed) => Ok(streamed),
+            Err(e) => {
+                upload_ct.cancel();
+                Err(e)
+            }
+        }
+    };
+    let upload = archive(
+        upload_ct.clone(),
+        client.clone(),
+        detail.video_detail(&options.metadata),
+        VideoSource::Stream(Box::new(reader)),
+        thumbnail.as_deref(),
+        access_token,
+        options,
+    );
+
+    let (streamed, uploaded) = tokio::join!(stream, upload);
+    let streamed = streamed.context("Streaming VOD segments")?;
+    let uploaded = uploaded?;
+
+    if let Some(video) = &uploaded
+        && !ct.is_cancelled()
+    {
+        let download = DownloadInfo::new(
+            &variant,
+            media
+                .segments
+                .iter()
+                .zip(streamed)
+                .map(|(segment, s)| (segment, s.muted, s.unmuted)),
+            started_at,
+        );
+        save_sidecar(
+            options,
+            &video_info.id,
+            Sidecar {
+                video: video_info,
+                download: &download,
+                chapters: &chapters,
+                muted_ranges: &muted_ranges,
+                youtube: std::slice::from_ref(video),
+                thumbnail: thumbnail.as_deref(),
+            },
+        )
+        .await;
+    }
+
+    Ok(uploaded)
+}
+
+/// Saves the `.info.json` sidecar of an archived VOD into [`ArchiveOptions::info_dir`]
+///
+/// Failures are only logged as the video is already archived
+async fn save_sidecar(options: &ArchiveOptions, id: &str, sidecar: Sidecar<'_>) {
+    let base = options.info_dir.join(id);
+    if let Err(e) = write_sidecar(&base, sidecar).await {
+        warn!("Unable to save video info: {e:#}");
+    }
+}
+
+/// Title, description and tags of an archived video
+struct ArchiveDetail {
+    title: String,
+    description: String,
+    tags: Vec<String>,
+}
+
+impl ArchiveDetail {
+    fn new(video_info: &VideoInfo, values: &TemplateValues, options: &ArchiveOptions) -> Self {
+        let (title_template, description_template) =
+            options.templates.for_channel(&video_info.owner.login);
+
+        let mut tags = options.metadata.tags.clone();
+        if options.metadata.auto_tags.unwrap_or(true) {
+            tags.extend([
+                video_info.owner.display_name.clone(),
+                video_info.owner.login.clone(),
+            ]);
+            tags.extend(video_info.game.as_ref().map(|g| g.display_name.clone()));
+        }
+
+        Self {
+            title: title_template.render_title(values),
+            description: description_template.render(values),
+            tags: sanitize_tags(tags),
+        }
+    }
+
+    fn video_detail<'a>(&'a self, metadata: &'a VideoMetadata) -> VideoDetail<'a> {
+        VideoDetail {
+            title: &self.title,
+            description: &self.description,
+            tags: &self.tags,
+            metadata,
+        }
+    }
+}
+
+/// Uploads a video to YouTube, then sets its thumbnail
+async fn archive(
+    ct: CancellationToken,
+    client: reqwest::Client,
+    video_detail: VideoDetail<'_>,
+    video: VideoSource,
+    thumbnail: Option<&[u8]>,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    options: &ArchiveOptions,
+) -> Result<Option<UploadedVideo>> {
+    info!("Uploading video to Youtube...");
+    let uploaded = upload_video(
+        ct,
+        client.clone(),
+        access_token,
+        video_detail,
+        video,
+        &options.upload,
+    )
+    .await?;
+    let Some(video) = &uploaded else {
+        return Ok(None);
+    };
+    info!("Video successfully uploaded: {}", video.url());
+
+    // The video itself is already uploaded, so a missing thumbnail is not worth failing over
+    if let Some(thumbnail) = thumbnail {
+        match set_thumbnail(&client, access_token, video, thumbnail.to_vec()).await {
+            Ok(()) => info!("Video thumbnail set"),
+            Err(e) => warn!("Unable to set the video thumbnail: {e:#}"),
+        }
+    }
+
+    Ok(uploaded)
+}
@@ -0,0 +1,328 @@
+use std::{
+    fmt::Write as _,
+    fs::Metadata,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use super::{ArchiveDetail, ArchiveOptions, archive};
+use crate::{
+    download::DownloadedVideo,
+    ffmpeg::{self, SplitLimits},
+    template::TemplateValues,
+    twitch::structs::{Chapter, VideoInfo},
+    util::format_timestamp,
+    youtube::{UploadedVideo, VideoSource, update_video},
+};
+
+const PARTS_DIR_NAME: &str = "parts";
+const PARTS_MANIFEST_FILE_NAME: &str = "parts.json";
+
+/// Parts a video has been split into and their uploads, stored in the parts directory
+///
+/// Lets a restarted archive skip splitting and the parts which are already uploaded
+#[derive(Debug, Serialize, Deserialize)]
+struct PartsManifest {
+    /// Length of the split video, so a re-created video is split again
+    source_length: u64,
+    /// Modification time of the split video
+    source_modified: SystemTime,
+    parts: Vec<PartEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PartEntry {
+    file_name: String,
+    duration: Duration,
+    uploaded: Option<UploadedVideo>,
+}
+
+/// Uploads a downloaded video, first splitting it into parts when it exceeds
+/// [`ArchiveOptions::split`]
+///
+/// Parts are uploaded one after another as "Part N/M", each linking to the others in its
+/// description. Every part uses the same `thumbnail`.
+pub(super) async fn archive_file(
+    ct: &CancellationToken,
+    client: &Client,
+    video_info: &VideoInfo,
+    video: &DownloadedVideo,
+    thumbnail: Option<&[u8]>,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    options: &ArchiveOptions,
+) -> Result<Vec<UploadedVideo>> {
+    let metadata = tokio::fs::metadata(&video.path)
+        .await
+        .context("Reading video metadata")?;
+    let duration = ffmpeg::probe_duration(&video.path).await?;
+    let part_count = options.split.part_count(duration, metadata.len());
+    let values = TemplateValues::new(video_info, &video.chapters, &video.muted_ranges);
+
+    if part_count == 1 {
+        let detail = ArchiveDetail::new(video_info, &values, options);
+        let uploaded = archive(
+            ct.clone(),
+            client.clone(),
+            detail.video_detail(&options.metadata),
+            VideoSource::File(video.path.clone()),
+            thumbnail,
+            access_token,
+            options,
+        )
+        .await?;
+        return Ok(uploaded.into_iter().collect());
+    }
+
+    info!(
+        "Video is {} long and {}, which is over YouTube's upload limits",
+        format_timestamp(duration),
+        indicatif::DecimalBytes(metadata.len())
+    );
+    let parts_dir = video.path.with_file_name(PARTS_DIR_NAME);
+    let mut manifest = split(
+        &video.path,
+        &parts_dir,
+        &metadata,
+        duration,
+        part_count,
+        options.split,
+    )
+    .await?;
+    let part_count = manifest.parts.len();
+
+    for part in 0..part_count {
+        if manifest.parts[part].uploaded.is_some() {
+            info!("Part {}/{part_count} is already uploaded", part + 1);
+            continue;
+        }
+
+        let values = part_values(&values, &manifest, part);
+        let mut detail = ArchiveDetail::new(video_info, &values, options);
+        detail.description = with_part_links(&detail.description, &manifest.parts, part);
+
+        info!("Uploading part {}/{part_count}", part + 1);
+        let uploaded = archive(
+            ct.clone(),
+            client.clone(),
+            detail.video_detail(&options.metadata),
+            VideoSource::File(parts_dir.join(&manifest.parts[part].file_name)),
+            thumbnail,
+            access_token,
+            options,
+        )
+        .await?;
+        let Some(uploaded) = uploaded else {
+            break;
+        };
+
+        manifest.parts[part].uploaded = Some(uploaded);
+        manifest.save(&parts_dir).await?;
+    }
+
+    if manifest.parts.iter().all(|p| p.uploaded.is_some()) {
+        link_parts(
+            client,
+            access_token,
+            video_info,
+            &values,
+            &manifest,
+            options,
+        )
+        .await;
+    }
+
+    Ok(manifest
+        .parts
+        .into_iter()
+        .filter_map(|p| p.uploaded)
+        .collect())
+}
+
+/// Splits a video into parts inside of `parts_dir`, reusing the parts of a previous run when the
+/// video has not changed since
+async fn split(
+    video: &Path,
+    parts_dir: &Path,
+    metadata: &Metadata,
+    duration: Duration,
+    mut part_count: usize,
+    limits: SplitLimits,
+) -> Result<PartsManifest> {
+    let source_modified = metadata
+        .modified()
+        .context("Reading video modification time")?;
+    let previous = PartsManifest::load(parts_dir)
+        .await
+        .filter(|m| m.source_length == metadata.len() && m.source_modified == source_modified);
+    if let Some(manifest) = previous {
+        info!(
+            "Video has already been split into {} parts",
+            manifest.parts.len()
+        );
+        return Ok(manifest);
+    }
+
+    loop {
+        info!("Splitting video into {part_count} parts");
+        // Leftover parts of a previous split would be mixed in otherwise
+        match tokio::fs::remove_dir_all(parts_dir).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Removing previously split parts"),
+        }
+        tokio::fs::create_dir_all(parts_dir)
+            .await
+            .context("Creating parts directory")?;
+
+        let parts = ffmpeg::split_video(video, parts_dir, duration, part_count).await?;
+
+        // Parts are cut by duration, so parts with a higher bitrate can still be too large
+        if let Some(oversized) = find_oversized(&parts, limits.max_size).await? {
+            warn!(
+                "{} is over the maximum part size, splitting into more parts",
+                oversized.display()
+            );
+            part_count += 1;
+            continue;
+        }
+
+        let mut entries = Vec::with_capacity(parts.len());
+        for part in &parts {
+            entries.push(PartEntry {
+                file_name: part.file_name().unwrap().to_string_lossy().into_owned(),
+                duration: ffmpeg::probe_duration(part).await?,
+                uploaded: None,
+            });
+        }
+
+        let manifest = PartsManifest {
+            source_length: metadata.len(),
+            source_modified,
+            parts: entries,
+        };
+        manifest.save(parts_dir).await?;
+        return Ok(manifest);
+    }
+}
+
+async fn find_oversized(parts: &[PathBuf], max_size: u64) -> Result<Option<&PathBuf>> {
+    for part in parts {
+        let size = tokio::fs::metadata(part)
+            .await
+            .context("Reading part metadata")?
+            .len();
+        if size > max_size {
+            return Ok(Some(part));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Updates the description of every part to link to all of the other parts
+///
+/// Parts are uploaded before the links to the following parts are known, so only the last part
+/// links to everything when it is uploaded. Failures are only logged as the parts themselves are
+/// already uploaded.
+async fn link_parts(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    video_info: &VideoInfo,
+    values: &TemplateValues,
+    manifest: &PartsManifest,
+    options: &ArchiveOptions,
+) {
+    let part_count = manifest.parts.len();
+    for (part, entry) in manifest.parts.iter().enumerate().take(part_count - 1) {
+        let Some(uploaded) = &entry.uploaded else {
+            continue;
+        };
+
+        let values = part_values(values, manifest, part);
+        let mut detail = ArchiveDetail::new(video_info, &values, options);
+        detail.description = with_part_links(&detail.description, &manifest.parts, part);
+
+        if let Err(e) = update_video(
+            client,
+            access_token,
+            uploaded,
+            detail.video_detail(&options.metadata),
+        )
+        .await
+        {
+            warn!(
+                "Unable to link the other parts in the description of part {}/{part_count}: {e:#}",
+                part + 1
+            );
+        }
+    }
+}
+
+/// Template values of a single part, with its chapters timed from the start of the part
+fn part_values(values: &TemplateValues, manifest: &PartsManifest, part: usize) -> TemplateValues {
+    let start = manifest.parts[..part]
+        .iter()
+        .map(|p| p.duration)
+        .sum::<Duration>();
+    let end = start + manifest.parts[part].duration;
+
+    let mut values = values.clone();
+    values.part = Some((part + 1, manifest.parts.len()));
+    values.chapters = values
+        .chapters
+        .iter()
+        .filter(|c| c.start < end && c.end > start)
+        .map(|c| Chapter {
+            start: c.start.saturating_sub(start),
+            end: c.end.min(end).saturating_sub(start),
+            title: c.title.clone(),
+        })
+        .collect();
+    values
+}
+
+/// Puts links to the other uploaded parts in front of a part's description
+fn with_part_links(description: &str, parts: &[PartEntry], current: usize) -> String {
+    let mut links = String::from("This stream is split into multiple parts:\n");
+    for (part, entry) in parts.iter().enumerate() {
+        if part == current {
+            let _ = writeln!(links, "Part {}: This video", part + 1);
+        } else if let Some(uploaded) = &entry.uploaded {
+            let _ = writeln!(links, "Part {}: {}", part + 1, uploaded.url());
+        }
+    }
+
+    format!("{links}\n{description}")
+}
+
+impl PartsManifest {
+    async fn load(parts_dir: &Path) -> Option<Self> {
+        let data = match tokio::fs::read(parts_dir.join(PARTS_MANIFEST_FILE_NAME)).await {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Unable to read the previously split parts: {e}");
+                return None;
+            }
+        };
+
+        serde_json::from_slice(&data)
+            .inspect_err(|e| warn!("Parts manifest is corrupted, ignoring it: {e}"))
+            .ok()
+    }
+
+    async fn save(&self, parts_dir: &Path) -> Result<()> {
+        let data = serde_json::to_vec(self).context("Serializing parts manifest")?;
+        tokio::fs::write(parts_dir.join(PARTS_MANIFEST_FILE_NAME), data)
+            .await
+            .context("Writing parts manifest")
+    }
+}
@@ -0,0 +1,94 @@
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use super::ArchiveOptions;
+use crate::{
+    template::{Template, TemplateValues},
+    twitch::structs::VideoInfo,
+    youtube::{
+        MAX_PLAYLIST_TITLE_CHARS, PrivacyStatus, UploadedVideo, find_or_create_playlist,
+        insert_playlist_item,
+    },
+};
+
+/// Playlists archived videos are added to, set with command line flags or the `[playlists]`
+/// table of the config file
+#[derive(Debug, Clone, Default, clap::Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Playlists {
+    /// Add archived videos to the playlist titled by this template, creating it when missing.
+    /// Can be repeated, e.g. `--playlist "{channel} VODs" --playlist "{game}"`
+    ///
+    /// Uses the same placeholders as `--title-template`. Playlists whose title is empty (e.g.
+    /// `{game}` without a game) are skipped
+    #[arg(long = "playlist", value_name = "TEMPLATE")]
+    pub titles: Vec<Template>,
+
+    /// Privacy of created playlists [default: `--privacy`]
+    #[arg(long = "playlist-privacy", value_enum, value_name = "PRIVACY")]
+    pub privacy: Option<PrivacyStatus>,
+}
+
+impl Playlists {
+    /// Fills every setting missing from `self` with the one from `other`
+    #[must_use]
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            titles: if self.titles.is_empty() {
+                other.titles
+            } else {
+                self.titles
+            },
+            privacy: self.privacy.or(other.privacy),
+        }
+    }
+}
+
+/// Adds uploaded videos to every playlist of [`ArchiveOptions::playlists`], in order
+///
+/// Failures are only logged as the videos themselves are already uploaded
+pub(super) async fn add_to_playlists(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    video_info: &VideoInfo,
+    uploaded: &[UploadedVideo],
+    options: &ArchiveOptions,
+) {
+    let values = TemplateValues::new(video_info, &[], &[]);
+    let privacy = options
+        .playlists
+        .privacy
+        .or(options.metadata.privacy)
+        .unwrap_or_default();
+
+    let mut titles: Vec<String> = Vec::new();
+    for template in &options.playlists.titles {
+        let title = template
+            .render_limited(&values, MAX_PLAYLIST_TITLE_CHARS)
+            .trim()
+            .to_string();
+        if !title.is_empty() && !titles.contains(&title) {
+            titles.push(title);
+        }
+    }
+
+    for title in titles {
+        let playlist_id = match find_or_create_playlist(client, access_token, &title, privacy).await
+        {
+            Ok(id) => id,
+            Err(e) => {
+                warn!("Unable to find or create playlist {title:?}: {e:#}");
+                continue;
+            }
+        };
+
+        for video in uploaded {
+            match insert_playlist_item(client, access_token, &playlist_id, &video.id).await {
+                Ok(()) => info!("Added {} to playlist {title:?}", video.url()),
+                Err(e) => warn!("Unable to add {} to playlist {title:?}: {e:#}", video.url()),
+            }
+        }
+    }
+}
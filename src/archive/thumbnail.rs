@@ -0,0 +1,72 @@
+use std::{ffi::OsStr, time::Duration};
+
+use anyhow::{Result, bail};
+use tracing::{debug, info, warn};
+
+use crate::{
+    ffmpeg,
+    twitch::structs::VideoInfo,
+    youtube::{MAX_THUMBNAIL_BYTES, ThumbnailSource, VideoMetadata},
+};
+
+/// JPEG qualities tried from best to worst until the thumbnail is small enough for YouTube
+const THUMBNAIL_QUALITIES: [u8; 4] = [2, 5, 10, 20];
+
+/// Creates the custom thumbnail of an archived video
+///
+/// Frames are taken from `video`, a video file or URL lasting `duration`. Returns `None` when
+/// YouTube should pick a thumbnail or when the thumbnail cannot be created.
+pub async fn create_thumbnail(
+    video_info: &VideoInfo,
+    video: &OsStr,
+    duration: Duration,
+    metadata: &VideoMetadata,
+) -> Option<Vec<u8>> {
+    let frame_timestamp = metadata
+        .thumbnail_timestamp
+        .map(Duration::from_secs)
+        .filter(|t| *t < duration)
+        .unwrap_or(duration / 2);
+    // Twitch shows a "processing" placeholder until the VOD has a thumbnail
+    let preview = video_info
+        .preview_thumbnail_url
+        .as_deref()
+        .filter(|url| !url.contains("404_processing"));
+
+    let (input, seek) = match (metadata.thumbnail.unwrap_or_default(), preview) {
+        (ThumbnailSource::Auto, _) => return None,
+        (ThumbnailSource::Twitch, Some(url)) => (OsStr::new(url), None),
+        (ThumbnailSource::Twitch, None) => {
+            info!("Twitch has no thumbnail for this VOD yet, using a frame of the video instead");
+            (video, Some(frame_timestamp))
+        }
+        (ThumbnailSource::Frame, _) => (video, Some(frame_timestamp)),
+    };
+
+    match encode(input, seek).await {
+        Ok(thumbnail) => Some(thumbnail),
+        Err(e) => {
+            warn!("Unable to create a thumbnail, YouTube will pick one instead: {e:#}");
+            None
+        }
+    }
+}
+
+/// Encodes a thumbnail, lowering its quality until it fits [`MAX_THUMBNAIL_BYTES`]
+async fn encode(input: &OsStr, seek: Option<Duration>) -> Result<Vec<u8>> {
+    for quality in THUMBNAIL_QUALITIES {
+        let thumbnail = ffmpeg::encode_thumbnail(input, seek, quality).await?;
+        if thumbnail.len() <= MAX_THUMBNAIL_BYTES {
+            return Ok(thumbnail);
+        }
+        debug!(
+            "Thumbnail is {} at quality {quality}, lowering its quality",
+            indicatif::DecimalBytes(thumbnail.len() as u64)
+        );
+    }
+
+    bail!(
+        "Thumbnail is still larger than {} at the lowest quality",
+        indicatif::DecimalBytes(MAX_THUMBNAIL_BYTES as u64)
+    )
+}
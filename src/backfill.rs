@@ -0,0 +1,207 @@
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use tracing::{debug, info};
+
+use crate::{
+    state::StateStore,
+    twitch::{
+        ChannelRef,
+        api::get_channel_videos_page,
+        structs::{BroadcastType, Status, VideoInfo},
+    },
+};
+
+/// Which videos of a channel are archived by the `backfill` command
+#[derive(Debug, Clone, clap::Args)]
+pub struct BackfillFilter {
+    /// Only archive videos streamed / uploaded on or after this date, e.g. `2025-01-31`
+    #[arg(long, value_name = "DATE")]
+    pub since: Option<NaiveDate>,
+
+    /// Only archive videos streamed / uploaded on or before this date, e.g. `2025-12-31`
+    #[arg(long, value_name = "DATE")]
+    pub until: Option<NaiveDate>,
+
+    /// Types of videos to archive, e.g. `--type archive,highlight`
+    #[arg(
+        long = "type",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "archive",
+        value_name = "TYPE"
+    )]
+    pub types: Vec<BroadcastType>,
+
+    /// Only archive videos at least this long
+    #[arg(long, default_value_t = 0, value_name = "MINUTES")]
+    pub min_length: u64,
+}
+
+impl BackfillFilter {
+    fn matches(&self, video: &VideoInfo) -> bool {
+        let date = video.created_at.date_naive();
+
+        self.types.contains(&video.broadcast_type)
+            && self.since.is_none_or(|since| date >= since)
+            && self.until.is_none_or(|until| date <= until)
+            && video.length_seconds >= self.min_length * 60
+            // Still being streamed, left for `monitor` to archive once it ends
+            && !matches!(video.status, Status::RECORDING)
+    }
+}
+
+/// Pages through every video of a channel, queueing the ones matching `filter` into `state`
+///
+/// Videos are queued oldest first, as they are the first to expire. Videos which are already
+/// archived or queued are skipped.
+///
+/// Returns the amount of queued videos
+///
+/// # Errors
+/// Errors when the channel does not exist, when its videos cannot be fetched or when the queue
+/// cannot be written
+pub async fn queue_channel_videos(
+    state: &StateStore,
+    channel: &ChannelRef,
+    filter: &BackfillFilter,
+) -> Result<usize> {
+    let mut videos = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = get_channel_videos_page(channel, cursor.as_deref())
+            .await?
+            .with_context(|| format!("Channel {channel:?} does not exist"))?;
+        debug!("Fetched {} channel videos", page.videos.len());
+
+        // Videos are sorted newest first, so every following page is older than `since`
+        let reached_since = filter.since.is_some_and(|since| {
+            page.videos
+                .last()
+                .is_some_and(|v| v.created_at.date_naive() < since)
+        });
+        videos.extend(page.videos.into_iter().filter(|v| filter.matches(v)));
+
+        match page.next_cursor {
+            Some(next) if !reached_since => cursor = Some(next),
+            _ => break,
+        }
+    }
+    info!("Found {} videos matching the filters", videos.len());
+
+    let mut queued = 0;
+    for video in videos.iter().rev() {
+        let vod_id = video.id.parse::<u64>().context("Parsing video ID")?;
+
+        if state.enqueue(vod_id).await? {
+            info!(
+                "Queued VOD {vod_id} from {}: {}",
+                video.created_at.format("%Y-%m-%d"),
+                video.title
+            );
+            queued += 1;
+        } else {
+            debug!("VOD {vod_id} is already archived or queued, skipping it");
+        }
+    }
+
+    Ok(queued)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+    use crate::twitch::structs::Channel;
+
+    fn video(created_at: &str, broadcast_type: BroadcastType) -> VideoInfo {
+        VideoInfo {
+            id: "123".to_string(),
+            title: "Speedrun".to_string(),
+            description: None,
+            created_at: created_at.parse::<DateTime<Utc>>().unwrap(),
+            length_seconds: 3600,
+            view_count: 0,
+            status: Status::RECORDED,
+            broadcast_type,
+            preview_thumbnail_url: None,
+            game: None,
+            owner: Channel {
+                login: "streamer".to_string(),
+                display_name: "Streamer".to_string(),
+                profile_image_url: None,
+                banner_image_url: None,
+            },
+        }
+    }
+
+    fn filter() -> BackfillFilter {
+        BackfillFilter {
+            since: None,
+            until: None,
+            types: vec![BroadcastType::Archive],
+            min_length: 0,
+        }
+    }
+
+    #[test]
+    fn matches_dates_inclusively() {
+        let filter = BackfillFilter {
+            since: NaiveDate::from_ymd_opt(2025, 1, 1),
+            until: NaiveDate::from_ymd_opt(2025, 1, 31),
+            ..filter()
+        };
+        for (created_at, matches) in [
+            ("2024-12-31T23:59:59Z", false),
+            ("2025-01-01T00:00:00Z", true),
+            ("2025-01-31T23:59:59Z", true),
+            ("2025-02-01T00:00:00Z", false),
+        ] {
+            let video = video(created_at, BroadcastType::Archive);
+            assert_eq!(filter.matches(&video), matches, "{created_at}");
+        }
+    }
+
+    #[test]
+    fn matches_types() {
+        let video = |broadcast_type| video("2025-01-31T12:00:00Z", broadcast_type);
+        assert!(filter().matches(&video(BroadcastType::Archive)));
+        assert!(!filter().matches(&video(BroadcastType::Highlight)));
+        assert!(!filter().matches(&video(BroadcastType::Unknown)));
+
+        let filter = BackfillFilter {
+            types: vec![BroadcastType::Highlight, BroadcastType::Upload],
+            ..filter()
+        };
+        assert!(!filter.matches(&video(BroadcastType::Archive)));
+        assert!(filter.matches(&video(BroadcastType::Highlight)));
+        assert!(filter.matches(&video(BroadcastType::Upload)));
+    }
+
+    #[test]
+    fn matches_min_length() {
+        let filter = BackfillFilter {
+            min_length: 60,
+            ..filter()
+        };
+        let mut video = video("2025-01-31T12:00:00Z", BroadcastType::Archive);
+        assert!(filter.matches(&video));
+        video.length_seconds = 3599;
+        assert!(!filter.matches(&video));
+    }
+
+    #[test]
+    fn skips_vods_still_being_recorded() {
+        let mut video = video("2025-01-31T12:00:00Z", BroadcastType::Archive);
+        video.status = Status::RECORDING;
+        assert!(!filter().matches(&video));
+    }
+
+    #[test]
+    fn parses_unknown_broadcast_types() {
+        let parsed = serde_json::from_str::<BroadcastType>(r#""PREMIERE_UPLOAD""#).unwrap();
+        assert_eq!(parsed, BroadcastType::Unknown);
+        let parsed = serde_json::from_str::<BroadcastType>(r#""PAST_PREMIERE""#).unwrap();
+        assert_eq!(parsed, BroadcastType::PastPremiere);
+    }
+}
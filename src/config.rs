@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::{archive::Playlists, ratelimit::ByteRate, template::Templates, youtube::VideoMetadata};
+
+/// Settings read from the `--config` TOML file, used for the command line flags which are not given
+///
+/// The file is reloaded on `SIGHUP`, see [`spawn_reloader`]
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Download rate limit shared by every segment download (e.g. `10M`)
+    pub download_rate: Option<ByteRate>,
+    /// Upload rate limit of YouTube uploads (e.g. `2M`)
+    pub upload_rate: Option<ByteRate>,
+    /// Metadata of archived videos
+    pub youtube: VideoMetadata,
+    /// Title and description templates of archived videos
+    pub templates: Templates,
+    /// Playlists archived videos are added to
+    pub playlists: Playlists,
+}
+
+impl Config {
+    /// Reads and parses a config file
+    ///
+    /// # Errors
+    /// Errors when the file cannot be read or is not a valid config
+    pub async fn load(path: &Path) -> Result<Self> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .context("Reading config file")?;
+
+        toml::from_str(&content).context("Parsing config file")
+    }
+}
+
+/// Reloads the config file whenever a `SIGHUP` signal is received, e.g. `kill -HUP <pid>`
+///
+/// `on_reload` is called with the new config. Invalid configs are logged and ignored.
+pub fn spawn_reloader<F>(ct: CancellationToken, path: PathBuf, on_reload: F)
+where
+    F: Fn(Config) + Send + 'static,
+{
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            error!("Unable to listen for SIGHUP, config file will not be reloaded");
+            return;
+        };
+
+        loop {
+            tokio::select! {
+                () = ct.cancelled() => return,
+                _ = hangup.recv() => {}
+            }
+
+            info!("Caught SIGHUP signal! Reloading config file");
+            match Config::load(&path).await {
+                Ok(config) => on_reload(config),
+                Err(e) => error!("Unable to reload config file: {e:#}"),
+            }
+        }
+    });
+
+    #[cfg(not(unix))]
+    {
+        let _ = (ct, path, on_reload);
+        tracing::warn!("Reloading the config file is only supported on Unix");
+    }
+}
@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use chrono::Utc;
+use reqwest::Url;
+use tokio::{fs::File, io::AsyncWriteExt, select};
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use super::{DownloadOptions, DownloadedVideo, SegmentError};
+use crate::{
+    info::DownloadInfo,
+    ratelimit::RateLimiter,
+    twitch::{self, structs::ClipInfo},
+};
+
+/// Downloads a clip, which is a single MP4 file instead of a playlist of segments
+///
+/// Clips are short, so an interrupted download is started over instead of being resumed
+///
+/// # Errors
+/// Errors when the clip has no matching quality, when its access token cannot be fetched or
+/// when the download keeps failing
+pub async fn download_clip(
+    ct: CancellationToken,
+    client: reqwest::Client,
+    temp_download_dir: &Path,
+    options: &DownloadOptions,
+    clip: &ClipInfo,
+) -> Result<DownloadedVideo> {
+    let started_at = Utc::now();
+    let quality = options
+        .quality
+        .select_clip(&clip.video_qualities)
+        .context("Selecting clip quality")?;
+    info!(
+        "Selected quality: {}p{}",
+        quality.quality,
+        quality.frame_rate.round()
+    );
+
+    let (token_value, token_signature) = twitch::api::get_clip_cdn_tokens(&clip.slug).await?;
+    let mut url = Url::parse(&quality.source_url).context("Parsing clip URL")?;
+    url.query_pairs_mut()
+        .append_pair("sig", &token_signature)
+        .append_pair("token", &token_value);
+    debug!("Selected quality clip uri: {}", quality.source_url);
+
+    let temp_download_dir = temp_download_dir.join(format!("vod-squirrel-clip-{}/", clip.slug));
+    tokio::fs::create_dir_all(&temp_download_dir)
+        .await
+        .context("Creating download directory")?;
+    let out_file_path = temp_download_dir.join("out.mp4");
+
+    let mut attempt = 0;
+    let size = loop {
+        match download_file(&client, &options.rate_limiter, &url, &out_file_path).await {
+            Ok(size) => break size,
+            Err(e) if e.is_retriable() && attempt < options.retry_policy.max_retries => {
+                attempt += 1;
+                let delay = options.retry_policy.backoff(attempt);
+                warn!(
+                    "Clip download failed, retrying in {delay:.1?} ({attempt}/{}): {e}",
+                    options.retry_policy.max_retries
+                );
+
+                select! {
+                    () = ct.cancelled() => break 0,
+                    () = tokio::time::sleep(delay) => {}
+                }
+            }
+            Err(SegmentError::Retriable(e) | SegmentError::Missing(e) | SegmentError::Fatal(e)) => {
+                return Err(e.context("Downloading clip"));
+            }
+        }
+    };
+    if !ct.is_cancelled() {
+        info!("Done downloading the clip!");
+    }
+
+    Ok(DownloadedVideo {
+        path: out_file_path,
+        muted_ranges: Vec::new(),
+        chapters: Vec::new(),
+        info: DownloadInfo::from_clip(clip, quality, size, started_at),
+    })
+}
+
+/// Downloads the clip file, returning its length
+async fn download_file(
+    client: &reqwest::Client,
+    rate_limiter: &RateLimiter,
+    url: &Url,
+    path: &Path,
+) -> Result<u64, SegmentError> {
+    let res = client
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| SegmentError::from_reqwest(e, "Requesting clip"))?;
+
+    let expected_length = res.content_length();
+    let pb = expected_length.map_or_else(
+        indicatif::ProgressBar::no_length,
+        indicatif::ProgressBar::new,
+    );
+    let mut file = File::create(path)
+        .await
+        .context("Creating clip file")
+        .map_err(SegmentError::Fatal)?;
+
+    let mut written = 0;
+    let mut stream = res.bytes_stream();
+    while let Some(data) = stream.next().await {
+        let data = data.map_err(|e| SegmentError::from_reqwest(e, "Downloading clip"))?;
+        rate_limiter.acquire(data.len()).await;
+        file.write_all(&data)
+            .await
+            .context("Writing clip to disk")
+            .map_err(SegmentError::Fatal)?;
+        written += data.len() as u64;
+        pb.set_position(written);
+    }
+    file.flush()
+        .await
+        .context("Flushing clip")
+        .map_err(SegmentError::Fatal)?;
+    pb.finish_and_clear();
+
+    // The connection may be closed in the middle of the body without any error
+    if let Some(expected_length) = expected_length
+        && written != expected_length
+    {
+        return Err(SegmentError::Retriable(anyhow!(
+            "Clip is incomplete ({written} of {expected_length} bytes)"
+        )));
+    }
+
+    Ok(written)
+}
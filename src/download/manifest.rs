@@ -0,0 +1,327 @@
+use std::{collections::HashMap, io::ErrorKind, path::Path};
+
+use anyhow::{Context, Result};
+use m3u8_rs::MediaSegment;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::twitch::cdn::is_muted_segment;
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Per-VOD record of which segments have been downloaded, stored next to the chunks
+///
+/// Used to resume an interrupted download without re-fetching completed segments
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadManifest {
+    pub vod_id: u64,
+    /// Media playlist URI of the variant the segments were downloaded from
+    pub variant_uri: String,
+    pub segments: Vec<SegmentEntry>,
+    /// Whether every segment has been concatenated into the output video, so a restarted
+    /// archive can go straight to uploading it
+    #[serde(default)]
+    pub concatenated: bool,
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentEntry {
+    pub uri: String,
+    /// Byte length reported by the CDN, known once the segment has been requested at least once
+    pub expected_length: Option<u64>,
+    /// URL the segment file is downloaded from, which differs from `uri` when its original audio
+    /// was recovered. A partial file is only resumed from the same URL.
+    #[serde(default)]
+    pub source: Option<String>,
+    pub completed: bool,
+    /// Whether Twitch has muted the audio of this segment
+    #[serde(default)]
+    pub muted: bool,
+    /// Whether the original audio of a muted segment was downloaded instead
+    #[serde(default)]
+    pub unmuted: bool,
+    /// Whether the segment is not available on the CDN, leaving a gap in the video
+    #[serde(default)]
+    pub missing: bool,
+}
+
+impl DownloadManifest {
+    /// Creates a manifest for the given media segments, carrying over the progress of `previous`
+    /// if it was made for the same VOD variant
+    #[must_use]
+    pub fn new(
+        vod_id: u64,
+        variant_uri: &str,
+        segments: &[MediaSegment],
+        previous: Option<Self>,
+    ) -> Self {
+        let previous = previous.filter(|p| p.vod_id == vod_id && p.variant_uri == variant_uri);
+        // The output video is outdated when the VOD got new segments (e.g. it was still live)
+        let concatenated = previous.as_ref().is_some_and(|p| {
+            p.concatenated
+                && p.segments.len() == segments.len()
+                && p.segments.iter().zip(segments).all(|(p, s)| p.uri == s.uri)
+        });
+        let mut previous = previous
+            .map(|p| {
+                p.segments
+                    .into_iter()
+                    .map(|s| (s.uri.clone(), s))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+
+        let segments = segments
+            .iter()
+            .map(|s| {
+                let mut entry = previous.remove(&s.uri).unwrap_or_else(|| SegmentEntry {
+                    uri: s.uri.clone(),
+                    expected_length: None,
+                    source: None,
+                    completed: false,
+                    muted: false,
+                    unmuted: false,
+                    missing: false,
+                });
+                // A missing segment might have been replaced by its muted copy
+                entry.muted |= is_muted_segment(s);
+                entry
+            })
+            .collect();
+
+        Self {
+            vod_id,
+            variant_uri: variant_uri.to_string(),
+            segments,
+            concatenated,
+        }
+    }
+
+    /// Reads the manifest inside of a download directory
+    ///
+    /// Returns `None` if there is no manifest or if it is unreadable
+    ///
+    /// # Errors
+    /// Errors when the manifest file exists but cannot be read
+    pub async fn load(download_dir: &Path) -> Result<Option<Self>> {
+        let data = match tokio::fs::read(download_dir.join(MANIFEST_FILE_NAME)).await {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Reading download manifest"),
+        };
+
+        match serde_json::from_slice(&data) {
+            Ok(m) => Ok(Some(m)),
+            Err(e) => {
+                warn!("Download manifest is corrupted, ignoring it: {e}");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Atomically writes the manifest inside of a download directory
+    ///
+    /// # Errors
+    /// Errors when the manifest cannot be written to disk
+    pub async fn save(&self, download_dir: &Path) -> Result<()> {
+        let temp_path = download_dir.join(format!("{MANIFEST_FILE_NAME}.tmp"));
+        let data = serde_json::to_vec(self).context("Serializing download manifest")?;
+
+        tokio::fs::write(&temp_path, data)
+            .await
+            .context("Writing download manifest")?;
+        tokio::fs::rename(&temp_path, download_dir.join(MANIFEST_FILE_NAME))
+            .await
+            .context("Replacing download manifest")?;
+
+        Ok(())
+    }
+
+    /// Checks completed segments against the files on disk, un-marking any segment whose file
+    /// is missing or does not match its expected length
+    pub async fn verify(&mut self, download_dir: &Path) {
+        for segment in self.segments.iter_mut().filter(|s| s.completed) {
+            let actual_length = tokio::fs::metadata(download_dir.join(&segment.uri))
+                .await
+                .map(|m| m.len())
+                .ok();
+
+            if actual_length.is_none() || actual_length != segment.expected_length {
+                debug!(
+                    "Segment {} is incomplete ({actual_length:?} of {:?} bytes)",
+                    segment.uri, segment.expected_length
+                );
+                segment.completed = false;
+                self.concatenated = false;
+            }
+        }
+    }
+
+    /// Records where a segment is being downloaded from, once its download (re)started
+    pub fn set_download_source(&mut self, index: usize, source: &str, length: Option<u64>) {
+        let segment = &mut self.segments[index];
+        segment.expected_length = length;
+        segment.source = Some(source.to_string());
+    }
+
+    pub fn set_incomplete(&mut self, index: usize) {
+        let segment = &mut self.segments[index];
+        segment.expected_length = None;
+        segment.source = None;
+        segment.completed = false;
+        segment.unmuted = false;
+        segment.missing = false;
+        self.concatenated = false;
+    }
+
+    pub fn set_completed(&mut self, index: usize, length: u64, muted: bool, unmuted: bool) {
+        let segment = &mut self.segments[index];
+        segment.expected_length = Some(length);
+        segment.completed = true;
+        segment.muted = muted;
+        segment.unmuted = unmuted;
+        segment.missing = false;
+    }
+
+    /// Records a segment which is not available on the CDN, it is downloaded again on resume
+    pub fn set_missing(&mut self, index: usize) {
+        self.set_incomplete(index);
+        self.segments[index].missing = true;
+    }
+
+    #[must_use]
+    pub fn completed_count(&self) -> usize {
+        self.segments.iter().filter(|s| s.completed).count()
+    }
+
+    #[must_use]
+    pub fn missing_count(&self) -> usize {
+        self.segments.iter().filter(|s| s.missing).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir;
+
+    fn segments(uris: &[&str]) -> Vec<MediaSegment> {
+        uris.iter()
+            .map(|uri| MediaSegment {
+                uri: (*uri).to_string(),
+                duration: 10.0,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn verify_unmarks_missing_and_truncated_segments() {
+        let dir = test_dir("manifest-verify");
+        let mut manifest =
+            DownloadManifest::new(1, "720p.m3u8", &segments(&["0.ts", "1.ts", "2.ts"]), None);
+        for index in 0..3 {
+            manifest.set_completed(index, 4, false, false);
+        }
+        manifest.concatenated = true;
+        std::fs::write(dir.join("0.ts"), [0; 4]).unwrap();
+        std::fs::write(dir.join("1.ts"), [0; 2]).unwrap();
+
+        manifest.verify(&dir).await;
+
+        let completed = manifest
+            .segments
+            .iter()
+            .map(|s| s.completed)
+            .collect::<Vec<_>>();
+        assert_eq!(completed, [true, false, false]);
+        assert!(!manifest.concatenated);
+    }
+
+    #[tokio::test]
+    async fn saves_and_loads_manifest() {
+        let dir = test_dir("manifest-save");
+        assert!(DownloadManifest::load(&dir).await.unwrap().is_none());
+
+        let mut manifest =
+            DownloadManifest::new(1, "720p.m3u8", &segments(&["0.ts", "1.ts"]), None);
+        manifest.set_completed(1, 188, false, false);
+        manifest.save(&dir).await.unwrap();
+
+        let loaded = DownloadManifest::load(&dir).await.unwrap().unwrap();
+        assert_eq!(loaded.vod_id, 1);
+        assert_eq!(loaded.variant_uri, "720p.m3u8");
+        assert_eq!(loaded.completed_count(), 1);
+        assert_eq!(loaded.segments[1].expected_length, Some(188));
+        assert!(!dir.join("manifest.json.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn ignores_corrupted_manifest() {
+        let dir = test_dir("manifest-corrupted");
+        std::fs::write(dir.join(MANIFEST_FILE_NAME), "{\"vod_id\": 1, \"segm").unwrap();
+
+        assert!(DownloadManifest::load(&dir).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn resumes_progress_of_the_same_variant() {
+        let playlist = segments(&["0.ts", "1-muted.ts", "2.ts"]);
+        let mut previous = DownloadManifest::new(1, "720p.m3u8", &playlist, None);
+        previous.set_completed(0, 188, false, false);
+        previous.set_completed(1, 376, true, true);
+        previous.set_download_source(2, "https://cdn/2.ts", Some(564));
+
+        let manifest = DownloadManifest::new(1, "720p.m3u8", &playlist, Some(previous.clone()));
+        assert_eq!(manifest.completed_count(), 2);
+        assert!(!manifest.segments[0].muted);
+        assert!(manifest.segments[1].muted && manifest.segments[1].unmuted);
+        assert_eq!(
+            manifest.segments[2].source.as_deref(),
+            Some("https://cdn/2.ts")
+        );
+        assert_eq!(manifest.segments[2].expected_length, Some(564));
+
+        // Segments of another variant or VOD are not the same files
+        let other_variant = DownloadManifest::new(1, "1080p.m3u8", &playlist, Some(previous));
+        assert_eq!(other_variant.completed_count(), 0);
+        assert!(other_variant.segments[1].muted);
+        assert!(other_variant.segments[2].source.is_none());
+    }
+
+    #[test]
+    fn concatenated_video_is_outdated_by_new_segments() {
+        let playlist = segments(&["0.ts", "1.ts"]);
+        let mut previous = DownloadManifest::new(1, "720p.m3u8", &playlist, None);
+        previous.set_completed(0, 188, false, false);
+        previous.set_completed(1, 188, false, false);
+        previous.concatenated = true;
+
+        let same = DownloadManifest::new(1, "720p.m3u8", &playlist, Some(previous.clone()));
+        assert!(same.concatenated);
+
+        let grown = DownloadManifest::new(
+            1,
+            "720p.m3u8",
+            &segments(&["0.ts", "1.ts", "2.ts"]),
+            Some(previous),
+        );
+        assert!(!grown.concatenated);
+        assert_eq!(grown.completed_count(), 2);
+    }
+
+    #[test]
+    fn incomplete_segments_forget_their_source() {
+        let mut manifest = DownloadManifest::new(1, "720p.m3u8", &segments(&["0-muted.ts"]), None);
+        manifest.set_download_source(0, "https://cdn/0-unmuted.ts", Some(188));
+        manifest.set_completed(0, 188, true, true);
+        manifest.concatenated = true;
+
+        manifest.set_incomplete(0);
+        let segment = &manifest.segments[0];
+        assert!(!segment.completed && !segment.unmuted);
+        assert!(segment.source.is_none() && segment.expected_length.is_none());
+        assert!(!manifest.concatenated);
+    }
+}
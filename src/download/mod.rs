@@ -0,0 +1,801 @@
+mod clip;
+mod manifest;
+mod retry;
+mod stream;
+mod verify;
+
+pub use clip::*;
+pub use manifest::*;
+pub use retry::*;
+pub use stream::*;
+pub use verify::*;
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Utc;
+use m3u8_rs::{MediaPlaylist, VariantStream};
+use reqwest::{StatusCode, Url, header::RANGE};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    select,
+    sync::{Mutex, Semaphore},
+};
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    ffmpeg::{ConcatMethod, concat_video},
+    info::DownloadInfo,
+    ratelimit::RateLimiter,
+    state::{self, JobState, StateStore},
+    twitch::{
+        self,
+        cdn::{
+            MutedRange, QualityPreference, init_segment_uri, muted_ranges, muted_segment_uri,
+            unmuted_segment_uris,
+        },
+        structs::Chapter,
+    },
+};
+
+/// Amount of newly completed segments between each download manifest save
+const MANIFEST_SAVE_INTERVAL: usize = 25;
+/// Amount of times segments failing verification are re-downloaded
+const MAX_VERIFICATION_RETRIES: usize = 3;
+/// fMP4 segments joined behind their initialization segment, see [`join_fmp4_segments`]
+const JOINED_FILE_NAME: &str = "joined.mp4";
+
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// The amount of parallel segment downloads to be done at once
+    pub parallelism: usize,
+    pub retry_policy: RetryPolicy,
+    pub quality: QualityPreference,
+    /// Try downloading the original audio of segments muted by Twitch
+    pub try_unmute: bool,
+    /// Leave out segments missing from the CDN instead of failing the download, when their muted
+    /// copy is missing too
+    pub skip_missing_segments: bool,
+    pub concat_method: ConcatMethod,
+    /// Pipe segments straight into ffmpeg instead of saving them to disk first
+    pub streaming: bool,
+    /// Shared by every segment download
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Archive history the download progress is recorded into
+    pub state: Option<Arc<StateStore>>,
+}
+
+/// A downloaded and concatenated VOD
+#[derive(Debug, Clone)]
+pub struct DownloadedVideo {
+    pub path: PathBuf,
+    /// Sections of the video where the audio is muted by Twitch
+    pub muted_ranges: Vec<MutedRange>,
+    /// Game / category changes of the VOD, also embedded into the video
+    pub chapters: Vec<Chapter>,
+    pub info: DownloadInfo,
+}
+
+/// A segment which is queued to be downloaded
+struct SegmentJob {
+    /// Position of the segment inside of the download manifest
+    index: usize,
+    entry: SegmentEntry,
+    url: Url,
+    file_path: PathBuf,
+}
+
+/// State shared between every segment download of a VOD
+#[derive(Clone)]
+struct SegmentDownloader {
+    /// Cancelled on CTRL + C or when a segment fails in a way that retrying won't fix
+    abort: CancellationToken,
+    client: reqwest::Client,
+    rate_limiter: Arc<RateLimiter>,
+    retry_policy: RetryPolicy,
+    try_unmute: bool,
+    skip_missing_segments: bool,
+    permits: Arc<Semaphore>,
+    manifest: Arc<Mutex<DownloadManifest>>,
+    download_dir: PathBuf,
+    media_url: Url,
+    pb: indicatif::ProgressBar,
+}
+
+/// Fetches the media playlist of the VOD variant matching [`DownloadOptions::quality`]
+///
+/// Returns the selected variant and its media playlist
+///
+/// # Errors
+/// Errors when the VOD playlists cannot be fetched or no variant matches the quality preference
+pub async fn fetch_media(
+    vod_id: u64,
+    options: &DownloadOptions,
+) -> Result<(VariantStream, MediaPlaylist)> {
+    // Get CDN access tokens
+    let (token_value, token_signature) = twitch::api::get_video_cdn_tokens(vod_id, None)
+        .await
+        .context("Getting VOD access tokens")?;
+
+    // Get VOD HLS master playlist file
+    let vod_playlist =
+        twitch::cdn::get_video_playlist_file(vod_id, &token_value, &token_signature).await?;
+
+    let variant = options
+        .quality
+        .select(&vod_playlist)
+        .context("Selecting VOD quality")?;
+    info!(
+        "Selected quality: {}",
+        twitch::cdn::describe_variant(variant)
+    );
+    debug!("Selected quality media uri: {}", variant.uri);
+
+    // Get VOD media playlist file
+    let media = twitch::cdn::get_video_media(&variant.uri)
+        .await
+        .context("Getting VOD media")?;
+
+    Ok((variant.clone(), media))
+}
+
+/// Downloads a VOD into `temp_download_dir` and concatenates it into a single video file
+///
+/// Progress is tracked in a [`DownloadManifest`] next to the chunks, so an interrupted download
+/// will only fetch the missing segments when ran again. When [`DownloadOptions::streaming`] is
+/// set, chunks are not saved and are piped into ffmpeg using [`download_streaming`] instead.
+///
+/// Failing segments are retried according to [`DownloadOptions::retry_policy`]. A non-retriable
+/// failure (e.g. a full disk or an expired CDN token) stops the remaining segments from being
+/// downloaded. Segments missing from the CDN are replaced by their muted copy, and otherwise fail
+/// the download unless [`DownloadOptions::skip_missing_segments`] is set.
+/// Every segment is verified with [`verify_segment`] before concatenation, and broken segments
+/// are downloaded again.
+///
+/// # Errors
+/// Errors when the VOD playlists cannot be fetched, when the download directory or manifest
+/// cannot be written, when any segment fails to download or is missing, or when the video chunks
+/// cannot be concatenated
+#[allow(clippy::too_many_lines)]
+pub async fn download(
+    ct: CancellationToken,
+    client: reqwest::Client,
+    temp_download_dir: &Path,
+    options: &DownloadOptions,
+    vod_id: u64,
+) -> Result<DownloadedVideo> {
+    let started_at = Utc::now();
+    let (variant, media) = fetch_media(vod_id, options).await?;
+    state::record(options.state.as_deref(), vod_id, JobState::Downloading).await?;
+    let segment_count = media.segments.len();
+    info!("Found {segment_count} segments to download!");
+    let chapters = fetch_chapters(vod_id).await;
+
+    let temp_download_dir = temp_download_dir.join(format!("vod-squirrel-{vod_id}/"));
+    if options.streaming {
+        tokio::fs::create_dir_all(&temp_download_dir)
+            .await
+            .context("Creating download directory")?;
+
+        info!(
+            "Streaming into {temp_download_dir:?} with {} parallellism",
+            options.parallelism
+        );
+        let out_file_path = temp_download_dir.join("out.mp4");
+        let streamed = download_streaming(
+            &ct,
+            &client,
+            options,
+            &Url::from_str(&variant.uri)?,
+            &media.segments,
+            &out_file_path,
+            &chapters,
+        )
+        .await?;
+
+        if !ct.is_cancelled() {
+            state::record(options.state.as_deref(), vod_id, JobState::Concatenated).await?;
+        }
+
+        let muted_ranges = muted_ranges(
+            media
+                .segments
+                .iter()
+                .zip(&streamed)
+                .map(|(segment, s)| (segment, s.muted && !s.unmuted)),
+        );
+        let info = DownloadInfo::new(
+            &variant,
+            media
+                .segments
+                .iter()
+                .zip(streamed)
+                .map(|(segment, s)| (segment, s.muted, s.unmuted)),
+            started_at,
+        );
+        return Ok(DownloadedVideo {
+            path: out_file_path,
+            muted_ranges,
+            chapters,
+            info,
+        });
+    }
+
+    let previous_manifest = match tokio::fs::create_dir(&temp_download_dir).await {
+        Ok(()) => None,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            info!("Folder already exists. Resuming the previous download");
+            DownloadManifest::load(&temp_download_dir).await?
+        }
+        Err(e) => bail!(e),
+    };
+
+    let mut manifest =
+        DownloadManifest::new(vod_id, &variant.uri, &media.segments, previous_manifest);
+    manifest.verify(&temp_download_dir).await;
+    manifest.save(&temp_download_dir).await?;
+
+    let completed_count = manifest.completed_count();
+    if completed_count > 0 {
+        info!(
+            "{completed_count} of {segment_count} segments are already downloaded, skipping them"
+        );
+    }
+
+    let pending_segments = manifest
+        .segments
+        .iter()
+        .cloned()
+        .enumerate()
+        .filter(|(_, s)| !s.completed)
+        .collect::<Vec<_>>();
+
+    info!(
+        "Downloading on {temp_download_dir:?} with {} parallellism",
+        options.parallelism
+    );
+
+    let pb = indicatif::ProgressBar::new(segment_count as u64);
+    pb.set_position(completed_count as u64);
+    let downloader = SegmentDownloader {
+        abort: ct.child_token(),
+        client,
+        rate_limiter: options.rate_limiter.clone(),
+        retry_policy: options.retry_policy,
+        try_unmute: options.try_unmute,
+        skip_missing_segments: options.skip_missing_segments,
+        permits: Arc::new(Semaphore::new(options.parallelism)),
+        manifest: Arc::new(Mutex::new(manifest)),
+        download_dir: temp_download_dir.clone(),
+        media_url: Url::from_str(&variant.uri)?,
+        pb: pb.clone(),
+    };
+
+    let mut failures = downloader.download_all(pending_segments).await?;
+
+    // Every segment is verified once, afterward only the re-downloaded ones are
+    let mut verification_retries = 0;
+    let mut requeued_indices = None::<HashSet<usize>>;
+    while !downloader.abort.is_cancelled() {
+        let completed_segments = downloader
+            .manifest
+            .lock()
+            .await
+            .segments
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(i, s)| s.completed && requeued_indices.as_ref().is_none_or(|r| r.contains(i)))
+            .collect::<Vec<_>>();
+
+        let broken_segments =
+            verify_segments(&temp_download_dir, completed_segments, options.parallelism).await;
+        if broken_segments.is_empty() {
+            break;
+        }
+
+        let mut manifest = downloader.manifest.lock().await;
+        let mut requeued_segments = Vec::new();
+        for (index, entry, e) in broken_segments {
+            warn!("Segment {} failed verification: {e:#}", entry.uri);
+            manifest.set_incomplete(index);
+            failures.insert(
+                entry.uri,
+                SegmentError::Retriable(e.context("Failed verification")),
+            );
+            requeued_segments.push((index, manifest.segments[index].clone()));
+        }
+        drop(manifest);
+
+        verification_retries += 1;
+        if verification_retries > MAX_VERIFICATION_RETRIES {
+            break;
+        }
+
+        warn!(
+            "Re-downloading {} segments that failed verification",
+            requeued_segments.len()
+        );
+        pb.set_position(pb.position() - requeued_segments.len() as u64);
+        requeued_indices = Some(requeued_segments.iter().map(|(i, _)| *i).collect());
+        failures.extend(downloader.download_all(requeued_segments).await?);
+    }
+    pb.finish_and_clear();
+
+    // Tasks are done, so this is the last reference to the manifest
+    let SegmentDownloader {
+        manifest, client, ..
+    } = downloader;
+    let mut manifest = Arc::into_inner(manifest).unwrap().into_inner();
+    manifest
+        .save(&temp_download_dir)
+        .await
+        .context("Saving download progress")?;
+
+    let out_file_path = temp_download_dir.join("out.mp4");
+    let download_info = |manifest: &DownloadManifest| {
+        DownloadInfo::new(
+            &variant,
+            media
+                .segments
+                .iter()
+                .zip(&manifest.segments)
+                .map(|(segment, entry)| (segment, entry.muted, entry.unmuted)),
+            started_at,
+        )
+    };
+    if ct.is_cancelled() {
+        info!("Download progress is saved. Run the same command again to resume downloading");
+        return Ok(DownloadedVideo {
+            path: out_file_path,
+            muted_ranges: Vec::new(),
+            chapters,
+            info: download_info(&manifest),
+        });
+    }
+
+    let incomplete_segments = manifest
+        .segments
+        .iter()
+        .filter(|s| !s.completed && !s.missing)
+        .collect::<Vec<_>>();
+    if !incomplete_segments.is_empty() {
+        error!("The following segments were not downloaded:");
+        for segment in &incomplete_segments {
+            if let Some(e) = failures.get(&segment.uri) {
+                error!("- {}: {e}", segment.uri);
+            } else {
+                error!("- {}: Skipped due to an earlier failure", segment.uri);
+            }
+        }
+        bail!(
+            "{} of {segment_count} segments failed to download. Run the same command again to retry",
+            incomplete_segments.len()
+        );
+    }
+    let missing_count = manifest.missing_count();
+    if missing_count > 0 && !options.skip_missing_segments {
+        error!("The following segments are missing from Twitch's CDN:");
+        for segment in manifest.segments.iter().filter(|s| s.missing) {
+            error!("- {}", segment.uri);
+        }
+        bail!(
+            "{missing_count} of {segment_count} segments are missing from Twitch's CDN. Use `--skip-missing-segments` to archive the VOD without them"
+        );
+    }
+    if missing_count > 0 {
+        let missing_ranges = muted_ranges(
+            media
+                .segments
+                .iter()
+                .zip(&manifest.segments)
+                .map(|(segment, entry)| (segment, entry.missing)),
+        );
+        warn!(
+            "{missing_count} segments are missing from Twitch's CDN, the video will skip over {}",
+            missing_ranges
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    info!("Done downloading all chunks!");
+
+    // This is not a simple 1..length as stream name might contain `-muted` for silenced chunks
+    let segment_file_names = manifest
+        .segments
+        .iter()
+        .filter(|s| s.completed)
+        .map(|s| s.uri.clone())
+        .collect::<Vec<_>>();
+
+    if manifest.concatenated && out_file_path.is_file() {
+        info!("Video chunks are already concatenated, reusing the previous video");
+    } else {
+        info!("Concatenating video chunks now");
+        let joined_file_name = match init_segment_uri(&media.segments) {
+            Some(uri) => Some(
+                join_fmp4_segments(
+                    &ct,
+                    &client,
+                    options,
+                    &Url::from_str(&variant.uri)?,
+                    uri,
+                    &temp_download_dir,
+                    &segment_file_names,
+                )
+                .await?,
+            ),
+            None => None,
+        };
+        concat_video(
+            &temp_download_dir,
+            joined_file_name
+                .clone()
+                .map_or(segment_file_names, |f| vec![f]),
+            &out_file_path,
+            options.concat_method,
+            &chapters,
+        )
+        .await?;
+        if let Some(joined_file_name) = joined_file_name {
+            tokio::fs::remove_file(temp_download_dir.join(joined_file_name))
+                .await
+                .ok();
+        }
+        info!("Successfully concatenated video!");
+
+        manifest.concatenated = true;
+        manifest
+            .save(&temp_download_dir)
+            .await
+            .context("Saving download progress")?;
+    }
+    state::record(options.state.as_deref(), vod_id, JobState::Concatenated).await?;
+
+    let unmuted_count = manifest.segments.iter().filter(|s| s.unmuted).count();
+    if unmuted_count > 0 {
+        info!("Recovered the original audio of {unmuted_count} muted segments");
+    }
+    let muted_ranges = muted_ranges(
+        media
+            .segments
+            .iter()
+            .zip(&manifest.segments)
+            .map(|(segment, entry)| (segment, entry.muted && !entry.unmuted)),
+    );
+
+    Ok(DownloadedVideo {
+        path: out_file_path,
+        muted_ranges,
+        chapters,
+        info: download_info(&manifest),
+    })
+}
+
+/// Joins fMP4 segments into a single file behind their initialization segment, as they are not
+/// playable on their own for ffmpeg to concatenate them
+///
+/// Returns the file name of the joined video inside of `download_dir`
+async fn join_fmp4_segments(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    options: &DownloadOptions,
+    media_url: &Url,
+    init_uri: &str,
+    download_dir: &Path,
+    file_names: &[String],
+) -> Result<String> {
+    let init = fetch_with_retry(ct, client, options, media_url.join(init_uri)?, init_uri)
+        .await
+        .context("Downloading initialization segment")?;
+
+    let mut joined = File::create(download_dir.join(JOINED_FILE_NAME))
+        .await
+        .context("Creating joined video")?;
+    joined
+        .write_all(&init)
+        .await
+        .context("Writing joined video")?;
+    for file_name in file_names {
+        let mut segment = File::open(download_dir.join(file_name))
+            .await
+            .context("Opening segment")?;
+        tokio::io::copy(&mut segment, &mut joined)
+            .await
+            .context("Writing joined video")?;
+    }
+    joined.flush().await.context("Writing joined video")?;
+
+    Ok(JOINED_FILE_NAME.to_string())
+}
+
+/// Fetches the chapters of a VOD, which are not worth failing the download over
+pub async fn fetch_chapters(vod_id: u64) -> Vec<Chapter> {
+    match twitch::api::get_video_chapters(vod_id).await {
+        Ok(chapters) => {
+            if !chapters.is_empty() {
+                info!("Found {} chapters", chapters.len());
+            }
+            chapters
+        }
+        Err(e) => {
+            warn!("Unable to fetch VOD chapters, continuing without them: {e:#}");
+            Vec::new()
+        }
+    }
+}
+
+impl SegmentDownloader {
+    /// Downloads the given segments in parallel
+    ///
+    /// Returns the segments which failed to download along with their errors
+    async fn download_all(
+        &self,
+        segments: Vec<(usize, SegmentEntry)>,
+    ) -> Result<HashMap<String, SegmentError>> {
+        let mut download_tasks = tokio::task::JoinSet::new();
+
+        // Start queueing for downloads
+        for (index, entry) in segments {
+            let downloader = self.clone();
+            let mut job = SegmentJob {
+                index,
+                url: self.media_url.join(&entry.uri)?,
+                file_path: self.download_dir.join(&entry.uri),
+                entry,
+            };
+
+            download_tasks.spawn(async move {
+                let _permit = select! {
+                    () = downloader.abort.cancelled() => return None,
+                    p = downloader.permits.acquire() => p.unwrap()
+                };
+
+                let written = match downloader.download_with_retry(&mut job).await {
+                    Ok(w) => w,
+                    Err(e) if e.is_missing() => {
+                        warn!("Segment {} is missing: {e}", job.entry.uri);
+                        let mut manifest = downloader.manifest.lock().await;
+                        manifest.set_missing(job.index);
+                        // The download fails anyway, the rest is only downloaded to be resumed
+                        if !downloader.skip_missing_segments
+                            && too_many_missing_segments(
+                                manifest.missing_count(),
+                                manifest.segments.len(),
+                            )
+                        {
+                            error!("Stopping download, too many segments are missing");
+                            downloader.abort.cancel();
+                        }
+                        drop(manifest);
+
+                        downloader.pb.inc(1);
+                        return None;
+                    }
+                    Err(e) => {
+                        if !e.is_retriable() {
+                            error!("Stopping download, segment {} failed: {e}", job.entry.uri);
+                            downloader.abort.cancel();
+                        }
+                        return Some((job.entry.uri, e));
+                    }
+                };
+
+                let mut manifest = downloader.manifest.lock().await;
+                manifest.set_completed(job.index, written, job.entry.muted, job.entry.unmuted);
+                if manifest.completed_count() % MANIFEST_SAVE_INTERVAL == 0
+                    && let Err(e) = manifest.save(&downloader.download_dir).await
+                {
+                    // The disk is unlikely to recover (e.g. full), so the rest is not downloaded
+                    let e = e.context("Saving download progress");
+                    error!("Stopping download: {e:#}");
+                    downloader.abort.cancel();
+                    return Some((job.entry.uri, SegmentError::Fatal(e)));
+                }
+                drop(manifest);
+
+                debug!("Done downloading {}!", job.entry.uri);
+                downloader.pb.inc(1);
+                None
+            });
+        }
+
+        Ok(download_tasks
+            .join_all()
+            .await
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
+    /// Downloads a single segment, retrying retriable failures with a backoff
+    async fn download_with_retry(&self, job: &mut SegmentJob) -> Result<u64, SegmentError> {
+        if self.try_unmute
+            && job.entry.muted
+            && let Some(written) = self.download_unmuted(job).await
+        {
+            return Ok(written);
+        }
+
+        let mut attempt = 0;
+        loop {
+            match download_segment(&self.client, &self.rate_limiter, job, &self.manifest).await {
+                Ok(written) => return Ok(written),
+                Err(e) if e.is_missing() => {
+                    return self.download_muted(job).await.ok_or(e);
+                }
+                Err(e) if e.is_retriable() && attempt < self.retry_policy.max_retries => {
+                    attempt += 1;
+                    let delay = self.retry_policy.backoff(attempt);
+                    warn!(
+                        "Segment {} failed, retrying in {delay:.1?} ({attempt}/{}): {e}",
+                        job.entry.uri, self.retry_policy.max_retries
+                    );
+
+                    select! {
+                        () = self.abort.cancelled() => return Err(e),
+                        () = tokio::time::sleep(delay) => {}
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Tries to download the muted copy of a missing segment into the segment's file
+    ///
+    /// Returns `None` when there is no muted copy either
+    async fn download_muted(&self, job: &mut SegmentJob) -> Option<u64> {
+        let uri = muted_segment_uri(&job.entry.uri)?;
+        let original_url = job.url.clone();
+        job.url = self.media_url.join(&uri).ok()?;
+        job.entry.expected_length = None;
+
+        let result = download_segment(&self.client, &self.rate_limiter, job, &self.manifest).await;
+        job.url = original_url;
+        match result {
+            Ok(written) => {
+                info!("Segment {} is missing, using its muted copy", job.entry.uri);
+                job.entry.muted = true;
+                Some(written)
+            }
+            Err(e) => {
+                debug!("Muted copy of {} is not available: {e}", job.entry.uri);
+                job.entry.expected_length = None;
+                None
+            }
+        }
+    }
+
+    /// Tries to download the original audio of a muted segment into the muted segment's file
+    ///
+    /// Returns `None` when the original audio is not available
+    async fn download_unmuted(&self, job: &mut SegmentJob) -> Option<u64> {
+        let muted_url = job.url.clone();
+
+        for uri in unmuted_segment_uris(&job.entry.uri) {
+            let Ok(url) = self.media_url.join(&uri) else {
+                continue;
+            };
+            job.url = url;
+            job.entry.expected_length = None;
+
+            match download_segment(&self.client, &self.rate_limiter, job, &self.manifest).await {
+                Ok(written) => {
+                    debug!(
+                        "Recovered the original audio of {} from {uri}",
+                        job.entry.uri
+                    );
+                    job.entry.unmuted = true;
+                    job.url = muted_url;
+                    return Some(written);
+                }
+                Err(e) => debug!(
+                    "Original audio of {} is not available at {uri}: {e}",
+                    job.entry.uri
+                ),
+            }
+        }
+
+        job.url = muted_url;
+        job.entry.expected_length = None;
+        job.entry.unmuted = false;
+        None
+    }
+}
+
+/// Downloads a single segment into its file, continuing a partially downloaded file when
+/// its expected length is known
+///
+/// Returns the total length of the segment file
+async fn download_segment(
+    client: &reqwest::Client,
+    rate_limiter: &RateLimiter,
+    job: &mut SegmentJob,
+    manifest: &Mutex<DownloadManifest>,
+) -> Result<u64, SegmentError> {
+    let existing_length = tokio::fs::metadata(&job.file_path)
+        .await
+        .map_or(0, |m| m.len());
+    // A partial file from another source (e.g. the unmuted audio) cannot be continued
+    let source = job.url.as_str();
+    let resume_offset = match job.entry.expected_length {
+        Some(expected)
+            if existing_length < expected && job.entry.source.as_deref() == Some(source) =>
+        {
+            existing_length
+        }
+        _ => 0,
+    };
+
+    let mut req = client.get(job.url.clone());
+    if resume_offset > 0 {
+        req = req.header(RANGE, format!("bytes={resume_offset}-"));
+    }
+    let req = req
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| SegmentError::from_reqwest(e, "Requesting video segment"))?;
+
+    let (mut file, mut written) = if req.status() == StatusCode::PARTIAL_CONTENT {
+        debug!("Resuming {} from byte {resume_offset}", job.entry.uri);
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&job.file_path)
+            .await
+            .context("Opening partially downloaded segment")
+            .map_err(SegmentError::Fatal)?;
+        (file, resume_offset)
+    } else {
+        let file = File::create(&job.file_path)
+            .await
+            .context("Creating segment file")
+            .map_err(SegmentError::Fatal)?;
+        (file, 0)
+    };
+
+    let expected_length = req.content_length().map(|l| l + written);
+    job.entry.expected_length = expected_length;
+    job.entry.source = Some(job.url.to_string());
+    manifest
+        .lock()
+        .await
+        .set_download_source(job.index, job.url.as_str(), expected_length);
+
+    let mut res_stream = req.bytes_stream();
+    while let Some(data) = res_stream.next().await {
+        let data = data.map_err(|e| SegmentError::from_reqwest(e, "Downloading video stream"))?;
+        rate_limiter.acquire(data.len()).await;
+        file.write_all(&data)
+            .await
+            .context("Writing video data to disk")
+            .map_err(SegmentError::Fatal)?;
+        written += data.len() as u64;
+    }
+    file.flush()
+        .await
+        .context("Flushing video data")
+        .map_err(SegmentError::Fatal)?;
+
+    if let Some(expected_length) = expected_length
+        && written != expected_length
+    {
+        return Err(SegmentError::Retriable(anyhow!(
+            "Segment is incomplete ({written} of {expected_length} bytes)"
+        )));
+    }
+
+    Ok(written)
+}
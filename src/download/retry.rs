@@ -0,0 +1,139 @@
+use std::{fmt::Display, time::Duration};
+
+use reqwest::StatusCode;
+
+/// How a failing segment download / upload chunk should be retried
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Amount of retries after the first attempt
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_mins(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry attempt (starting from 1)
+    ///
+    /// Exponential backoff with "full jitter", so parallel segments failing at the same time
+    /// (e.g. a CDN hiccup) won't retry in lockstep
+    #[must_use]
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+
+        ceiling.mul_f64(fastrand::f64())
+    }
+}
+
+/// Share of a VOD's segments which may be missing from the CDN before the download is stopped,
+/// as the download fails anyway unless missing segments are skipped
+const MAX_MISSING_SEGMENTS_PERCENT: usize = 5;
+/// Amount of missing segments always tolerated, for short VODs
+const MIN_MISSING_SEGMENTS_ALLOWED: usize = 5;
+
+/// Error from a single segment download / upload chunk attempt
+#[derive(Debug)]
+pub enum SegmentError {
+    /// Transient errors (timeouts, resets, 5xx, 429) which may succeed when retried
+    Retriable(anyhow::Error),
+    /// The segment is not available on the CDN anymore (404, 410), which happens on old VODs.
+    /// Only this segment fails, see [`too_many_missing_segments`].
+    Missing(anyhow::Error),
+    /// Errors that will never succeed when retried (e.g. expired CDN token, disk errors)
+    Fatal(anyhow::Error),
+}
+
+impl Display for SegmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Retriable(e) => write!(f, "{e:#}"),
+            Self::Missing(e) => write!(f, "{e:#} (segment is missing)"),
+            Self::Fatal(e) => write!(f, "{e:#} (not retriable)"),
+        }
+    }
+}
+
+impl SegmentError {
+    /// Classifies a network error by its kind and HTTP status code
+    #[must_use]
+    pub fn from_reqwest(error: reqwest::Error, context: &'static str) -> Self {
+        let retriable = error.status().map_or_else(
+            || error.is_timeout() || error.is_connect() || error.is_request() || error.is_body(),
+            is_retriable_status,
+        );
+
+        let missing = error.status().is_some_and(is_missing_status);
+
+        let error = anyhow::Error::new(error).context(context);
+        if retriable {
+            Self::Retriable(error)
+        } else if missing {
+            Self::Missing(error)
+        } else {
+            Self::Fatal(error)
+        }
+    }
+
+    #[must_use]
+    pub const fn is_retriable(&self) -> bool {
+        matches!(self, Self::Retriable(_))
+    }
+
+    #[must_use]
+    pub const fn is_missing(&self) -> bool {
+        matches!(self, Self::Missing(_))
+    }
+}
+
+#[must_use]
+pub fn is_retriable_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+#[must_use]
+pub fn is_missing_status(status: StatusCode) -> bool {
+    // 403 means the CDN token expired or is invalid, so every other segment would fail too
+    status == StatusCode::NOT_FOUND || status == StatusCode::GONE
+}
+
+/// Whether so many of `total` segments are missing that the rest will likely be missing too
+#[must_use]
+pub fn too_many_missing_segments(missing: usize, total: usize) -> bool {
+    missing > (total * MAX_MISSING_SEGMENTS_PERCENT / 100).max(MIN_MISSING_SEGMENTS_ALLOWED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_tokens_are_not_missing_segments() {
+        assert!(is_missing_status(StatusCode::NOT_FOUND));
+        assert!(is_missing_status(StatusCode::GONE));
+        assert!(!is_missing_status(StatusCode::FORBIDDEN));
+        assert!(!is_retriable_status(StatusCode::FORBIDDEN));
+        assert!(is_retriable_status(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[test]
+    fn tolerates_a_few_missing_segments() {
+        assert!(!too_many_missing_segments(5, 10));
+        assert!(too_many_missing_segments(6, 10));
+        assert!(!too_many_missing_segments(50, 1000));
+        assert!(too_many_missing_segments(51, 1000));
+    }
+}
@@ -0,0 +1,352 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow, bail};
+use bytes::{Bytes, BytesMut};
+use futures_util::{StreamExt as _, stream::FuturesOrdered};
+use m3u8_rs::MediaSegment;
+use reqwest::Url;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    select,
+    sync::mpsc,
+};
+use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
+use tracing::{debug, info, warn};
+
+use super::{DownloadOptions, SegmentError, verify_ts_packets};
+use crate::{
+    ffmpeg,
+    ratelimit::RateLimiter,
+    twitch::{
+        cdn::{init_segment_uri, is_muted_segment, muted_segment_uri, unmuted_segment_uris},
+        structs::Chapter,
+    },
+};
+
+/// How a segment ended up in a streamed video
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamedSegment {
+    /// Audio was muted by Twitch, or the muted copy of a missing segment was used
+    pub muted: bool,
+    /// The original audio of a muted segment was recovered
+    pub unmuted: bool,
+    /// The segment is not available on the CDN and was skipped
+    pub missing: bool,
+}
+
+/// Downloads segments in parallel and pipes them in order into ffmpeg, remuxing them into
+/// `out_file` without writing any chunks to disk
+///
+/// Streamed downloads cannot be resumed. See [`stream_segments`].
+///
+/// Returns how each segment was downloaded
+///
+/// # Errors
+/// Errors when any segment fails to download or when ffmpeg is unable to remux the video
+pub async fn download_streaming(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    options: &DownloadOptions,
+    media_url: &Url,
+    segments: &[MediaSegment],
+    out_file: &Path,
+    chapters: &[Chapter],
+) -> Result<Vec<StreamedSegment>> {
+    let input_format = if init_segment_uri(segments).is_some() {
+        "mp4"
+    } else {
+        "mpegts"
+    };
+    let mut ffmpeg = ffmpeg::spawn_stdin_remux(out_file, input_format, chapters).await?;
+    let mut ffmpeg_stdin = ffmpeg.stdin.take().unwrap();
+
+    let result = stream_segments(ct, client, options, media_url, segments, &mut ffmpeg_stdin).await;
+    let result = match result {
+        Ok(streamed) => ffmpeg_stdin
+            .shutdown()
+            .await
+            .context("Closing ffmpeg input")
+            .map(|()| streamed),
+        Err(e) => Err(e),
+    };
+    drop(ffmpeg_stdin);
+
+    if ct.is_cancelled() || result.is_err() {
+        ffmpeg.kill().await.ok();
+        return result;
+    }
+
+    ffmpeg::wait_for_exit(ffmpeg, "Video remuxing").await?;
+    info!("Done streaming all chunks!");
+
+    result
+}
+
+/// Downloads segments in parallel and writes them in order into `writer`, behind the
+/// initialization segment of fMP4 variants
+///
+/// Segments are downloaded in a separate task, so downloads carry on while `writer` is busy. At
+/// most twice [`DownloadOptions::parallelism`] segments are downloaded ahead and kept in memory
+/// while waiting for their turn. `writer` is not closed afterward.
+///
+/// Segments missing from the CDN are replaced by their muted copy, and otherwise skipped only
+/// when [`DownloadOptions::skip_missing_segments`] is set, like [`super::download`] does.
+///
+/// Returns how each segment was downloaded. Stops early without an error when `ct` is cancelled.
+///
+/// # Errors
+/// Errors when any segment fails to download, when a segment is missing and may not be skipped or
+/// when the data cannot be written
+pub async fn stream_segments<W>(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    options: &DownloadOptions,
+    media_url: &Url,
+    segments: &[MediaSegment],
+    writer: &mut W,
+) -> Result<Vec<StreamedSegment>>
+where
+    W: AsyncWrite + Unpin,
+{
+    // fMP4 segments are only playable behind their initialization segment
+    if let Some(uri) = init_segment_uri(segments) {
+        let url = media_url.join(uri)?;
+        let init = select! {
+            () = ct.cancelled() => return Ok(Vec::new()),
+            init = fetch_with_retry(ct, client, options, url, uri) => init?,
+        };
+        writer
+            .write_all(&init)
+            .await
+            .context("Writing streamed video data")?;
+    }
+
+    // Downloads keep going while the writer is busy, until the channel is full
+    let (tx, mut rx) = mpsc::channel(options.parallelism.max(1));
+    let _fetcher = AbortOnDropHandle::new(tokio::spawn(fetch_segments(
+        ct.clone(),
+        client.clone(),
+        options.clone(),
+        media_url.clone(),
+        segments.to_vec(),
+        tx,
+    )));
+
+    let pb = indicatif::ProgressBar::new(segments.len() as u64);
+    let mut streamed = Vec::with_capacity(segments.len());
+    let result = async {
+        loop {
+            let next = select! {
+                () = ct.cancelled() => return Ok(()),
+                next = rx.recv() => next.transpose()?,
+            };
+            let Some((data, segment)) = next else {
+                return Ok(());
+            };
+
+            writer
+                .write_all(&data)
+                .await
+                .context("Writing streamed video data")?;
+            streamed.push(segment);
+            pb.inc(1);
+        }
+    }
+    .await;
+    pb.finish_and_clear();
+
+    result.map(|()| streamed)
+}
+
+/// Downloads segments in parallel, sending them in order into `tx`
+///
+/// Stops at the first failing segment, after sending its error, or when `tx` is closed
+async fn fetch_segments(
+    ct: CancellationToken,
+    client: reqwest::Client,
+    options: DownloadOptions,
+    media_url: Url,
+    segments: Vec<MediaSegment>,
+    tx: mpsc::Sender<Result<(Bytes, StreamedSegment)>>,
+) {
+    let mut pending = segments.iter();
+    let mut fetches = FuturesOrdered::new();
+    loop {
+        while fetches.len() < options.parallelism.max(1)
+            && let Some(segment) = pending.next()
+        {
+            fetches.push_back(fetch_segment(&ct, &client, &options, &media_url, segment));
+        }
+        let Some(result) = fetches.next().await else {
+            break;
+        };
+
+        let failed = result.is_err();
+        if tx.send(result).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+/// Downloads a segment into memory, retrying retriable failures with a backoff
+///
+/// Returns the segment data, which is empty for missing segments, and how it was downloaded
+async fn fetch_segment(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    options: &DownloadOptions,
+    media_url: &Url,
+    segment: &MediaSegment,
+) -> Result<(Bytes, StreamedSegment)> {
+    let muted = is_muted_segment(segment);
+    if options.try_unmute && muted {
+        for uri in unmuted_segment_uris(&segment.uri) {
+            let Ok(url) = media_url.join(&uri) else {
+                continue;
+            };
+
+            match fetch_segment_once(client, &options.rate_limiter, url).await {
+                Ok(data) => {
+                    debug!("Recovered the original audio of {} from {uri}", segment.uri);
+                    let streamed = StreamedSegment {
+                        muted,
+                        unmuted: true,
+                        missing: false,
+                    };
+                    return Ok((data, streamed));
+                }
+                Err(e) => debug!(
+                    "Original audio of {} is not available at {uri}: {e}",
+                    segment.uri
+                ),
+            }
+        }
+    }
+
+    let url = media_url.join(&segment.uri)?;
+    let error = match retry_segment(ct, client, options, url, &segment.uri).await {
+        Ok(data) => {
+            let streamed = StreamedSegment {
+                muted,
+                ..Default::default()
+            };
+            return Ok((data, streamed));
+        }
+        Err(e) if e.is_missing() => e,
+        Err(e) => bail!("Segment {} failed: {e}", segment.uri),
+    };
+
+    if let Some(uri) = muted_segment_uri(&segment.uri) {
+        let url = media_url.join(&uri)?;
+        match fetch_segment_once(client, &options.rate_limiter, url).await {
+            Ok(data) => {
+                info!("Segment {} is missing, using its muted copy", segment.uri);
+                let streamed = StreamedSegment {
+                    muted: true,
+                    ..Default::default()
+                };
+                return Ok((data, streamed));
+            }
+            Err(e) => debug!("Muted copy of {} is not available: {e}", segment.uri),
+        }
+    }
+
+    if !options.skip_missing_segments {
+        bail!(
+            "Segment {} is missing from Twitch's CDN: {error}. Use `--skip-missing-segments` to archive the VOD without it",
+            segment.uri
+        );
+    }
+    warn!("Segment {} is missing, skipping it: {error}", segment.uri);
+    let streamed = StreamedSegment {
+        muted,
+        unmuted: false,
+        missing: true,
+    };
+    Ok((Bytes::new(), streamed))
+}
+
+/// Downloads a segment at `url` into memory, retrying retriable failures with a backoff
+///
+/// # Errors
+/// Errors when the segment keeps failing to download or when cancelled while waiting to retry
+pub async fn fetch_with_retry(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    options: &DownloadOptions,
+    url: Url,
+    uri: &str,
+) -> Result<Bytes> {
+    retry_segment(ct, client, options, url, uri)
+        .await
+        .map_err(|e| anyhow!("Segment {uri} failed: {e}"))
+}
+
+async fn retry_segment(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    options: &DownloadOptions,
+    url: Url,
+    uri: &str,
+) -> Result<Bytes, SegmentError> {
+    let retry_policy = &options.retry_policy;
+    let mut attempt = 0;
+    loop {
+        match fetch_segment_once(client, &options.rate_limiter, url.clone()).await {
+            Ok(data) => return Ok(data),
+            Err(e) if e.is_retriable() && attempt < retry_policy.max_retries => {
+                attempt += 1;
+                let delay = retry_policy.backoff(attempt);
+                warn!(
+                    "Segment {uri} failed, retrying in {delay:.1?} ({attempt}/{}): {e}",
+                    retry_policy.max_retries
+                );
+
+                select! {
+                    () = ct.cancelled() => return Err(e),
+                    () = tokio::time::sleep(delay) => {}
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn fetch_segment_once(
+    client: &reqwest::Client,
+    rate_limiter: &RateLimiter,
+    url: Url,
+) -> Result<Bytes, SegmentError> {
+    // Only MPEG-TS segments have packets to check, fMP4 ones are only checked by length
+    let is_ts = Path::new(url.path()).extension().is_some_and(|e| e == "ts");
+    let req = client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| SegmentError::from_reqwest(e, "Requesting video segment"))?;
+
+    let expected_length = req.content_length();
+    let mut data =
+        BytesMut::with_capacity(expected_length.unwrap_or_default().try_into().unwrap_or(0));
+    let mut res_stream = req.bytes_stream();
+    while let Some(chunk) = res_stream.next().await {
+        let chunk = chunk.map_err(|e| SegmentError::from_reqwest(e, "Downloading video stream"))?;
+        rate_limiter.acquire(chunk.len()).await;
+        data.extend_from_slice(&chunk);
+    }
+
+    if let Some(expected_length) = expected_length
+        && data.len() as u64 != expected_length
+    {
+        return Err(SegmentError::Retriable(anyhow!(
+            "Segment is incomplete ({} of {expected_length} bytes)",
+            data.len()
+        )));
+    }
+    if is_ts {
+        verify_ts_packets(&data, 0).map_err(SegmentError::Retriable)?;
+    }
+
+    Ok(data.freeze())
+}
@@ -0,0 +1,149 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail, ensure};
+use futures_util::{StreamExt as _, stream};
+use tokio::fs::File;
+
+use super::SegmentEntry;
+use crate::util::read_full;
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+/// Checks that a downloaded segment file is complete
+///
+/// The file length is compared against the length reported by the CDN, and MPEG-TS segments
+/// (`.ts`) are checked to be made out of whole 188-byte packets starting with a sync byte.
+/// Other segment containers (e.g. fMP4 on AV1 / HEVC variants) are only checked by length.
+///
+/// # Errors
+/// Errors with the reason when the segment is incomplete or corrupted
+pub async fn verify_segment(path: &Path, expected_length: Option<u64>) -> Result<()> {
+    let mut file = File::open(path).await.context("Opening segment")?;
+    let length = file
+        .metadata()
+        .await
+        .context("Reading segment metadata")?
+        .len();
+
+    ensure!(length > 0, "Segment is empty");
+    if let Some(expected_length) = expected_length {
+        ensure!(
+            length == expected_length,
+            "Segment is {length} bytes, expected {expected_length} bytes"
+        );
+    }
+
+    if path.extension().is_none_or(|e| e != "ts") {
+        return Ok(());
+    }
+
+    ensure!(
+        length.is_multiple_of(TS_PACKET_SIZE as u64),
+        "Segment length {length} is not aligned to {TS_PACKET_SIZE}-byte MPEG-TS packets"
+    );
+
+    let mut buf = vec![0; TS_PACKET_SIZE * 1024];
+    let mut offset = 0;
+    loop {
+        // Fill the whole buffer so every read starts on a packet boundary
+        let filled = read_full(&mut file, &mut buf)
+            .await
+            .context("Reading segment")?;
+        if filled == 0 {
+            return Ok(());
+        }
+
+        verify_ts_packets(&buf[..filled], offset)?;
+        offset += filled;
+    }
+}
+
+/// Checks that every 188-byte MPEG-TS packet in `data` starts with a sync byte
+///
+/// `offset` is the position of `data` inside of the segment, used for the error message
+///
+/// # Errors
+/// Errors with the position of the first packet missing its sync byte
+pub fn verify_ts_packets(data: &[u8], offset: usize) -> Result<()> {
+    if let Some(position) = data
+        .iter()
+        .step_by(TS_PACKET_SIZE)
+        .position(|b| *b != TS_SYNC_BYTE)
+    {
+        bail!(
+            "Missing MPEG-TS sync byte at offset {}",
+            offset + position * TS_PACKET_SIZE
+        );
+    }
+
+    ensure!(
+        data.len().is_multiple_of(TS_PACKET_SIZE),
+        "Segment length {} is not aligned to {TS_PACKET_SIZE}-byte MPEG-TS packets",
+        offset + data.len()
+    );
+
+    Ok(())
+}
+
+/// Verifies downloaded segments in parallel
+///
+/// Returns the segments which failed verification along with the reason
+pub async fn verify_segments(
+    download_dir: &Path,
+    segments: Vec<(usize, SegmentEntry)>,
+    parallelism: usize,
+) -> Vec<(usize, SegmentEntry, anyhow::Error)> {
+    stream::iter(segments)
+        .map(|(index, entry)| async move {
+            verify_segment(&download_dir.join(&entry.uri), entry.expected_length)
+                .await
+                .err()
+                .map(|e| (index, entry, e))
+        })
+        .buffer_unordered(parallelism)
+        .filter_map(std::future::ready)
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets(count: usize) -> Vec<u8> {
+        let mut data = vec![0; TS_PACKET_SIZE * count];
+        for packet in data.chunks_mut(TS_PACKET_SIZE) {
+            packet[0] = TS_SYNC_BYTE;
+        }
+        data
+    }
+
+    #[test]
+    fn accepts_whole_packets() {
+        assert!(verify_ts_packets(&packets(3), 0).is_ok());
+        assert!(verify_ts_packets(&[], 0).is_ok());
+    }
+
+    #[test]
+    fn reports_missing_sync_byte_offset() {
+        let mut data = packets(3);
+        data[TS_PACKET_SIZE * 2] = 0;
+
+        let error = verify_ts_packets(&data, TS_PACKET_SIZE * 10).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Missing MPEG-TS sync byte at offset {}",
+                TS_PACKET_SIZE * 12
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_partial_packet() {
+        let mut data = packets(2);
+        data.truncate(TS_PACKET_SIZE + 10);
+        assert!(verify_ts_packets(&data, 0).is_err());
+    }
+}
@@ -11,22 +11,41 @@ use tokio::sync::{Notify, OnceCell, mpsc};
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info, instrument, warn};
 
+use crate::twitch::structs::LiveStream;
+
 const TWITCH_EVENTSUB_ADD_URL: &str = "https://api.twitch.tv/helix/eventsub/subscriptions";
 const TWITCH_EVENTSUB_WS_URL: &str = "wss://eventsub.wss.twitch.tv/ws?keepalive_timeout_seconds=30";
 
 // https://twitchtokengenerator.com
 const TWITCH_OAUTH_CLIENT_ID: &str = "gp762nuuoqcoxypju8c569th9wz7q5";
 
-/// Initiates a Twitch eventsub feed listening for channels going offline after stream
+/// A channel going live / offline
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    /// A channel started a live stream, which gets saved as a past broadcast
+    Online {
+        broadcaster_id: u64,
+        stream: LiveStream,
+    },
+    Offline {
+        broadcaster_id: u64,
+    },
+}
+
+/// Initiates a Twitch eventsub feed listening for channels going live and offline
 ///
 /// # Returns
-/// Returns an [`mpsc::Receiver`] channel with channel ID as a message
-/// Each message equates to a channel going offline
+/// Returns an [`mpsc::UnboundedReceiver`] channel with a [`StreamEvent`] as a message
+///
+/// Events are buffered so that the connection is kept alive while a VOD is being archived
+///
+/// # Errors
+/// Never errors for now, the connection panics on failures instead
 #[instrument(skip(ct, broadcaster_ids))]
-pub async fn listen_for_offline(
+pub async fn listen_for_streams(
     ct: CancellationToken,
     broadcaster_ids: Vec<u64>,
-) -> Result<mpsc::Receiver<u64>> {
+) -> Result<mpsc::UnboundedReceiver<StreamEvent>> {
     let client = reqwest::Client::builder()
         .user_agent(format!(
             "{}/{} (+{})",
@@ -38,7 +57,7 @@ pub async fn listen_for_offline(
         .build()
         .unwrap();
 
-    let (tx, rx) = mpsc::channel::<u64>(1);
+    let (tx, rx) = mpsc::unbounded_channel::<StreamEvent>();
 
     let session_id = Arc::new(tokio::sync::OnceCell::new());
     let notify = Arc::new(tokio::sync::Notify::new());
@@ -56,7 +75,7 @@ pub async fn listen_for_offline(
                 info!("(re-)Connecting to Twitch EventSub via WebSocket");
                 let upgrade = ws_client
                     .clone()
-                    .get(connection_url.unwrap_or(TWITCH_EVENTSUB_WS_URL.to_string()))
+                    .get(connection_url.unwrap_or_else(|| TWITCH_EVENTSUB_WS_URL.to_string()))
                     .upgrade()
                     .send()
                     .await
@@ -78,7 +97,7 @@ pub async fn listen_for_offline(
                             panic!("Didn't get any message for 40s. Connection is effectively poisoned!")
                             // TODO: Handle re-registration (resend EventSub subscriptions)
                         },
-                        Some(Ok(message)) = stream.next() => handle_ws_message(message, &session_id, &notify, &tx).await
+                        Some(Ok(message)) = stream.next() => handle_ws_message(message, &session_id, &notify, &tx)
                     };
 
                     if let SocketAction::Reconnect(url) = action {
@@ -92,34 +111,34 @@ pub async fn listen_for_offline(
 
     // Register streamer once session_id is initialized
     {
-        let session_id = session_id.clone();
-        let notify = notify.clone();
         tokio::spawn(async move {
             notify.notified().await;
             info!("EventSub Session ID: {}", session_id.get().unwrap());
 
             for id in broadcaster_ids {
-                info!("Requesting Twitch to send `stream.offline` event for broadcaster ID {id}");
-                let req = client
-                    .post(TWITCH_EVENTSUB_ADD_URL)
-                    .header("Client-Id", TWITCH_OAUTH_CLIENT_ID)
-                    .bearer_auth(
-                        std::env::var("TWITCH_OAUTH_ACCESS_TOKEN")
-                        .expect("Env var TWITCH_OAUTH_ACCESS_TOKEN is missing; Generate one on https://twitchtokengenerator.com/quick/yRxQrfaVAK"),
-                    )
-                    .json(&json!({
-                        "type": "stream.offline",
-                        "version": "1",
-                        "condition": { "broadcaster_user_id": id.to_string() },
-                        "transport": { "method": "websocket", "session_id": session_id.get().unwrap() }
-                    }))
-                    .send()
-                    .await
-                    .context(format!("Sending `stream.offline` event request for userid {id}"))
-                    .unwrap();
+                for event in ["stream.online", "stream.offline"] {
+                    info!("Requesting Twitch to send `{event}` event for broadcaster ID {id}");
+                    let req = client
+                        .post(TWITCH_EVENTSUB_ADD_URL)
+                        .header("Client-Id", TWITCH_OAUTH_CLIENT_ID)
+                        .bearer_auth(
+                            std::env::var("TWITCH_OAUTH_ACCESS_TOKEN")
+                            .expect("Env var TWITCH_OAUTH_ACCESS_TOKEN is missing; Generate one on https://twitchtokengenerator.com/quick/yRxQrfaVAK"),
+                        )
+                        .json(&json!({
+                            "type": event,
+                            "version": "1",
+                            "condition": { "broadcaster_user_id": id.to_string() },
+                            "transport": { "method": "websocket", "session_id": session_id.get().unwrap() }
+                        }))
+                        .send()
+                        .await
+                        .context(format!("Sending `{event}` event request for userid {id}"))
+                        .unwrap();
 
-                if !req.status().is_success() {
-                    error!("Request failed: {}", req.text().await.unwrap());
+                    if !req.status().is_success() {
+                        error!("Request failed: {}", req.text().await.unwrap());
+                    }
                 }
             }
 
@@ -134,11 +153,11 @@ enum SocketAction {
     None,
     Reconnect(String),
 }
-async fn handle_ws_message(
+fn handle_ws_message(
     message: reqwest_websocket::Message,
     session_id: &OnceCell<Value>,
     notify: &Arc<Notify>,
-    tx: &mpsc::Sender<u64>,
+    tx: &mpsc::UnboundedSender<StreamEvent>,
 ) -> SocketAction {
     match message {
         Message::Text(m) => {
@@ -160,31 +179,55 @@ async fn handle_ws_message(
             match message_type.as_str() {
                 "session_keepalive" => {
                     // Noop
-                    return SocketAction::None;
+                    SocketAction::None
                 }
                 "notification" => {
                     info!("Received notification message!");
-                    let channel_id = &m["payload"]["event"]["broadcaster_user_id"]
+                    let event = &m["payload"]["event"];
+                    let broadcaster_id = event["broadcaster_user_id"]
                         .as_str()
-                        .context("Stream offline notification message does not contain broadcaster_user_id!")
+                        .context(
+                            "Stream notification message does not contain broadcaster_user_id!",
+                        )
                         .unwrap()
                         .parse::<u64>()
                         .unwrap();
-                    tx.send(*channel_id)
-                        .await
-                        .expect("Unable to announce offline");
 
-                    return SocketAction::None;
+                    let stream_event = match m["metadata"]["subscription_type"].as_str() {
+                        // Reruns, premieres and watch parties are not saved as past broadcasts
+                        Some("stream.online") if event["type"] != "live" => {
+                            info!(
+                                "Ignoring `{}` stream of broadcaster ID {broadcaster_id}",
+                                event["type"]
+                            );
+                            return SocketAction::None;
+                        }
+                        Some("stream.online") => StreamEvent::Online {
+                            broadcaster_id,
+                            stream: serde_json::from_value(event.clone())
+                                .context("Parsing stream online notification")
+                                .unwrap(),
+                        },
+                        Some("stream.offline") => StreamEvent::Offline { broadcaster_id },
+                        other => {
+                            warn!("Unhandled subscription type: {other:?}");
+                            return SocketAction::None;
+                        }
+                    };
+                    tx.send(stream_event)
+                        .expect("Unable to announce stream event");
+
+                    SocketAction::None
                 }
                 "session_reconnect" => {
                     info!("Need to reconnect!");
                     let reconnect_url = m["payload"]["session"]["reconnect_url"].as_str().unwrap();
-                    return SocketAction::Reconnect(reconnect_url.to_string());
+                    SocketAction::Reconnect(reconnect_url.to_string())
                 }
 
                 other => {
                     warn!("Unhandled message type: {other}");
-                    return SocketAction::None;
+                    SocketAction::None
                 }
             }
         }
@@ -195,8 +238,6 @@ async fn handle_ws_message(
             panic!("Twitch closed WS connection!");
         }
 
-        _ => {
-            return SocketAction::None;
-        }
+        _ => SocketAction::None,
     }
 }
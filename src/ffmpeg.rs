@@ -1,8 +1,25 @@
-use std::{io::ErrorKind, path::Path, process::Stdio, str::FromStr};
+use std::{
+    ffi::OsStr,
+    fmt::Write as _,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    time::Duration,
+};
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, bail, ensure};
+use tokio::process::Child;
 use tracing::{debug, error};
 
+use crate::twitch::structs::Chapter;
+
+const CONCAT_LIST_FILE_NAME: &str = "concat.ffconcat";
+const CHAPTERS_FILE_NAME: &str = "chapters.ffmetadata";
+const PART_FILE_PREFIX: &str = "part_";
+/// How much shorter than the maximum duration parts are aimed to be
+const SPLIT_MARGIN: Duration = Duration::from_mins(1);
+
 /// Checks if ffmpeg is installed / available in PATH
 ///
 /// # Panics
@@ -21,29 +38,89 @@ pub async fn is_installed() -> bool {
         .success()
 }
 
-/// Concatenate videos using concat protocol\
-/// See: <https://trac.ffmpeg.org/wiki/Concatenate#protocol>
+/// The first line of `ffmpeg -version`, e.g. `ffmpeg version 7.1 Copyright (c) 2000-2024 ...`
+///
+/// # Errors
+/// Errors when `ffmpeg` is not installed or exits unsuccessfully
+pub async fn version() -> Result<String> {
+    let out = match tokio::process::Command::new("ffmpeg")
+        .arg("-version")
+        .output()
+        .await
+    {
+        Ok(o) => o,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!("`ffmpeg` is not installed or available in PATH!")
+        }
+        Err(e) => bail!("Unknown error: {e}"),
+    };
+    ensure!(out.status.success(), "FFMPEG exit code not success");
+
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// How video chunks are concatenated by ffmpeg
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConcatMethod {
+    /// Concat demuxer reading a generated list file\
+    /// See: <https://trac.ffmpeg.org/wiki/Concatenate#demuxer>
+    #[default]
+    Demuxer,
+    /// Concat protocol with every chunk in a single argument. Opens every chunk at once,
+    /// which may hit the open files limit and argument length limit on long videos\
+    /// See: <https://trac.ffmpeg.org/wiki/Concatenate#protocol>
+    Protocol,
+}
+
+/// Concatenate videos inside a directory into a single file, embedding `chapters` into it
 ///
 /// # Errors
+/// Errors when the concat list or chapters file cannot be written, when `ffmpeg` is not
+/// installed or when it exits unsuccessfully
 ///
 /// # Panics
-/// Will panic if FFMPEG returned an error (e.g. not installed, encoding errors), process cannot be spawned or if there is an error while awaiting its status.\
+/// Will panic if the process cannot be spawned or if there is an error while awaiting its status.\
 /// See `tokio::process::Command.status()`
 pub async fn concat_video(
     video_directory: &Path,
     file_names: Vec<String>,
     out_file: &Path,
+    method: ConcatMethod,
+    chapters: &[Chapter],
 ) -> Result<()> {
-    // TODO: Compare / consider using concatenate demuxer
-    // Might fix ulimit issues as it might force ffmpeg to handle open files
-    let mut input_string = String::from_str("concat:").unwrap();
-    input_string.push_str(
-        &file_names
-            .iter()
-            .map(|f| video_directory.join(f).to_str().unwrap().to_string())
-            .collect::<Vec<String>>()
-            .join("|"),
-    );
+    let input_args = match method {
+        ConcatMethod::Demuxer => {
+            let list_path = video_directory.join(CONCAT_LIST_FILE_NAME);
+            write_concat_list(&list_path, &file_names).await?;
+
+            vec![
+                "-f".to_string(),
+                "concat".to_string(),
+                // Allow any file name, list entries are generated from the media playlist
+                "-safe".to_string(),
+                "0".to_string(),
+                "-i".to_string(),
+                list_path.to_str().unwrap().to_string(),
+            ]
+        }
+        ConcatMethod::Protocol => {
+            let mut input_string = String::from_str("concat:").unwrap();
+            input_string.push_str(
+                &file_names
+                    .iter()
+                    .map(|f| video_directory.join(f).to_str().unwrap().to_string())
+                    .collect::<Vec<String>>()
+                    .join("|"),
+            );
+
+            vec!["-i".to_string(), input_string]
+        }
+    };
+    let chapter_args = chapter_args(out_file, chapters).await?;
 
     // TODO: Use FFMPEG's actual API for efficiency
     let child = match tokio::process::Command::new("ffmpeg")
@@ -54,12 +131,177 @@ pub async fn concat_video(
             "error",
             "-avoid_negative_ts",
             "make_zero",
+        ])
+        .args(input_args)
+        .args(chapter_args)
+        .args(["-c", "copy", out_file.to_str().unwrap()])
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!("`ffmpeg` is not installed or available in PATH!")
+        }
+        Err(e) => bail!("Unknown error: {e}"),
+    };
+
+    wait_for_exit(child, "Video concatenation").await
+}
+
+/// Spawns ffmpeg remuxing a stream of `input_format` (`mpegts` / `mp4`) written into its stdin
+/// into `out_file`, embedding `chapters` into it
+///
+/// Close the child's stdin once everything is written, then wait for it with [`wait_for_exit`]
+///
+/// # Errors
+/// Errors when the chapters file cannot be written, when `ffmpeg` is not installed or the
+/// process cannot be spawned
+pub async fn spawn_stdin_remux(
+    out_file: &Path,
+    input_format: &str,
+    chapters: &[Chapter],
+) -> Result<Child> {
+    let chapter_args = chapter_args(out_file, chapters).await?;
+
+    match tokio::process::Command::new("ffmpeg")
+        .args([
+            "-y",
+            "-loglevel",
+            "error",
+            "-f",
+            input_format,
             "-i",
-            &input_string,
+            "pipe:0",
+        ])
+        .args(chapter_args)
+        .args(["-c", "copy", out_file.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(c) => Ok(c),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!("`ffmpeg` is not installed or available in PATH!")
+        }
+        Err(e) => bail!("Unknown error: {e}"),
+    }
+}
+
+/// Maximum duration and size of every part a video is split into
+#[derive(Debug, Clone, Copy)]
+pub struct SplitLimits {
+    pub max_duration: Duration,
+    /// In bytes
+    pub max_size: u64,
+}
+
+impl SplitLimits {
+    /// The amount of parts a video has to be split into to stay under the limits
+    ///
+    /// Parts are aimed a minute shorter than the maximum duration, as they are cut at the first
+    /// keyframe after each split point
+    #[must_use]
+    pub fn part_count(&self, duration: Duration, size: u64) -> usize {
+        let max_duration = self
+            .max_duration
+            .saturating_sub(SPLIT_MARGIN)
+            .max(SPLIT_MARGIN)
+            .as_millis();
+        let by_duration = duration.as_millis().div_ceil(max_duration);
+        let by_size = u128::from(size.div_ceil(self.max_size.max(1)));
+
+        usize::try_from(by_duration.max(by_size))
+            .unwrap_or(usize::MAX)
+            .max(1)
+    }
+}
+
+/// Reads the duration of a video using `ffprobe`
+///
+/// # Errors
+/// Errors when `ffprobe` is not installed or it is unable to read the video
+pub async fn probe_duration(video: &Path) -> Result<Duration> {
+    let out = match tokio::process::Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(video)
+        .output()
+        .await
+    {
+        Ok(o) => o,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!("`ffprobe` is not installed or available in PATH!")
+        }
+        Err(e) => bail!("Unknown error: {e}"),
+    };
+
+    if !out.status.success() {
+        bail!(
+            "Unable to read video duration: {}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+
+    let seconds = String::from_utf8_lossy(&out.stdout)
+        .trim()
+        .parse::<f64>()
+        .context("Parsing video duration")?;
+    Duration::try_from_secs_f64(seconds).context("Invalid video duration")
+}
+
+/// Splits a video into `parts` parts of roughly equal duration inside of `out_dir`, without
+/// re-encoding it
+///
+/// Parts are cut at the first keyframe after each split point, so they can be a bit longer than
+/// `duration / parts`. Returns the paths of the parts in order.\
+/// See: <https://ffmpeg.org/ffmpeg-formats.html#segment>
+///
+/// # Errors
+/// Errors when `ffmpeg` is not installed or when it exits unsuccessfully
+pub async fn split_video(
+    video: &Path,
+    out_dir: &Path,
+    duration: Duration,
+    parts: usize,
+) -> Result<Vec<PathBuf>> {
+    ensure!(parts > 1, "A video must be split into at least 2 parts");
+
+    let parts_u128 = parts as u128;
+    let split_times = (1..parts_u128)
+        .map(|i| {
+            let millis = duration.as_millis() * i / parts_u128;
+            format!("{}.{:03}", millis / 1000, millis % 1000)
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let child = match tokio::process::Command::new("ffmpeg")
+        .args(["-stats", "-y", "-loglevel", "error", "-i"])
+        .arg(video)
+        .args([
+            "-map",
+            "0",
+            // Chapters are timed from the start of the whole video
+            "-map_chapters",
+            "-1",
             "-c",
             "copy",
-            out_file.to_str().unwrap(),
+            "-f",
+            "segment",
+            "-segment_times",
+            &split_times,
+            "-segment_format",
+            "mp4",
+            "-reset_timestamps",
+            "1",
         ])
+        .arg(out_dir.join(format!("{PART_FILE_PREFIX}%03d.mp4")))
         .spawn()
     {
         Ok(c) => c,
@@ -69,9 +311,149 @@ pub async fn concat_video(
         Err(e) => bail!("Unknown error: {e}"),
     };
 
+    wait_for_exit(child, "Video splitting").await?;
+
+    let mut paths = Vec::with_capacity(parts);
+    for part in 0..parts {
+        let path = out_dir.join(format!("{PART_FILE_PREFIX}{part:03}.mp4"));
+        // Split points past the end of the video do not create a part
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            break;
+        }
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+/// Copies `video` into `out_file` with `subtitles` added as a subtitle stream titled `title`
+///
+/// MP4 / MOV only support plain text subtitles and `WebM` only `WebVTT`, so styling (e.g. ASS
+/// colors) is lost when muxing into them. Matroska keeps subtitles as they are.
+///
+/// # Errors
+/// Errors when `ffmpeg` is not installed or when it exits unsuccessfully
+pub async fn mux_subtitles(
+    video: &Path,
+    subtitles: &Path,
+    out_file: &Path,
+    title: &str,
+) -> Result<()> {
+    let extension = out_file
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase);
+    let subtitle_codec = match extension.as_deref() {
+        Some("mp4" | "m4v" | "mov") => "mov_text",
+        Some("webm") => "webvtt",
+        _ => "copy",
+    };
+
+    let child = match tokio::process::Command::new("ffmpeg")
+        .args(["-stats", "-y", "-loglevel", "error", "-i"])
+        .arg(video)
+        .arg("-i")
+        .arg(subtitles)
+        .args([
+            "-map",
+            "0",
+            "-map",
+            "1",
+            "-c",
+            "copy",
+            "-c:s",
+            subtitle_codec,
+            "-metadata:s:s:0",
+            &format!("title={title}"),
+        ])
+        .arg(out_file)
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!("`ffmpeg` is not installed or available in PATH!")
+        }
+        Err(e) => bail!("Unknown error: {e}"),
+    };
+
+    wait_for_exit(child, "Subtitle muxing").await
+}
+
+/// Encodes a single frame of `input` into a JPEG thumbnail, scaled down to fit 1280x720
+///
+/// `input` can be a video / image file or URL, and is seeked to `seek` first. `quality` is
+/// passed to `-q:v`, ranging from 2 (best) to 31 (worst).
+///
+/// # Errors
+/// Errors when `ffmpeg` is not installed, when it exits unsuccessfully or when there is no frame
+/// at `seek`
+pub async fn encode_thumbnail(
+    input: &OsStr,
+    seek: Option<Duration>,
+    quality: u8,
+) -> Result<Vec<u8>> {
+    let mut command = tokio::process::Command::new("ffmpeg");
+    command.args(["-y", "-loglevel", "error"]);
+    if let Some(seek) = seek {
+        command.args(["-ss", &format!("{:.3}", seek.as_secs_f64())]);
+    }
+    command
+        .arg("-i")
+        .arg(input)
+        .args([
+            "-frames:v",
+            "1",
+            "-vf",
+            "scale=w=1280:h=720:force_original_aspect_ratio=decrease",
+            "-q:v",
+            &quality.to_string(),
+            "-f",
+            "image2pipe",
+            "-c:v",
+            "mjpeg",
+            "pipe:1",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let child = match command.spawn() {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!("`ffmpeg` is not installed or available in PATH!")
+        }
+        Err(e) => bail!("Unknown error: {e}"),
+    };
+
+    let out = child
+        .wait_with_output()
+        .await
+        .context("Waiting for thumbnail encoding")?;
+    if !out.status.success() {
+        bail!(
+            "Unable to encode thumbnail: {}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+    ensure!(
+        !out.stdout.is_empty(),
+        "There is no frame to make a thumbnail from"
+    );
+
+    Ok(out.stdout)
+}
+
+/// Waits for an ffmpeg process to exit, logging its output when it was not successful
+///
+/// # Errors
+/// Errors when the process exits unsuccessfully
+///
+/// # Panics
+/// Will panic if there is an error while awaiting the process' status
+pub async fn wait_for_exit(child: Child, action: &str) -> Result<()> {
     let out = child.wait_with_output().await.unwrap();
     if !out.status.success() {
-        error!("Video concatenation is unsuccessful");
+        error!("{action} is unsuccessful");
         error!("stdout: {}", String::from_utf8_lossy(&out.stdout));
         error!("stderr: {}", String::from_utf8_lossy(&out.stderr));
         bail!("FFMPEG exit code not success")
@@ -79,3 +461,128 @@ pub async fn concat_video(
 
     Ok(())
 }
+
+/// Writes `chapters` into an `ffmetadata` file next to `out_file`, returning the arguments
+/// adding it as the second input of ffmpeg
+///
+/// Returns no arguments when there are no chapters, keeping ffmpeg's default stream selection
+async fn chapter_args(out_file: &Path, chapters: &[Chapter]) -> Result<Vec<String>> {
+    if chapters.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut metadata = String::from(";FFMETADATA1\n");
+    for chapter in chapters {
+        let _ = write!(
+            metadata,
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            chapter.start.as_millis(),
+            chapter.end.as_millis(),
+            escape_metadata_value(&chapter.title)
+        );
+    }
+
+    let metadata_path = out_file.with_file_name(CHAPTERS_FILE_NAME);
+    tokio::fs::write(&metadata_path, metadata)
+        .await
+        .context("Writing chapters file")?;
+
+    Ok(vec![
+        "-f".to_string(),
+        "ffmetadata".to_string(),
+        "-i".to_string(),
+        metadata_path.to_str().unwrap().to_string(),
+        // Streams are mapped explicitly once there is a second input. Twitch's timed metadata
+        // stream is left out as MP4 cannot hold it
+        "-map".to_string(),
+        "0:v?".to_string(),
+        "-map".to_string(),
+        "0:a?".to_string(),
+        "-map_chapters".to_string(),
+        "1".to_string(),
+    ])
+}
+
+/// Escapes special characters of an `ffmetadata` value
+///
+/// See: <https://ffmpeg.org/ffmpeg-formats.html#Metadata-2>
+fn escape_metadata_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Writes an `ffconcat` list file for the concat demuxer\
+/// Relative file names are resolved from the list file's directory
+///
+/// See: <https://ffmpeg.org/ffmpeg-formats.html#concat-1>
+async fn write_concat_list(list_path: &Path, file_names: &[String]) -> Result<()> {
+    let mut list = String::from("ffconcat version 1.0\n");
+    for file_name in file_names {
+        list.push_str("file ");
+        list.push_str(&escape_concat_path(file_name));
+        list.push('\n');
+    }
+
+    tokio::fs::write(list_path, list)
+        .await
+        .context("Writing concat list file")
+}
+
+/// Quotes a path for an `ffconcat` list file
+///
+/// Special characters lose their meaning inside of single quotes, and a single quote is written
+/// by closing the quote, escaping it, then reopening the quote
+fn escape_concat_path(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: SplitLimits = SplitLimits {
+        max_duration: Duration::from_hours(12),
+        max_size: 256 * 1000 * 1000 * 1000,
+    };
+
+    #[test]
+    fn short_videos_are_not_split() {
+        assert_eq!(LIMITS.part_count(Duration::ZERO, 0), 1);
+        assert_eq!(LIMITS.part_count(Duration::from_hours(11), 1000), 1);
+    }
+
+    #[test]
+    fn splits_long_videos_with_a_margin() {
+        // Parts are aimed at 11:59:00 as they are cut at a keyframe after the split point
+        assert_eq!(LIMITS.part_count(Duration::from_hours(12), 1000), 2);
+        assert_eq!(
+            LIMITS.part_count(Duration::from_mins(11 * 60 + 59), 1000),
+            1
+        );
+        assert_eq!(LIMITS.part_count(Duration::from_hours(30), 1000), 3);
+    }
+
+    #[test]
+    fn splits_large_videos() {
+        assert_eq!(
+            LIMITS.part_count(Duration::from_hours(1), LIMITS.max_size),
+            1
+        );
+        assert_eq!(
+            LIMITS.part_count(Duration::from_hours(1), LIMITS.max_size + 1),
+            2
+        );
+        let tiny = SplitLimits {
+            max_duration: Duration::from_secs(30),
+            max_size: 0,
+        };
+        // Limits below the margin still split at the margin, and a size of 0 is not divided by
+        assert_eq!(tiny.part_count(Duration::from_mins(3), 10), 10);
+    }
+}
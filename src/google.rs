@@ -1,4 +1,4 @@
-use std::{str::FromStr, time::Duration};
+use std::{str::FromStr, sync::Once, time::Duration};
 
 use anyhow::{Context, Result, ensure};
 use pkce::{code_challenge, code_verifier};
@@ -6,11 +6,14 @@ use reqwest::{Client, Url};
 use serde_json::Value;
 use tokio::{select, sync::watch};
 use tokio_util::sync::CancellationToken;
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 
 const OAUTH_AUTH_URI: &str = "https://accounts.google.com/o/oauth2/v2/auth";
 const OAUTH_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
-const OAUTH_SCOPE: &str = "https://www.googleapis.com/auth/youtube.upload";
+/// Full YouTube access is needed to manage playlists, captions and edit uploaded videos,
+/// `youtube.upload` only allows uploading. Older refresh tokens have narrower scopes, see
+/// [`check_granted_scope`]
+const OAUTH_SCOPE: &str = "https://www.googleapis.com/auth/youtube.force-ssl";
 
 const GOOGLE_CLIENT_ID: &str =
     "915486646698-1etc3ipkfvd77phikc9qrghnr1c1cam8.apps.googleusercontent.com";
@@ -100,6 +103,9 @@ pub async fn generate_access_token(
         .json::<Value>()
         .await
         .context("Parsing access token request")?;
+    if let Some(scope) = json["scope"].as_str() {
+        check_granted_scope(scope);
+    }
 
     Ok(json["access_token"]
         .as_str()
@@ -108,12 +114,29 @@ pub async fn generate_access_token(
         .into_boxed_str())
 }
 
+/// Warns once when the refresh token was authorized before [`OAUTH_SCOPE`] was widened
+///
+/// Such tokens can still upload videos, but anything they are not authorized for is rejected by
+/// YouTube
+fn check_granted_scope(granted: &str) {
+    static WARNING: Once = Once::new();
+
+    if granted.split(' ').any(|s| s == OAUTH_SCOPE) {
+        return;
+    }
+    WARNING.call_once(|| {
+        warn!(
+            "REFRESH_TOKEN is missing permissions, so adding videos to playlists, linking split parts or uploading chat captions might fail. Run the `login` command again and replace REFRESH_TOKEN with the new token to authorize them"
+        );
+    });
+}
+
 /// # Panics
 #[must_use = "You might use an expired access token"]
 #[instrument(skip(ct))]
 pub fn watch_access_token(ct: CancellationToken) -> watch::Receiver<Option<Box<str>>> {
     let client = Client::builder()
-        .timeout(Duration::from_secs(60))
+        .timeout(Duration::from_mins(1))
         .build()
         .unwrap();
 
@@ -132,9 +155,9 @@ pub fn watch_access_token(ct: CancellationToken) -> watch::Receiver<Option<Box<s
             };
             set_access_token.send_replace(Some(access_token));
 
-            info!("Refreshed access token. Sleeping for 3600 sec");
+            info!("Refreshed access token. Sleeping for 59 minutes");
             select! {
-                () = tokio::time::sleep(Duration::from_secs(3540)) => {
+                () = tokio::time::sleep(Duration::from_mins(59)) => {
                     info!("Refreshing access token");
                 }
                 () = ct.cancelled() => {
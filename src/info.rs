@@ -0,0 +1,208 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use m3u8_rs::{MediaSegment, VariantStream};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    ffmpeg,
+    twitch::{
+        cdn::MutedRange,
+        structs::{Chapter, ClipInfo, ClipQuality, VideoInfo},
+    },
+    youtube::UploadedVideo,
+};
+
+/// How a VOD was downloaded, kept for its `.info.json` sidecar file
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadInfo {
+    pub variant: VariantInfo,
+    pub segments: Vec<SegmentInfo>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// The quality / variant of the VOD that was downloaded
+#[derive(Debug, Clone, Serialize)]
+pub struct VariantInfo {
+    /// e.g. `1080p60`
+    pub name: Option<String>,
+    pub uri: String,
+    /// In bits per second
+    pub bandwidth: u64,
+    pub resolution: Option<String>,
+    pub frame_rate: Option<f64>,
+    pub codecs: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentInfo {
+    pub uri: String,
+    /// In seconds
+    pub duration: f32,
+    /// Audio was muted by Twitch
+    pub muted: bool,
+    /// The original audio of a muted segment was recovered, see `--try-unmute`
+    pub unmuted: bool,
+}
+
+impl DownloadInfo {
+    /// Describes a download which started at `started_at` and just finished
+    ///
+    /// `segments` are every media segment along with whether they were muted and unmuted
+    pub fn new<'a>(
+        variant: &VariantStream,
+        segments: impl IntoIterator<Item = (&'a MediaSegment, bool, bool)>,
+        started_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            variant: VariantInfo {
+                name: variant.video.clone(),
+                uri: variant.uri.clone(),
+                bandwidth: variant.bandwidth,
+                resolution: variant.resolution.map(|r| r.to_string()),
+                frame_rate: variant.frame_rate,
+                codecs: variant.codecs.clone(),
+            },
+            segments: segments
+                .into_iter()
+                .map(|(segment, muted, unmuted)| SegmentInfo {
+                    uri: segment.uri.clone(),
+                    duration: segment.duration,
+                    muted,
+                    unmuted,
+                })
+                .collect(),
+            started_at,
+            finished_at: Utc::now(),
+        }
+    }
+
+    /// Describes a clip download of `size` bytes, which is a single MP4 file
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    #[must_use]
+    pub fn from_clip(
+        clip: &ClipInfo,
+        quality: &ClipQuality,
+        size: u64,
+        started_at: DateTime<Utc>,
+    ) -> Self {
+        let bandwidth = size * 8 / clip.duration_seconds.max(1);
+        Self {
+            variant: VariantInfo {
+                name: Some(format!(
+                    "{}p{}",
+                    quality.quality,
+                    quality.frame_rate.round() as u64
+                )),
+                uri: quality.source_url.clone(),
+                bandwidth,
+                resolution: None,
+                frame_rate: Some(quality.frame_rate),
+                codecs: None,
+            },
+            segments: vec![SegmentInfo {
+                uri: quality.source_url.clone(),
+                duration: clip.duration_seconds as f32,
+                muted: false,
+                unmuted: false,
+            }],
+            started_at,
+            finished_at: Utc::now(),
+        }
+    }
+}
+
+/// Everything known about a downloaded / archived VOD
+pub struct Sidecar<'a> {
+    pub video: &'a VideoInfo,
+    pub download: &'a DownloadInfo,
+    pub chapters: &'a [Chapter],
+    pub muted_ranges: &'a [MutedRange],
+    /// Every part the VOD was uploaded as, empty when it was only downloaded
+    pub youtube: &'a [UploadedVideo],
+    pub thumbnail: Option<&'a [u8]>,
+}
+
+/// Contents of the `.info.json` sidecar file
+#[derive(Serialize)]
+struct InfoJson<'a> {
+    /// e.g. `vod-squirrel 0.1.0`
+    generator: String,
+    ffmpeg_version: Option<String>,
+    video: &'a VideoInfo,
+    download: &'a DownloadInfo,
+    chapters: &'a [Chapter],
+    muted_ranges: &'a [MutedRange],
+    youtube: Vec<YouTubeInfo<'a>>,
+    /// File name of the thumbnail, saved next to the sidecar file
+    thumbnail: Option<String>,
+}
+
+#[derive(Serialize)]
+struct YouTubeInfo<'a> {
+    id: &'a str,
+    url: String,
+}
+
+/// Saves `sidecar` as `<base>.info.json`, along with its thumbnail as `<base>.jpg`
+///
+/// `base` is usually the path of the video, whose extension is replaced
+///
+/// # Errors
+/// Errors when the files cannot be written
+pub async fn write_sidecar(base: &Path, sidecar: Sidecar<'_>) -> Result<()> {
+    let json_path = base.with_extension("info.json");
+    let thumbnail_path = base.with_extension("jpg");
+
+    let thumbnail = match sidecar.thumbnail {
+        Some(image) => {
+            tokio::fs::write(&thumbnail_path, image)
+                .await
+                .context("Writing thumbnail")?;
+            thumbnail_path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+        }
+        None => None,
+    };
+
+    let ffmpeg_version = match ffmpeg::version().await {
+        Ok(version) => Some(version),
+        Err(e) => {
+            warn!("Unable to read ffmpeg's version: {e:#}");
+            None
+        }
+    };
+
+    let info = InfoJson {
+        generator: format!("vod-squirrel {}", env!("CARGO_PKG_VERSION")),
+        ffmpeg_version,
+        video: sidecar.video,
+        download: sidecar.download,
+        chapters: sidecar.chapters,
+        muted_ranges: sidecar.muted_ranges,
+        youtube: sidecar
+            .youtube
+            .iter()
+            .map(|v| YouTubeInfo {
+                id: &v.id,
+                url: v.url(),
+            })
+            .collect(),
+        thumbnail,
+    };
+    let json = serde_json::to_vec_pretty(&info).context("Serializing video info")?;
+    tokio::fs::write(&json_path, json)
+        .await
+        .context("Writing video info")?;
+    info!("Saved video info into {json_path:?}");
+
+    Ok(())
+}
@@ -0,0 +1,282 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use tracing::{info, warn};
+
+use crate::{twitch::structs::VideoInfo, util::truncate_string};
+
+/// Stream titles are shortened so that file names stay under the 255 bytes most file systems
+/// allow
+const MAX_TITLE_BYTES: usize = 150;
+
+const SHOW_NFO_FILE_NAME: &str = "tvshow.nfo";
+const POSTER_FILE_NAME: &str = "poster.jpg";
+const FANART_FILE_NAME: &str = "fanart.jpg";
+
+/// Where a VOD is saved inside of a Jellyfin / Plex / Kodi library, e.g.
+/// `<library>/Channel/Season 2025/Channel - 2025-01-31 - Title [123456789].mp4`
+///
+/// Every channel is a show, with a season for every year
+#[must_use]
+pub fn episode_path(library: &Path, video_info: &VideoInfo) -> PathBuf {
+    let channel = show_name(video_info);
+    let title = truncate_string(&sanitize_file_name(&video_info.title), MAX_TITLE_BYTES);
+
+    library
+        .join(&channel)
+        .join(format!("Season {}", video_info.created_at.format("%Y")))
+        .join(format!(
+            "{channel} - {} - {title} [{}].mp4",
+            video_info.created_at.format("%Y-%m-%d"),
+            video_info.id
+        ))
+}
+
+/// Writes the `.nfo` file and thumbnail of an episode saved at `episode`, along with the
+/// `tvshow.nfo`, `poster.jpg` and `fanart.jpg` of its channel when they are missing
+///
+/// Channel files are never overwritten so they can be edited by hand. Missing channel images
+/// are only logged.
+///
+/// # Errors
+/// Errors when the episode files cannot be written
+pub async fn write_library_metadata(
+    client: &reqwest::Client,
+    episode: &Path,
+    video_info: &VideoInfo,
+    plot: &str,
+    thumbnail: Option<&[u8]>,
+) -> Result<()> {
+    tokio::fs::write(episode.with_extension("nfo"), episode_nfo(video_info, plot))
+        .await
+        .context("Writing episode metadata")?;
+
+    if let Some(thumbnail) = thumbnail {
+        let stem = episode.file_stem().unwrap_or_default().to_string_lossy();
+        tokio::fs::write(
+            episode.with_file_name(format!("{stem}-thumb.jpg")),
+            thumbnail,
+        )
+        .await
+        .context("Writing episode thumbnail")?;
+    }
+
+    // <show>/Season <year>/<episode>
+    let Some(show_dir) = episode.parent().and_then(Path::parent) else {
+        return Ok(());
+    };
+
+    let show_nfo = show_dir.join(SHOW_NFO_FILE_NAME);
+    if !tokio::fs::try_exists(&show_nfo).await.unwrap_or(false) {
+        tokio::fs::write(&show_nfo, show_nfo_contents(video_info))
+            .await
+            .context("Writing channel metadata")?;
+        info!("Saved channel metadata into {show_nfo:?}");
+    }
+
+    let owner = &video_info.owner;
+    for (file_name, url) in [
+        (POSTER_FILE_NAME, &owner.profile_image_url),
+        (FANART_FILE_NAME, &owner.banner_image_url),
+    ] {
+        let path = show_dir.join(file_name);
+        let Some(url) = url else {
+            continue;
+        };
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            continue;
+        }
+
+        if let Err(e) = download_image(client, url, &path).await {
+            warn!("Unable to save channel image {file_name}: {e:#}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn download_image(client: &reqwest::Client, url: &str, path: &Path) -> Result<()> {
+    let res = client.get(url).send().await.context("Downloading image")?;
+
+    let status = res.status();
+    if !status.is_success() {
+        bail!("Twitch responded with {status}");
+    }
+
+    let image = res.bytes().await.context("Downloading image")?;
+    tokio::fs::write(path, image).await.context("Writing image")
+}
+
+/// See: <https://jellyfin.org/docs/general/server/metadata/nfo>
+fn episode_nfo(video_info: &VideoInfo, plot: &str) -> String {
+    let genre = video_info.game.as_ref().map_or_else(String::new, |g| {
+        format!("  <genre>{}</genre>\n", escape_xml(&g.display_name))
+    });
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<episodedetails>
+  <title>{title}</title>
+  <showtitle>{show}</showtitle>
+  <plot>{plot}</plot>
+  <aired>{date}</aired>
+  <premiered>{date}</premiered>
+  <season>{year}</season>
+  <runtime>{runtime}</runtime>
+{genre}  <studio>Twitch</studio>
+  <uniqueid type="twitch" default="true">{id}</uniqueid>
+</episodedetails>
+"#,
+        title = escape_xml(&video_info.title),
+        show = escape_xml(&video_info.owner.display_name),
+        plot = escape_xml(plot),
+        date = video_info.created_at.format("%Y-%m-%d"),
+        year = video_info.created_at.format("%Y"),
+        runtime = video_info.length_seconds / 60,
+        id = escape_xml(&video_info.id),
+    )
+}
+
+fn show_nfo_contents(video_info: &VideoInfo) -> String {
+    let owner = &video_info.owner;
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<tvshow>
+  <title>{name}</title>
+  <plot>Past broadcasts of {name}, archived from https://www.twitch.tv/{login}</plot>
+  <studio>Twitch</studio>
+  <uniqueid type="twitch" default="true">{login}</uniqueid>
+</tvshow>
+"#,
+        name = escape_xml(&owner.display_name),
+        login = escape_xml(&owner.login),
+    )
+}
+
+/// Display names can be localized, falling back to the login when nothing usable is left
+fn show_name(video_info: &VideoInfo) -> String {
+    let name = sanitize_file_name(&video_info.owner.display_name);
+    if name.is_empty() {
+        sanitize_file_name(&video_info.owner.login)
+    } else {
+        name
+    }
+}
+
+/// Replaces characters which are not allowed in file names on Windows, where trailing dots and
+/// spaces are not allowed either
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect::<String>()
+        .trim_start()
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_string()
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+    use crate::twitch::structs::{BroadcastType, Channel, Game, Status};
+
+    fn video_info() -> VideoInfo {
+        VideoInfo {
+            id: "123".to_string(),
+            title: "Speedrun: any% <PB>".to_string(),
+            description: None,
+            created_at: "2025-01-31T12:00:00Z".parse::<DateTime<Utc>>().unwrap(),
+            length_seconds: 3600,
+            view_count: 0,
+            status: Status::RECORDED,
+            broadcast_type: BroadcastType::Archive,
+            preview_thumbnail_url: None,
+            game: Some(Game {
+                display_name: "Tom & Jerry".to_string(),
+            }),
+            owner: Channel {
+                login: "streamer".to_string(),
+                display_name: "Streamer".to_string(),
+                profile_image_url: None,
+                banner_image_url: None,
+            },
+        }
+    }
+
+    #[test]
+    fn sanitizes_reserved_characters() {
+        assert_eq!(
+            sanitize_file_name(r#"a<b>c:d"e/f\g|h?i*j"#),
+            "a_b_c_d_e_f_g_h_i_j"
+        );
+        assert_eq!(sanitize_file_name("tab\there"), "tab_here");
+        assert_eq!(sanitize_file_name("  Title... "), "Title");
+        assert_eq!(sanitize_file_name("Title. ."), "Title");
+        assert_eq!(sanitize_file_name("..."), "");
+    }
+
+    #[test]
+    fn saves_episodes_by_channel_and_year() {
+        let path = episode_path(Path::new("/library"), &video_info());
+        assert_eq!(
+            path,
+            Path::new(
+                "/library/Streamer/Season 2025/Streamer - 2025-01-31 - Speedrun_ any% _PB_ [123].mp4"
+            )
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_login_without_display_name() {
+        let mut video_info = video_info();
+        video_info.owner.display_name = "???".to_string();
+        assert_eq!(show_name(&video_info), "___");
+
+        video_info.owner.display_name = " . ".to_string();
+        assert_eq!(show_name(&video_info), "streamer");
+        let path = episode_path(Path::new("/library"), &video_info);
+        assert!(path.starts_with("/library/streamer/Season 2025"));
+    }
+
+    #[test]
+    fn escapes_xml() {
+        assert_eq!(
+            escape_xml(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn writes_escaped_episode_nfo() {
+        let nfo = episode_nfo(&video_info(), "Plot & <stuff>");
+        assert!(nfo.contains("<title>Speedrun: any% &lt;PB&gt;</title>"));
+        assert!(nfo.contains("<plot>Plot &amp; &lt;stuff&gt;</plot>"));
+        assert!(nfo.contains("<genre>Tom &amp; Jerry</genre>"));
+        assert!(nfo.contains("<aired>2025-01-31</aired>"));
+        assert!(nfo.contains("<season>2025</season>"));
+        assert!(nfo.contains("<runtime>60</runtime>"));
+        assert!(nfo.contains(r#"<uniqueid type="twitch" default="true">123</uniqueid>"#));
+    }
+
+    #[test]
+    fn leaves_out_missing_genre() {
+        let mut video_info = video_info();
+        video_info.game = None;
+        assert!(!episode_nfo(&video_info, "").contains("<genre>"));
+    }
+}
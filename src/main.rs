@@ -9,32 +9,56 @@
 #![allow(clippy::multiple_crate_versions, clippy::missing_panics_doc)]
 
 use std::{
-    io::ErrorKind,
+    collections::HashMap,
     path::{Path, PathBuf},
-    str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{Context, Result, bail};
+use archive::{ArchiveOptions, Playlists, create_thumbnail, download_and_archive};
+use backfill::BackfillFilter;
 use clap::{Parser, Subcommand};
-use ffmpeg::concat_video;
-use reqwest::Url;
-use tokio::{fs::File, io::AsyncWriteExt, select, sync::Semaphore};
-use tokio_stream::StreamExt;
+use config::Config;
+use download::{DownloadOptions, RetryPolicy, download};
+use eventsub::StreamEvent;
+use ffmpeg::{ConcatMethod, SplitLimits};
+use monitor::StreamVod;
+use ratelimit::{ByteRate, RateLimiter};
+use state::{JobState, StateStore};
+use subtitle::{ChatSubtitleOptions, SubtitleFormat};
+use template::{Template, TemplateValues, Templates};
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info, warn};
-use util::{truncate_string, warn_ulimit};
-use youtube::{VideoDetail, upload_video};
+use tracing::{debug, error, info, warn};
+use twitch::{
+    cdn::QualityPreference,
+    structs::{ClipInfo, Status, VideoInfo},
+};
+use util::warn_ulimit;
+use youtube::{UploadOptions, UploadedVideo, VideoMetadata};
 
+pub mod archive;
+pub mod backfill;
+pub mod config;
+pub mod download;
 pub mod eventsub;
 pub mod ffmpeg;
 pub mod google;
+pub mod info;
+pub mod library;
+pub mod monitor;
 pub mod oauth_server;
+pub mod ratelimit;
+pub mod state;
+pub mod subtitle;
+pub mod template;
 pub mod twitch;
 pub mod util;
 pub mod youtube;
 
 /// Archives a Twitch.tv Video (VOD) by uploading it to YouTube
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -42,6 +66,76 @@ struct Args {
     #[arg(short, long, default_value_t = 20, value_name = "COUNT")]
     parallelism: usize,
 
+    /// Video quality to download, optionally followed by preferred codecs
+    ///
+    /// Quality is one of `best`, `worst`, `audio_only` or a resolution such as `1080p60` / `720p`.
+    /// Codecs are `av1`, `h265` and `h264`, e.g. `--quality 1080p60,av1,h264`.
+    /// Only H.264 is downloaded by default, unless the VOD is not available in H.264
+    #[arg(short, long, default_value = "best", value_name = "QUALITY")]
+    quality: QualityPreference,
+
+    /// The amount of times a failing video chunk download is retried
+    #[arg(long, default_value_t = 5, value_name = "COUNT")]
+    retries: u32,
+
+    /// Initial delay between video chunk download retries, doubled after every attempt
+    #[arg(long, default_value_t = 1, value_name = "SECONDS")]
+    retry_delay: u64,
+
+    /// Try downloading the original audio of sections muted by Twitch
+    #[arg(long)]
+    try_unmute: bool,
+
+    /// Archive VODs with video chunks missing from Twitch's CDN, skipping over them
+    ///
+    /// Without it, downloads fail with the list of missing chunks instead
+    #[arg(long)]
+    skip_missing_segments: bool,
+
+    /// How video chunks are joined together by ffmpeg
+    #[arg(long, value_enum, default_value_t, value_name = "METHOD")]
+    concat_method: ConcatMethod,
+
+    /// Pipe video chunks straight into ffmpeg instead of saving them to disk first
+    ///
+    /// Only needs disk space for the final video, but interrupted downloads cannot be resumed
+    #[arg(long)]
+    stream: bool,
+
+    /// Upload video chunks to YouTube while they are being downloaded, without saving anything
+    /// to disk
+    ///
+    /// Interrupted archives cannot be resumed and have to be started over
+    #[arg(long)]
+    stream_upload: bool,
+
+    /// Limit the total download speed, in bytes per second (e.g. `500K`, `10M`)
+    #[arg(long, value_name = "RATE")]
+    download_rate: Option<ByteRate>,
+
+    /// Size of each chunk uploaded to YouTube. Failed chunks are retried from the last
+    /// byte YouTube has received
+    #[arg(long, default_value_t = 8, value_name = "MIB", value_parser = clap::value_parser!(u32).range(1..))]
+    upload_chunk_size: u32,
+
+    /// Limit the YouTube upload speed, in bytes per second (e.g. `500K`, `10M`)
+    #[arg(long, value_name = "RATE")]
+    upload_rate: Option<ByteRate>,
+
+    /// Videos longer than this are split into multiple YouTube uploads
+    #[arg(long, default_value_t = 12, value_name = "HOURS", value_parser = clap::value_parser!(u64).range(1..))]
+    max_part_duration: u64,
+
+    /// Videos larger than this are split into multiple YouTube uploads, in gigabytes
+    #[arg(long, default_value_t = 256, value_name = "GB", value_parser = clap::value_parser!(u64).range(1..))]
+    max_part_size: u64,
+
+    /// TOML config file, reloaded on `SIGHUP`
+    ///
+    /// Flags given on the command line take priority over their setting in the config file
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
     /// Cleanup the unprocessed video chunks afterward [default: true]
     #[arg(short, long, default_value_t = true)]
     cleanup: bool,
@@ -50,6 +144,41 @@ struct Args {
     #[arg(long, value_name = "DIR")]
     temp_dir: Option<PathBuf>,
 
+    /// Archive history of `archive` / `backfill` / `monitor`, used to never archive a VOD twice
+    /// and to resume interrupted archives
+    ///
+    /// Only one running vod-squirrel can use a history file at once
+    #[arg(long, default_value = "vod-squirrel-state.jsonl", value_name = "FILE")]
+    state_file: PathBuf,
+
+    /// Directory where `<VOD_ID>.info.json` files of archived videos are saved
+    ///
+    /// Downloaded videos have theirs saved next to them instead
+    #[arg(long, default_value = ".", value_name = "DIR")]
+    info_dir: PathBuf,
+
+    /// Title template of archived videos, e.g. `[{date:%Y-%m-%d}] {title}`
+    ///
+    /// Placeholders: `{title}`, `{channel}`, `{channel_login}`, `{game}`, `{date}`, `{duration}`,
+    /// `{vod_id}`, `{vod_url}`, `{part}`, `{parts}`, `{chapters}` and `{muted}`.
+    /// `{?game}...{/game}` is only kept when there is a game, `{!game}...{/game}` only when there
+    /// isn't
+    #[arg(long, value_name = "TEMPLATE")]
+    title_template: Option<Template>,
+
+    /// Description template of archived videos, see `--title-template`
+    #[arg(long, value_name = "TEMPLATE")]
+    description_template: Option<Template>,
+
+    #[command(flatten, next_help_heading = "YouTube metadata")]
+    metadata: VideoMetadata,
+
+    #[command(flatten, next_help_heading = "YouTube playlists")]
+    playlists: Playlists,
+
+    #[command(flatten, next_help_heading = "Chat subtitles")]
+    chat_subtitles: ChatSubtitleOptions,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -61,26 +190,98 @@ enum Commands {
 
     /// Download and save a Twitch video
     Download {
-        /// Twitch video URL / ID to Download
+        /// Twitch video / clip URL or video ID to Download
         #[arg(value_name = "VOD_URL")]
         vod: String,
 
         /// The file name / path of the video output
-        #[arg(value_name = "PATH")]
-        path: PathBuf,
+        #[arg(value_name = "PATH", required_unless_present = "library")]
+        path: Option<PathBuf>,
+
+        /// Save the video into a Jellyfin / Plex / Kodi library instead of PATH
+        ///
+        /// Videos are saved as `<DIR>/<Channel>/Season <Year>/<Channel> - <Date> - <Title>.mp4`
+        /// along with `.nfo` metadata and thumbnails, and channel images when missing
+        #[arg(long, value_name = "DIR", conflicts_with = "path")]
+        library: Option<PathBuf>,
+
+        /// Also save the chat replay next to the video, as `<PATH>.chat.jsonl`
+        #[arg(long)]
+        with_chat: bool,
+
+        /// Add the chat replay to the video as a subtitle stream
+        #[arg(long, requires = "with_chat")]
+        mux_chat: bool,
     },
 
     /// Download and archive a Twitch video to YouTube
     Archive {
-        /// Twitch video URL / ID to Download
+        /// Twitch video / clip URL or video ID to Download
+        #[arg(value_name = "VOD_URL")]
+        vod: String,
+
+        /// Also save the chat replay into the current directory, as `<VOD_ID>.chat.jsonl`
+        #[arg(long)]
+        with_chat: bool,
+
+        /// Upload the chat replay as a caption track of the YouTube video
+        #[arg(long, requires = "with_chat")]
+        chat_captions: bool,
+    },
+
+    /// Download the chat replay of a Twitch video as JSON lines
+    ///
+    /// Interrupted downloads are resumed when running the same command again
+    Chat {
+        /// Twitch video URL / ID to download the chat of
         #[arg(value_name = "VOD_URL")]
         vod: String,
+
+        /// The file name / path of the chat output
+        #[arg(value_name = "PATH")]
+        path: PathBuf,
+    },
+
+    /// Render a chat replay saved by `chat` into subtitles
+    Subtitles {
+        /// Chat replay saved by the `chat` command / `--with-chat`
+        #[arg(value_name = "CHAT")]
+        chat: PathBuf,
+
+        /// The file name / path of the subtitle output
+        #[arg(value_name = "PATH")]
+        path: PathBuf,
+
+        /// Subtitle format [default: guessed from the extension of PATH]
+        #[arg(long, value_enum)]
+        format: Option<SubtitleFormat>,
+    },
+
+    /// Archive the past videos of a channel, oldest first
+    ///
+    /// Videos are queued into the archive history, so an interrupted backfill is resumed by
+    /// running it again or by `monitor`
+    Backfill {
+        /// Twitch channel URL / login / ID
+        #[arg(value_name = "CHANNEL")]
+        channel: String,
+
+        #[command(flatten)]
+        filter: BackfillFilter,
+
+        /// Only queue the videos, without archiving them
+        #[arg(long)]
+        queue_only: bool,
     },
 
     /// Automatically monitors a channel for VODs and archives new ones
     Monitor {
         /// Twitch Channel(s) ID to monitor
         channel_id: Vec<u64>,
+
+        /// How long to wait for the VOD of a stream to be done recording after the stream ends
+        #[arg(long, default_value_t = 30, value_name = "MINUTES")]
+        vod_timeout: u64,
     },
 }
 
@@ -90,10 +291,13 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     dotenvy::dotenv().ok();
 
-    warn_ulimit();
-
     let args = Args::parse();
 
+    // Only the concat protocol opens every video chunk at once
+    if args.concat_method == ConcatMethod::Protocol {
+        warn_ulimit();
+    }
+
     let client = util::init_http_client();
     let ct = CancellationToken::new();
     util::spawn_ct_watcher(ct.clone());
@@ -105,6 +309,83 @@ async fn main() -> Result<()> {
         panic!("Provided temporary directory is not a valid directory!");
     });
 
+    let config = match &args.config {
+        Some(path) => Config::load(path)
+            .await
+            .with_context(|| format!("Loading config file {}", path.display()))?,
+        None => Config::default(),
+    };
+
+    // Flags given on the command line win over the config file
+    let download_limiter = Arc::new(RateLimiter::new(
+        args.download_rate.or(config.download_rate),
+    ));
+    let upload_limiter = Arc::new(RateLimiter::new(args.upload_rate.or(config.upload_rate)));
+    log_rate_limits(&download_limiter, &upload_limiter);
+
+    if let Some(path) = args.config.clone() {
+        let download_limiter = download_limiter.clone();
+        let upload_limiter = upload_limiter.clone();
+        config::spawn_reloader(ct.clone(), path, move |config| {
+            download_limiter.set_rate(args.download_rate.or(config.download_rate));
+            upload_limiter.set_rate(args.upload_rate.or(config.upload_rate));
+            log_rate_limits(&download_limiter, &upload_limiter);
+        });
+    }
+
+    let download_options = DownloadOptions {
+        parallelism: args.parallelism,
+        retry_policy: RetryPolicy {
+            max_retries: args.retries,
+            initial_backoff: Duration::from_secs(args.retry_delay),
+            ..Default::default()
+        },
+        quality: args.quality,
+        try_unmute: args.try_unmute,
+        skip_missing_segments: args.skip_missing_segments,
+        concat_method: args.concat_method,
+        streaming: args.stream,
+        rate_limiter: download_limiter,
+        state: None,
+    };
+    // Only archives are tracked, downloads are resumed from their download manifest
+    let state = match args.command {
+        Commands::Archive { .. } | Commands::Backfill { .. } | Commands::Monitor { .. } => {
+            Some(Arc::new(
+                StateStore::open(&args.state_file)
+                    .await
+                    .context("Opening archive history")?,
+            ))
+        }
+        _ => None,
+    };
+    let archive_options = Arc::new(ArchiveOptions {
+        download: DownloadOptions {
+            state: state.clone(),
+            ..download_options.clone()
+        },
+        stream_upload: args.stream_upload,
+        cleanup: args.cleanup,
+        metadata: args.metadata.or(config.youtube),
+        templates: Templates {
+            title: args.title_template,
+            description: args.description_template,
+            ..Default::default()
+        }
+        .or(config.templates),
+        upload: UploadOptions {
+            chunk_size: args.upload_chunk_size as usize * 1024 * 1024,
+            retry_policy: download_options.retry_policy,
+            rate_limiter: upload_limiter,
+        },
+        split: SplitLimits {
+            max_duration: Duration::from_secs(args.max_part_duration * 60 * 60),
+            max_size: args.max_part_size * 1000 * 1000 * 1000,
+        },
+        playlists: args.playlists.or(config.playlists),
+        info_dir: args.info_dir,
+    });
+
     match args.command {
         Commands::Login => {
             let (verifier, url) = google::generate_login_url();
@@ -128,15 +409,43 @@ async fn main() -> Result<()> {
             }
         }
 
-        Commands::Download { vod, path } => {
+        Commands::Download {
+            vod,
+            path,
+            library,
+            with_chat,
+            mux_chat,
+        } => {
             assert!(
                 (ffmpeg::is_installed().await),
                 "Unable to continue because `ffmpeg` is not installed!"
             );
 
+            let video_ref = twitch::extract_video(&vod)
+                .expect("Unable to extract for video ID. Did you paste in the correct URL / ID?");
+
+            let (video_info, clip) = get_and_print_video_or_clip_info(&video_ref)
+                .await
+                .expect("Unable to fetch for Twitch Video. Is the video public?");
+            let with_chat = with_chat && chat_supported(clip.as_ref());
+
+            // SAFETY: Either is required by clap
+            let path = path
+                .unwrap_or_else(|| library::episode_path(library.as_deref().unwrap(), &video_info));
+            if library.is_some() {
+                tokio::fs::create_dir_all(path.parent().unwrap())
+                    .await
+                    .expect("Unable to create library directory!");
+            }
+
             // Try creating a file on the path
             // Wont prevent TOCTOU but it'll ease the checking of valid filename
             if path.exists() {
+                // Downloading into a library again only adds the new episodes
+                if library.is_some() {
+                    info!("{path:?} is already in the library, skipping it");
+                    return Ok(());
+                }
                 panic!(
                     "File with the same name / path exists! Please delete or rename said file before continuing"
                 )
@@ -146,46 +455,198 @@ async fn main() -> Result<()> {
                 .expect("Unable to create file on the given path!");
             tokio::fs::remove_file(path.clone()).await.unwrap();
 
-            let vod_id = twitch::extract_video_id(&vod)
-                .expect("Unable to extract for video ID. Did you paste in the correct URL / ID?");
-
-            let _video_info = get_and_print_video_info(vod_id)
-                .await
-                .expect("Unable to fetch for Twitch Video. Is the video public?");
-
-            let file = download(
-                ct.clone(),
-                client,
-                &temp_download_dir,
-                args.parallelism,
-                vod_id,
-            )
-            .await
-            .unwrap();
+            let chat_path = path.with_extension("chat.jsonl");
+            let (video, ()) = tokio::join!(
+                download_video_or_clip(
+                    &ct,
+                    &client,
+                    &temp_download_dir,
+                    &download_options,
+                    &video_ref,
+                    clip.as_ref(),
+                ),
+                save_chat(
+                    with_chat,
+                    &ct,
+                    &video_info,
+                    &chat_path,
+                    &download_options.retry_policy
+                ),
+            );
+            let video = video.unwrap();
 
             if ct.is_cancelled() {
                 info!("CTRL + C caught! Quitting early...");
                 return Ok(());
             }
 
-            tokio::fs::rename(&file, path)
+            let muxed = mux_chat
+                && tokio::fs::try_exists(&chat_path).await.unwrap_or(false)
+                && match subtitle::mux_chat(&video.path, &chat_path, &path, args.chat_subtitles)
+                    .await
+                {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("Unable to add chat to the video, saving it without chat: {e:#}");
+                        false
+                    }
+                };
+            if !muxed {
+                tokio::fs::rename(&video.path, &path)
+                    .await
+                    .expect("Unable to move file!");
+            }
+
+            let thumbnail = create_thumbnail(
+                &video_info,
+                path.as_os_str(),
+                Duration::from_secs(video_info.length_seconds),
+                &archive_options.metadata,
+            )
+            .await;
+            let sidecar = info::Sidecar {
+                video: &video_info,
+                download: &video.info,
+                chapters: &video.chapters,
+                muted_ranges: &video.muted_ranges,
+                youtube: &[],
+                // Libraries keep it as `<episode>-thumb.jpg` instead
+                thumbnail: thumbnail.as_deref().filter(|_| library.is_none()),
+            };
+            if let Err(e) = info::write_sidecar(&path, sidecar).await {
+                warn!("Unable to save video info: {e:#}");
+            }
+
+            if library.is_some() {
+                let values = TemplateValues::new(&video_info, &video.chapters, &video.muted_ranges);
+                let (_, description_template) = archive_options
+                    .templates
+                    .for_channel(&video_info.owner.login);
+                let plot = description_template.render(&values);
+
+                if let Err(e) = library::write_library_metadata(
+                    &client,
+                    &path,
+                    &video_info,
+                    &plot,
+                    thumbnail.as_deref(),
+                )
                 .await
-                .expect("Unable to move file!");
+                {
+                    warn!("Unable to save library metadata: {e:#}");
+                }
+            }
 
             if args.cleanup {
                 info!("Cleaning up processing remnants");
-                tokio::fs::remove_dir_all(file.parent().unwrap())
+                tokio::fs::remove_dir_all(video.path.parent().unwrap())
                     .await
                     .unwrap();
             }
         }
 
-        Commands::Archive { vod } => {
+        Commands::Archive {
+            vod,
+            with_chat,
+            chat_captions,
+        } => {
             assert!(
                 (ffmpeg::is_installed().await),
                 "Unable to continue because `ffmpeg` is not installed!"
             );
             let access_token = google::watch_access_token(ct.clone());
+            let video_ref = twitch::extract_video(&vod)
+                .expect("Unable to extract for video ID. Did you paste in the correct URL / ID?");
+
+            let vod_id = match video_ref {
+                twitch::VideoRef::Video(vod_id) => vod_id,
+                twitch::VideoRef::Clip(slug) => {
+                    let clip = get_and_print_clip_info(&slug)
+                        .await
+                        .expect("Unable to fetch for Twitch Clip. Does the clip still exist?");
+                    if with_chat {
+                        chat_supported(Some(&clip));
+                    }
+
+                    archive::archive_clip(
+                        &ct,
+                        &client,
+                        &temp_download_dir,
+                        &archive_options,
+                        &clip,
+                        &access_token,
+                    )
+                    .await
+                    .unwrap();
+                    if ct.is_cancelled() {
+                        info!("CTRL + C caught! Quitting early...");
+                        return Ok(());
+                    }
+                    info!("All done!");
+                    return Ok(());
+                }
+            };
+            let video_info = get_and_print_video_info(vod_id)
+                .await
+                .expect("Unable to fetch for Twitch Video. Is the video public?");
+
+            // SAFETY: Opened for every archive
+            let state = state.unwrap();
+            if let Some(JobState::Uploaded { youtube_ids }) =
+                state.get(vod_id).await.context("Reading archive history")?
+            {
+                info!(
+                    "VOD is already archived as {}, skipping it",
+                    youtube_ids.join(", ")
+                );
+                return Ok(());
+            }
+            // Recorded before starting, so `monitor` / `backfill` resume it when interrupted
+            state
+                .enqueue(vod_id)
+                .await
+                .context("Queueing VOD into archive history")?;
+
+            let chat_path = PathBuf::from(format!("{vod_id}.chat.jsonl"));
+            let (archived, ()) = tokio::join!(
+                download_and_archive(
+                    &ct,
+                    &client,
+                    &temp_download_dir,
+                    &archive_options,
+                    &video_info,
+                    vod_id,
+                    &access_token,
+                ),
+                save_chat(
+                    with_chat,
+                    &ct,
+                    &video_info,
+                    &chat_path,
+                    &download_options.retry_policy
+                ),
+            );
+            let archived = archived.unwrap();
+
+            if ct.is_cancelled() {
+                info!("CTRL + C caught! Quitting early...");
+                return Ok(());
+            }
+
+            if chat_captions {
+                upload_chat_captions(
+                    &client,
+                    &access_token,
+                    &archived,
+                    &chat_path,
+                    &archive_options.metadata,
+                    args.chat_subtitles,
+                )
+                .await;
+            }
+        }
+
+        Commands::Chat { vod, path } => {
             let vod_id = twitch::extract_video_id(&vod)
                 .expect("Unable to extract for video ID. Did you paste in the correct URL / ID?");
 
@@ -193,97 +654,269 @@ async fn main() -> Result<()> {
                 .await
                 .expect("Unable to fetch for Twitch Video. Is the video public?");
 
-            let file_path = download(
-                ct.clone(),
-                client.clone(),
-                &temp_download_dir,
-                args.parallelism,
+            twitch::chat::download_chat(
+                &ct,
                 vod_id,
+                Duration::from_secs(video_info.length_seconds),
+                &path,
+                &download_options.retry_policy,
             )
-            .await
-            .unwrap();
+            .await?;
 
             if ct.is_cancelled() {
                 info!("CTRL + C caught! Quitting early...");
                 return Ok(());
             }
+        }
 
-            info!("Final file path: {file_path:?}");
+        Commands::Subtitles { chat, path, format } => {
+            let format = format
+                .or_else(|| SubtitleFormat::from_path(&path))
+                .context("Unable to guess the subtitle format from PATH, please set `--format`")?;
 
-            let latest_access_token = &access_token.borrow().clone().unwrap();
-            archive(
-                ct.clone(),
-                client.clone(),
-                &file_path,
-                video_info,
-                latest_access_token,
-            )
-            .await
-            .unwrap();
+            subtitle::write_chat_subtitles(&chat, &path, format, args.chat_subtitles).await?;
+            info!("Saved chat subtitles into {path:?}");
+        }
 
-            if args.cleanup {
-                info!("Cleaning up processing remnants");
-                tokio::fs::remove_dir_all(file_path.parent().unwrap())
-                    .await
-                    .unwrap();
+        Commands::Backfill {
+            channel,
+            filter,
+            queue_only,
+        } => {
+            let channel = twitch::extract_channel(&channel)?;
+            // SAFETY: Opened for every archive
+            let state = state.unwrap();
+
+            let queued = backfill::queue_channel_videos(&state, &channel, &filter).await?;
+            info!("Queued {queued} new videos");
+            if queue_only {
+                return Ok(());
+            }
+
+            assert!(
+                (ffmpeg::is_installed().await),
+                "Unable to continue because `ffmpeg` is not installed!"
+            );
+            let access_token = google::watch_access_token(ct.clone());
+            archive_unfinished(
+                &ct,
+                &client,
+                &temp_download_dir,
+                &archive_options,
+                &state,
+                &access_token,
+            )
+            .await?;
+            if ct.is_cancelled() {
+                info!("CTRL + C caught! Quitting early...");
+                return Ok(());
             }
         }
 
-        Commands::Monitor { channel_id } => {
+        Commands::Monitor {
+            channel_id,
+            vod_timeout,
+        } => {
             assert!(
                 (ffmpeg::is_installed().await),
                 "Unable to continue because `ffmpeg` is not installed!"
             );
             let access_token = google::watch_access_token(ct.clone());
+            // SAFETY: Opened for every archive
+            let state = state.unwrap();
 
-            let mut receiver = eventsub::listen_for_offline(ct.clone(), channel_id)
+            // Interrupted archives are finished before waiting for new ones
+            archive_unfinished(
+                &ct,
+                &client,
+                &temp_download_dir,
+                &archive_options,
+                &state,
+                &access_token,
+            )
+            .await?;
+            if ct.is_cancelled() {
+                info!("CTRL + C caught! Quitting early...");
+                return Ok(());
+            }
+
+            // Streams which started before monitoring are matched the same way
+            let mut live_streams = HashMap::new();
+            for &id in &channel_id {
+                match twitch::api::get_live_stream(id).await {
+                    Ok(Some(stream)) => {
+                        info!("User {id} is currently live (stream {})", stream.id);
+                        live_streams.insert(id, stream);
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("Unable to check whether user {id} is live: {e:#}"),
+                }
+            }
+
+            let mut receiver = eventsub::listen_for_streams(ct.clone(), channel_id)
                 .await
                 .context("Initiating EventSub connection")
                 .unwrap();
-
-            while let Some(uid) = receiver.recv().await {
+            let vod_timeout = Duration::from_mins(vod_timeout);
+            let mut queue_interval = tokio::time::interval_at(
+                tokio::time::Instant::now() + monitor::QUEUE_INTERVAL,
+                monitor::QUEUE_INTERVAL,
+            );
+            queue_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut queued_archives: Option<JoinHandle<()>> = None;
+
+            loop {
+                let event = tokio::select! {
+                    event = receiver.recv() => event,
+                    () = ct.cancelled() => None,
+                    _ = queue_interval.tick() => {
+                        // Archived in the background, so that streams are still followed meanwhile
+                        if queued_archives.as_ref().is_none_or(JoinHandle::is_finished) {
+                            let ct = ct.clone();
+                            let client = client.clone();
+                            let temp_download_dir = temp_download_dir.clone();
+                            let archive_options = archive_options.clone();
+                            let state = state.clone();
+                            let access_token = access_token.clone();
+                            queued_archives = Some(tokio::spawn(async move {
+                                if let Err(e) = archive_unfinished(
+                                    &ct,
+                                    &client,
+                                    &temp_download_dir,
+                                    &archive_options,
+                                    &state,
+                                    &access_token,
+                                )
+                                .await
+                                {
+                                    error!("Unable to archive the queued VODs: {e:#}");
+                                }
+                            }));
+                        }
+                        // VODs are created a little after their stream started
+                        for (&id, stream) in &mut live_streams {
+                            monitor::lookup_archive_video(id, stream).await;
+                        }
+                        continue;
+                    }
+                };
+                let Some(event) = event else {
+                    break;
+                };
+
+                let uid = match event {
+                    StreamEvent::Online {
+                        broadcaster_id,
+                        mut stream,
+                    } => {
+                        info!(
+                            "User {broadcaster_id} started streaming (stream {})",
+                            stream.id
+                        );
+                        monitor::lookup_archive_video(broadcaster_id, &mut stream).await;
+                        live_streams.insert(broadcaster_id, stream);
+                        continue;
+                    }
+                    StreamEvent::Offline { broadcaster_id } => broadcaster_id,
+                };
                 info!("User {uid} finished streaming!");
 
-                let temp_download_dir = temp_download_dir.clone();
+                let Some(stream) = live_streams.remove(&uid) else {
+                    warn!(
+                        "Missed the start of user {uid}'s stream, unable to tell which VOD belongs to it. Use `backfill` to archive it"
+                    );
+                    continue;
+                };
 
-                let vids = twitch::api::list_channel_videos(uid).await?.unwrap();
-                let latest_vod = vids.first().cloned().unwrap();
-                let vod_id = latest_vod.id.parse::<u64>().unwrap();
+                let latest_vod = match monitor::wait_for_stream_vod(&ct, uid, &stream, vod_timeout)
+                    .await
+                {
+                    Ok(StreamVod::Recorded(vod)) => *vod,
+                    Ok(StreamVod::VodsDisabled) => {
+                        warn!(
+                            "User {uid} has VODs disabled, stream {} was not saved by Twitch",
+                            stream.id
+                        );
+                        continue;
+                    }
+                    Ok(StreamVod::TimedOut(vod_id)) => {
+                        warn!(
+                            "VOD of stream {} was not done recording after {}",
+                            stream.id,
+                            util::format_timestamp(vod_timeout)
+                        );
+                        // Archived along with the other queued VODs once it is recorded
+                        let Some(vod_id) = vod_id else {
+                            continue;
+                        };
+                        let vod_id = match vod_id.parse::<u64>() {
+                            Ok(id) => id,
+                            Err(e) => {
+                                warn!("Unable to parse the ID of VOD {vod_id}: {e}");
+                                continue;
+                            }
+                        };
+                        match state.enqueue(vod_id).await {
+                            Ok(_) => info!(
+                                "Queued VOD {vod_id}, it will be archived once it is done recording"
+                            ),
+                            Err(e) => warn!("Unable to queue VOD {vod_id}: {e:#}"),
+                        }
+                        continue;
+                    }
+                    Ok(StreamVod::Cancelled) => break,
+                    Err(e) => {
+                        error!("Unable to find the VOD of stream {}: {e:#}", stream.id);
+                        continue;
+                    }
+                };
+                let vod_id = match latest_vod.id.parse::<u64>() {
+                    Ok(id) => id,
+                    Err(e) => {
+                        warn!("Unable to parse the ID of VOD {}: {e}", latest_vod.id);
+                        continue;
+                    }
+                };
+
+                match state.enqueue(vod_id).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        info!("VOD {vod_id} is already archived or being archived, skipping it");
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Unable to queue VOD {vod_id}: {e:#}");
+                        continue;
+                    }
+                }
 
-                let file_path = download(
-                    ct.clone(),
-                    client.clone(),
+                // Failures are recorded, so monitoring carries on with the next VOD
+                if let Err(e) = download_and_archive(
+                    &ct,
+                    &client,
                     &temp_download_dir,
-                    args.parallelism,
+                    &archive_options,
+                    &latest_vod,
                     vod_id,
+                    &access_token,
                 )
                 .await
-                .unwrap();
-
-                info!("Final file path: {file_path:?}");
+                {
+                    error!("Archiving VOD {vod_id} failed: {e:#}");
+                }
 
                 if ct.is_cancelled() {
-                    info!("CTRL + C caught! Quitting early...");
-                    return Ok(());
+                    break;
                 }
+            }
 
-                let latest_access_token = &access_token.borrow().clone().unwrap();
-                archive(
-                    ct.clone(),
-                    client.clone(),
-                    &file_path,
-                    latest_vod,
-                    latest_access_token,
-                )
-                .await
-                .unwrap();
-
-                if args.cleanup {
-                    info!("Cleaning up processing remnants");
-                    tokio::fs::remove_dir_all(file_path.parent().unwrap())
-                        .await
-                        .unwrap();
-                }
+            // Lets the queued archives record where they stopped
+            if let Some(queued_archives) = queued_archives {
+                queued_archives.await.context("Archiving the queued VODs")?;
+            }
+            if ct.is_cancelled() {
+                info!("CTRL + C caught! Quitting early...");
+                return Ok(());
             }
         }
     }
@@ -293,155 +926,205 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn get_and_print_video_info(vod_id: u64) -> Result<twitch::structs::VideoInfo> {
-    info!("Downloading Twitch Video ID: {vod_id}");
+/// Archives every queued / interrupted VOD of the archive history, oldest first
+///
+/// Failures are recorded, so archiving carries on with the next VOD. VODs still being recorded
+/// are left queued. Stops early once cancelled.
+async fn archive_unfinished(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    temp_download_dir: &Path,
+    archive_options: &ArchiveOptions,
+    state: &StateStore,
+    access_token: &tokio::sync::watch::Receiver<Option<Box<str>>>,
+) -> Result<()> {
+    for job in state.unfinished().await? {
+        let vod_id = job.vod_id;
+        info!("Archiving VOD {vod_id} ({:?})", job.state);
+        let Some(video_info) = twitch::api::get_video_info(vod_id).await? else {
+            warn!("VOD {vod_id} is no longer available");
+            let error = "VOD is no longer available".to_string();
+            state.set(vod_id, JobState::Failed { error }).await?;
+            continue;
+        };
+        if matches!(video_info.status, Status::RECORDING) {
+            debug!("VOD {vod_id} is still being recorded, leaving it queued");
+            continue;
+        }
 
-    let vod_info = twitch::api::get_video_info(vod_id).await?;
-    let Some(vod_info) = vod_info else {
-        panic!("VOD is innacessible!");
-    };
+        if let Err(e) = download_and_archive(
+            ct,
+            client,
+            temp_download_dir,
+            archive_options,
+            &video_info,
+            vod_id,
+            access_token,
+        )
+        .await
+        {
+            error!("Archiving VOD {vod_id} failed: {e:#}");
+        }
 
-    info!("Video title: {}", vod_info.title);
+        if ct.is_cancelled() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn log_rate_limits(download_limiter: &RateLimiter, upload_limiter: &RateLimiter) {
+    let describe =
+        |rate: Option<ByteRate>| rate.map_or_else(|| "unlimited".into(), |r| r.to_string());
     info!(
-        "Video author: {} ({})",
-        vod_info.owner.display_name, vod_info.owner.login
+        "Download rate limit: {}, upload rate limit: {}",
+        describe(download_limiter.rate()),
+        describe(upload_limiter.rate())
     );
-    info!("Video date: {}", vod_info.created_at);
-
-    Ok(vod_info)
 }
 
-async fn download(
-    ct: CancellationToken,
-    client: reqwest::Client,
-    temp_download_dir: &Path,
-    parallelism: usize,
-    vod_id: u64,
-) -> Result<PathBuf> {
-    // Get CDN access tokens
-    let (token_value, token_signature) = twitch::api::get_video_cdn_tokens(vod_id, None)
-        .await
-        .unwrap();
+/// Downloads the chat replay alongside a video when `--with-chat` is set
+///
+/// Failures are only logged so they won't interrupt the video itself
+async fn save_chat(
+    enabled: bool,
+    ct: &CancellationToken,
+    video_info: &twitch::structs::VideoInfo,
+    path: &Path,
+    retry_policy: &RetryPolicy,
+) {
+    if !enabled {
+        return;
+    }
 
-    // Get VOD HLS master playlist file
-    let vod_playlist =
-        twitch::cdn::get_video_playlist_file(vod_id, &token_value, &token_signature).await?;
+    let Ok(vod_id) = video_info.id.parse::<u64>() else {
+        warn!("Unable to save chat: invalid VOD ID {}", video_info.id);
+        return;
+    };
+    let length = Duration::from_secs(video_info.length_seconds);
+    if let Err(e) = twitch::chat::download_chat(ct, vod_id, length, path, retry_policy).await {
+        warn!("Unable to save chat into {path:?}: {e:#}");
+    }
+}
 
-    // TODO: Ensure that this is actually the highest quality variant of VOD
-    let highest_quality = vod_playlist.variants.first().unwrap();
-    info!("Highest quality media uri: {}", highest_quality.uri);
+/// Uploads the chat replay as `SubRip` captions of an archived video
+///
+/// Failures are only logged as the video itself is already uploaded
+async fn upload_chat_captions(
+    client: &reqwest::Client,
+    access_token: &tokio::sync::watch::Receiver<Option<Box<str>>>,
+    archived: &[UploadedVideo],
+    chat_path: &Path,
+    metadata: &VideoMetadata,
+    options: ChatSubtitleOptions,
+) {
+    let [video] = archived else {
+        if archived.len() > 1 {
+            warn!("Chat captions are not supported on videos split into multiple parts");
+        }
+        return;
+    };
 
-    // Get VOD media playlist file
-    let media = twitch::cdn::get_video_media(&highest_quality.uri)
-        .await
-        .context("Getting VOD media")?;
-    let segment_count = media.segments.len();
-    info!("Found {segment_count} segments to download!");
-
-    let temp_download_dir = temp_download_dir.join(format!("vod-squirrel-{vod_id}/"));
-    match tokio::fs::create_dir(&temp_download_dir).await {
-        Ok(()) => {}
-        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
-            warn!("Folder already exists. Was there an uncompleted download?");
+    let captions = match twitch::chat::read_chat(chat_path).await {
+        Ok(messages) => subtitle::render_chat(&messages, SubtitleFormat::Srt, options),
+        Err(e) => {
+            warn!("Unable to read chat for captions: {e:#}");
+            return;
         }
-        Err(e) => bail!(e),
+    };
+    // Chat is mostly written in the language of the stream
+    let language = metadata
+        .default_audio_language
+        .as_deref()
+        .or(metadata.default_language.as_deref())
+        .unwrap_or("en");
+
+    match youtube::insert_caption(client, access_token, video, "Chat", language, captions).await {
+        Ok(()) => info!("Added chat captions to {}", video.url()),
+        Err(e) => warn!("Unable to add chat captions to {}: {e:#}", video.url()),
     }
+}
 
-    info!("Downloading on {temp_download_dir:?} with {parallelism} parallellism",);
-
-    let download_parallelism = Arc::new(Semaphore::new(parallelism));
-    let mut download_tasks = tokio::task::JoinSet::new();
-    let mut segment_file_names = Vec::new();
-    let pb = indicatif::ProgressBar::new(segment_count as u64);
-
-    // Start queueing for downloads
-    for segment in media.segments {
-        // Pre-add to segment_file_names
-        // This is not a simple 1..length as stream name might contain `-muted` for silenced chunks
-        segment_file_names.push(segment.uri.clone());
-
-        let ct = ct.clone();
-        let pb = pb.clone();
-        let permit = download_parallelism.clone();
-        let client = client.clone();
-        let media_url = Url::from_str(&highest_quality.uri)?.join(&segment.uri)?;
-        let temp_file_path = temp_download_dir.clone().join(&segment.uri);
-
-        download_tasks.spawn(async move {
-            let _permit = select! {
-                () = ct.cancelled() => return,
-                p = permit.acquire() => p.unwrap()
-            };
-
-            let req = client.get(media_url).send().await.unwrap();
-            let mut res_stream = req.bytes_stream();
-
-            let mut file = File::create(temp_file_path).await.unwrap();
+/// Downloads a VOD, or a clip when `clip` is set
+async fn download_video_or_clip(
+    ct: &CancellationToken,
+    client: &reqwest::Client,
+    temp_download_dir: &Path,
+    options: &DownloadOptions,
+    video: &twitch::VideoRef,
+    clip: Option<&ClipInfo>,
+) -> Result<download::DownloadedVideo> {
+    match (video, clip) {
+        (_, Some(clip)) => {
+            download::download_clip(ct.clone(), client.clone(), temp_download_dir, options, clip)
+                .await
+        }
+        (twitch::VideoRef::Video(vod_id), None) => {
+            download(
+                ct.clone(),
+                client.clone(),
+                temp_download_dir,
+                options,
+                *vod_id,
+            )
+            .await
+        }
+        (twitch::VideoRef::Clip(slug), None) => bail!("Clip {slug} was not fetched"),
+    }
+}
 
-            while let Some(data) = res_stream.next().await {
-                let data = data
-                    .context(format!("Downloading video stream {}", segment.uri))
-                    .unwrap();
-                file.write_all(&data)
-                    .await
-                    .context("Writing video data to disk")
-                    .unwrap();
-            }
+/// Chat replays are only available for VODs, warns when `--with-chat` is used on a clip
+fn chat_supported(clip: Option<&ClipInfo>) -> bool {
+    if clip.is_some() {
+        warn!("Chat replays are not available for clips, continuing without chat");
+    }
+    clip.is_none()
+}
 
-            debug!("Done downloading {}!", segment.uri);
-            pb.inc(1);
-        });
+async fn get_and_print_video_or_clip_info(
+    video: &twitch::VideoRef,
+) -> Result<(VideoInfo, Option<ClipInfo>)> {
+    match video {
+        twitch::VideoRef::Video(vod_id) => Ok((get_and_print_video_info(*vod_id).await?, None)),
+        twitch::VideoRef::Clip(slug) => {
+            let clip = get_and_print_clip_info(slug).await?;
+            Ok((clip.to_video_info(), Some(clip)))
+        }
     }
+}
 
-    download_tasks.join_all().await;
-    pb.finish_and_clear();
-    info!("Done downloading all chunks!");
+async fn get_and_print_clip_info(slug: &str) -> Result<ClipInfo> {
+    info!("Downloading Twitch Clip: {slug}");
 
-    info!("Concatenating video chunks now");
-    let out_file_path = temp_download_dir.join("out.mp4");
-    concat_video(&temp_download_dir, segment_file_names, &out_file_path).await?;
-    info!("Successfully concatenated video!");
+    let Some(clip) = twitch::api::get_clip_info(slug).await? else {
+        bail!("Clip is innacessible!");
+    };
+
+    info!("Clip title: {}", clip.title);
+    info!(
+        "Clip author: {} ({})",
+        clip.broadcaster.display_name, clip.broadcaster.login
+    );
+    info!("Clip date: {}", clip.created_at);
 
-    Ok(out_file_path)
+    Ok(clip)
 }
 
-async fn archive(
-    ct: CancellationToken,
-    client: reqwest::Client,
-    video_path: &Path,
-    video_info: twitch::structs::VideoInfo,
-    access_token: &str,
-) -> Result<()> {
-    let final_file = File::open(video_path).await.unwrap();
-
-    info!("Uploading video to Youtube...");
-    upload_video(
-        ct.clone(),
-        client,
-        access_token,
-        VideoDetail {
-            title: &format!(
-                "[{}] {}",
-                video_info.created_at.date_naive(),
-                // Twitch stream title could be up to 140 chars
-                // YouTube title could only go up to 100 chars
-                truncate_string(&video_info.title, 85)
-            ),
-            description: &indoc::formatdoc!(
-                "Original stream title: {}
-                Streamed {} @ https://twitch.tv/{}
-                Game: {}",
-                video_info.title,
-                video_info.created_at,
-                video_info.owner.login,
-                video_info.game.display_name
-            ),
-        },
-        final_file,
-    )
-    .await
-    .unwrap();
-    info!("Video successfully uploaded");
+async fn get_and_print_video_info(vod_id: u64) -> Result<twitch::structs::VideoInfo> {
+    info!("Downloading Twitch Video ID: {vod_id}");
 
-    Ok(())
+    let vod_info = twitch::api::get_video_info(vod_id).await?;
+    let Some(vod_info) = vod_info else {
+        panic!("VOD is innacessible!");
+    };
+
+    info!("Video title: {}", vod_info.title);
+    info!(
+        "Video author: {} ({})",
+        vod_info.owner.display_name, vod_info.owner.login
+    );
+    info!("Video date: {}", vod_info.created_at);
+
+    Ok(vod_info)
 }
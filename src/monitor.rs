@@ -0,0 +1,192 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::twitch::{
+    api::{get_live_stream, list_channel_videos},
+    structs::{BroadcastType, LiveStream, Status, VideoInfo},
+};
+
+/// VODs are created within this long of the start of their stream
+const MATCH_TOLERANCE: Duration = Duration::from_mins(10);
+/// Delay between every check for the VOD of a stream
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Channels without any VOD of a stream after this long are assumed to have VODs disabled
+const MISSING_VOD_GRACE: Duration = Duration::from_mins(3);
+/// Delay between every attempt at archiving the VODs left in the archive history, e.g. the ones
+/// still being recorded once [`wait_for_stream_vod`] timed out
+pub const QUEUE_INTERVAL: Duration = Duration::from_mins(10);
+
+/// Outcome of waiting for the VOD of a stream
+#[derive(Debug)]
+pub enum StreamVod {
+    /// The VOD is done being recorded
+    Recorded(Box<VideoInfo>),
+    /// No VOD was saved, as the channel does not save its past broadcasts
+    VodsDisabled,
+    /// The VOD, when known, was still being recorded once the timeout passed
+    TimedOut(Option<String>),
+    Cancelled,
+}
+
+/// Looks up the VOD a live stream is being recorded into, when it is not known yet
+///
+/// Streams from `EventSub` notifications never come with their VOD, and the VOD may only be
+/// created a little after the stream started
+pub async fn lookup_archive_video(channel_id: u64, stream: &mut LiveStream) {
+    if stream.archive_video.is_some() {
+        return;
+    }
+
+    match get_live_stream(channel_id).await {
+        Ok(Some(live)) if live.id == stream.id && live.archive_video.is_some() => {
+            stream.archive_video = live.archive_video;
+        }
+        Ok(_) => debug!("VOD of stream {} is not known yet", stream.id),
+        Err(e) => warn!("Unable to look up the VOD of stream {}: {e:#}", stream.id),
+    }
+}
+
+/// Polls the past broadcasts of a channel until the VOD of `stream` is done being recorded
+///
+/// VODs are matched by the VOD of the stream when known. Otherwise they are matched by their
+/// creation time, which is within [`MATCH_TOLERANCE`] of the start of their stream. Highlights,
+/// uploads and older VODs are never picked.
+///
+/// # Errors
+/// Errors when the channel does not exist
+pub async fn wait_for_stream_vod(
+    ct: &CancellationToken,
+    channel_id: u64,
+    stream: &LiveStream,
+    timeout: Duration,
+) -> Result<StreamVod> {
+    let started = Instant::now();
+    let mut last_match = stream.archive_video.as_ref().map(|v| v.id.clone());
+    // Only successful listings tell that the VOD is missing
+    let mut missing_since = None;
+
+    loop {
+        match list_channel_videos(channel_id, BroadcastType::Archive).await {
+            Ok(Some(videos)) => match stream_vod(videos, stream) {
+                Some(vod) if matches!(vod.status, Status::RECORDED) => {
+                    return Ok(StreamVod::Recorded(Box::new(vod)));
+                }
+                Some(vod) => {
+                    debug!("VOD {} is still being recorded", vod.id);
+                    last_match = Some(vod.id);
+                }
+                None => {
+                    let since = *missing_since.get_or_insert_with(Instant::now);
+                    if last_match.is_none() && since.elapsed() >= MISSING_VOD_GRACE {
+                        return Ok(StreamVod::VodsDisabled);
+                    }
+                    debug!("VOD of stream {} is not listed yet", stream.id);
+                }
+            },
+            Ok(None) => bail!("Channel {channel_id} does not exist"),
+            Err(e) => warn!("Unable to list the VODs of channel {channel_id}, retrying: {e:#}"),
+        }
+
+        if started.elapsed() >= timeout {
+            return Ok(StreamVod::TimedOut(last_match));
+        }
+
+        tokio::select! {
+            () = ct.cancelled() => return Ok(StreamVod::Cancelled),
+            () = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+/// The VOD of `stream`, or else the VOD created the closest to its start
+fn stream_vod(videos: Vec<VideoInfo>, stream: &LiveStream) -> Option<VideoInfo> {
+    let mut archives = videos
+        .into_iter()
+        .filter(|v| v.broadcast_type == BroadcastType::Archive);
+    if let Some(archive) = &stream.archive_video {
+        return archives.find(|v| v.id == archive.id);
+    }
+
+    let distance =
+        |v: &VideoInfo| (v.created_at.timestamp() - stream.started_at.timestamp()).unsigned_abs();
+    archives
+        .filter(|v| distance(v) <= MATCH_TOLERANCE.as_secs())
+        .min_by_key(distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+    use crate::twitch::structs::{ArchiveVideo, Channel};
+
+    fn video(id: &str, created_at: &str, broadcast_type: BroadcastType) -> VideoInfo {
+        VideoInfo {
+            id: id.to_string(),
+            title: "Speedrun".to_string(),
+            description: None,
+            created_at: created_at.parse::<DateTime<Utc>>().unwrap(),
+            length_seconds: 3600,
+            view_count: 0,
+            status: Status::RECORDING,
+            broadcast_type,
+            preview_thumbnail_url: None,
+            game: None,
+            owner: Channel {
+                login: "streamer".to_string(),
+                display_name: "Streamer".to_string(),
+                profile_image_url: None,
+                banner_image_url: None,
+            },
+        }
+    }
+
+    fn stream(archive_video: Option<&str>) -> LiveStream {
+        LiveStream {
+            id: "42".to_string(),
+            started_at: "2025-01-31T12:00:00Z".parse().unwrap(),
+            archive_video: archive_video.map(|id| ArchiveVideo { id: id.to_string() }),
+        }
+    }
+
+    fn matched(videos: Vec<VideoInfo>, stream: &LiveStream) -> Option<String> {
+        stream_vod(videos, stream).map(|v| v.id)
+    }
+
+    #[test]
+    fn matches_the_vod_of_the_stream() {
+        let videos = || {
+            vec![
+                video("3", "2025-01-31T12:00:05Z", BroadcastType::Archive),
+                video("2", "2025-01-31T09:00:00Z", BroadcastType::Archive),
+            ]
+        };
+        assert_eq!(matched(videos(), &stream(Some("2"))).as_deref(), Some("2"));
+        // Not listed yet, even though another VOD started with the stream
+        assert_eq!(matched(videos(), &stream(Some("4"))), None);
+    }
+
+    #[test]
+    fn falls_back_to_the_closest_vod() {
+        let videos = vec![
+            video("4", "2025-01-31T12:01:00Z", BroadcastType::Highlight),
+            video("3", "2025-01-31T12:02:00Z", BroadcastType::Archive),
+            video("2", "2025-01-31T11:57:00Z", BroadcastType::Archive),
+            video("1", "2025-01-30T12:00:00Z", BroadcastType::Archive),
+        ];
+        assert_eq!(matched(videos, &stream(None)).as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn ignores_vods_of_other_streams() {
+        let videos = vec![
+            video("2", "2025-01-31T12:11:00Z", BroadcastType::Archive),
+            video("1", "2025-01-31T11:49:00Z", BroadcastType::Archive),
+        ];
+        assert_eq!(matched(videos, &stream(None)), None);
+    }
+}
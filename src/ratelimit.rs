@@ -0,0 +1,191 @@
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt as _};
+use serde::Deserialize;
+
+/// A transfer rate in bytes per second
+///
+/// Parsed from a number with an optional `K` / `M` / `G` suffix (powers of 1000), optionally
+/// followed by `B` or `B/s`, e.g. `500K`, `10M` or `1.5MB/s`. Suffixes are case insensitive and
+/// always mean bytes, so `1.5mb/s` is 1.5 megabytes and not megabits per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ByteRate(pub u64);
+
+impl FromStr for ByteRate {
+    type Err = anyhow::Error;
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn from_str(s: &str) -> Result<Self> {
+        let rate = s.trim();
+        let rate = strip_suffix_ignore_case(rate, "/s");
+        let rate = strip_suffix_ignore_case(rate, "b");
+
+        let (number, multiplier) = match rate.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&rate[..rate.len() - 1], 1e3),
+            Some('M') => (&rate[..rate.len() - 1], 1e6),
+            Some('G') => (&rate[..rate.len() - 1], 1e9),
+            _ => (rate, 1.0),
+        };
+        let number = number
+            .trim()
+            .parse::<f64>()
+            .context(format!("`{s}` is not a valid transfer rate"))?;
+        if !number.is_finite() || number <= 0.0 {
+            bail!("Transfer rate must be more than 0");
+        }
+
+        Ok(Self((number * multiplier).max(1.0) as u64))
+    }
+}
+
+fn strip_suffix_ignore_case<'a>(s: &'a str, suffix: &str) -> &'a str {
+    s.len()
+        .checked_sub(suffix.len())
+        .filter(|&i| s.is_char_boundary(i) && s[i..].eq_ignore_ascii_case(suffix))
+        .map_or(s, |i| &s[..i])
+}
+
+impl TryFrom<String> for ByteRate {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl Display for ByteRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/s", indicatif::DecimalBytes(self.0))
+    }
+}
+
+/// A token bucket shared by every transfer it limits, e.g. all parallel segment downloads
+///
+/// The rate can be changed while transfers are running, see [`RateLimiter::set_rate`]
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// `None` when unlimited
+    rate: Option<ByteRate>,
+    /// Bytes available to be transferred right away. Negative when transfers are in debt and
+    /// have to wait for the bucket to refill
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new(rate: Option<ByteRate>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    #[must_use]
+    pub fn rate(&self) -> Option<ByteRate> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Changes the rate of every transfer using this limiter, `None` removes the limit
+    pub fn set_rate(&self, rate: Option<ByteRate>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = rate;
+        bucket.tokens = 0.0;
+        bucket.last_refill = Instant::now();
+    }
+
+    /// Takes `bytes` out of the bucket, waiting until the bucket refills when it runs out
+    #[allow(clippy::cast_precision_loss)]
+    pub async fn acquire(&self, bytes: usize) {
+        let delay = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let Some(ByteRate(rate)) = bucket.rate else {
+                return;
+            };
+
+            // Allow bursts of up to a second worth of bytes
+            let now = Instant::now();
+            let refilled = now.duration_since(bucket.last_refill).as_secs_f64() * rate as f64;
+            bucket.tokens = (bucket.tokens + refilled).min(rate as f64) - bytes as f64;
+            bucket.last_refill = now;
+
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        };
+
+        tokio::time::sleep(delay).await;
+    }
+
+    /// Limits the rate of a stream of data chunks
+    pub fn throttle<S, E>(
+        self: &Arc<Self>,
+        stream: S,
+    ) -> impl Stream<Item = Result<Bytes, E>> + use<S, E>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+    {
+        let limiter = self.clone();
+        stream.then(move |chunk| {
+            let limiter = limiter.clone();
+            async move {
+                if let Ok(data) = &chunk {
+                    limiter.acquire(data.len()).await;
+                }
+                chunk
+            }
+        })
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_unit_suffixes() {
+        assert_eq!("500".parse::<ByteRate>().unwrap(), ByteRate(500));
+        assert_eq!("500K".parse::<ByteRate>().unwrap(), ByteRate(500_000));
+        assert_eq!("10m".parse::<ByteRate>().unwrap(), ByteRate(10_000_000));
+        assert_eq!("1.5MB/s".parse::<ByteRate>().unwrap(), ByteRate(1_500_000));
+        assert_eq!("1.5mb/s".parse::<ByteRate>().unwrap(), ByteRate(1_500_000));
+        assert_eq!("500kb".parse::<ByteRate>().unwrap(), ByteRate(500_000));
+        assert_eq!("64B/S".parse::<ByteRate>().unwrap(), ByteRate(64));
+        assert_eq!(
+            " 2 GB ".parse::<ByteRate>().unwrap(),
+            ByteRate(2_000_000_000)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        assert!("".parse::<ByteRate>().is_err());
+        assert!("0".parse::<ByteRate>().is_err());
+        assert!("-5M".parse::<ByteRate>().is_err());
+        assert!("fastM".parse::<ByteRate>().is_err());
+        assert!("5T".parse::<ByteRate>().is_err());
+    }
+}
@@ -0,0 +1,489 @@
+use std::{
+    collections::HashMap,
+    fs::TryLockError,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+use tracing::{debug, info, warn};
+
+/// The journal is rewritten on startup once it has this many more entries than VODs
+const COMPACTION_THRESHOLD: usize = 1000;
+
+/// Where a VOD is in its archival
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting to be archived
+    Queued,
+    Downloading,
+    /// Downloaded and concatenated into a single video
+    Concatenated,
+    Uploading,
+    Uploaded {
+        /// Every part the VOD was uploaded as
+        youtube_ids: Vec<String>,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+impl JobState {
+    /// Queued or somewhere in the middle of being archived
+    #[must_use]
+    pub const fn is_unfinished(&self) -> bool {
+        !matches!(self, Self::Uploaded { .. } | Self::Failed { .. })
+    }
+}
+
+/// A single state transition, stored as a line of the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub vod_id: u64,
+    pub updated_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub state: JobState,
+}
+
+/// Contents of the journal file
+struct Journal {
+    /// Last state of every VOD
+    jobs: HashMap<u64, Job>,
+    entries: usize,
+    /// Whether the last entry was only partially written
+    partial: bool,
+}
+
+/// Archive history of every VOD, persisted as a journal of state transitions (JSON lines)
+///
+/// Every transition is appended and synced to disk before moving on, so that VODs are never
+/// archived twice and unfinished ones can be resumed after a crash
+///
+/// The journal can be shared by several processes (e.g. `archive` next to `monitor`): it is
+/// re-read before every transition and only locked while being written to or compacted
+pub struct StateStore {
+    path: PathBuf,
+    /// Last known state of every VOD, refreshed from the journal whenever it is used
+    jobs: Mutex<HashMap<u64, Job>>,
+}
+
+impl std::fmt::Debug for StateStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateStore")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Exclusive right to archive a VOD, released once dropped
+///
+/// Held as a locked `<path>.claims/<vod_id>.lock` file, so that processes sharing the journal
+/// never archive the same VOD at once. The file is never removed, as another process may be
+/// waiting to lock it, and would then hold a lock on a file nobody else can see anymore.
+#[derive(Debug)]
+pub struct Claim {
+    _file: std::fs::File,
+}
+
+impl StateStore {
+    /// Opens the journal at `path`, creating it when missing
+    ///
+    /// A partially written last entry (e.g. from a crash) is ignored
+    ///
+    /// # Errors
+    /// Errors when the journal cannot be locked, cannot be read, has malformed entries or cannot
+    /// be written
+    pub async fn open(path: &Path) -> Result<Self> {
+        let lock = lock(path).await?;
+        let journal = read_journal(path).await?;
+        debug!(
+            "Loaded {} VODs from {} journal entries",
+            journal.jobs.len(),
+            journal.entries
+        );
+        if journal.partial {
+            warn!("Ignoring the partially written last entry of the state journal");
+        }
+        if journal.entries > journal.jobs.len() + COMPACTION_THRESHOLD || journal.partial {
+            compact(path, &journal.jobs).await?;
+        }
+        drop(lock);
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            jobs: Mutex::new(journal.jobs),
+        })
+    }
+
+    /// The last known state of a VOD
+    ///
+    /// # Errors
+    /// Errors when the journal cannot be read or has malformed entries
+    pub async fn get(&self, vod_id: u64) -> Result<Option<JobState>> {
+        let mut jobs = self.jobs.lock().await;
+        *jobs = read_journal(&self.path).await?.jobs;
+        Ok(jobs.get(&vod_id).map(|j| j.state.clone()))
+    }
+
+    /// Records a state transition of a VOD
+    ///
+    /// # Errors
+    /// Errors when the transition cannot be written to disk
+    pub async fn set(&self, vod_id: u64, state: JobState) -> Result<()> {
+        self.update(vod_id, |_| Some(state)).await?;
+        Ok(())
+    }
+
+    /// Queues a VOD unless it is already archived or on its way. Failed VODs are queued again.
+    ///
+    /// Returns whether the VOD was queued
+    ///
+    /// # Errors
+    /// Errors when the transition cannot be written to disk
+    pub async fn enqueue(&self, vod_id: u64) -> Result<bool> {
+        self.update(vod_id, |state| {
+            matches!(state, Some(JobState::Failed { .. }) | None).then_some(JobState::Queued)
+        })
+        .await
+    }
+
+    /// VODs which are queued or were interrupted, oldest first
+    ///
+    /// # Errors
+    /// Errors when the journal cannot be read or has malformed entries
+    pub async fn unfinished(&self) -> Result<Vec<Job>> {
+        let mut jobs = self.jobs.lock().await;
+        *jobs = read_journal(&self.path).await?.jobs;
+        let mut unfinished = jobs
+            .values()
+            .filter(|j| j.state.is_unfinished())
+            .cloned()
+            .collect::<Vec<_>>();
+        drop(jobs);
+        unfinished.sort_by_key(|j| j.updated_at);
+        Ok(unfinished)
+    }
+
+    /// Claims an unfinished VOD to be archived by this process
+    ///
+    /// Returns `None` when the VOD is being archived by another process, or is not unfinished
+    /// anymore
+    ///
+    /// # Errors
+    /// Errors when the claim cannot be locked or the journal cannot be read
+    pub async fn claim(&self, vod_id: u64) -> Result<Option<Claim>> {
+        let dir = self.path.with_extension("claims");
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .context("Creating VOD claims directory")?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(format!("{vod_id}.lock")))
+            .await
+            .context("Opening VOD claim")?
+            .into_std()
+            .await;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => return Err(e).context("Locking VOD claim"),
+        }
+        let claim = Claim { _file: file };
+
+        // Another process may have finished it before it could be claimed
+        let unfinished = self
+            .get(vod_id)
+            .await?
+            .is_some_and(|state| state.is_unfinished());
+        Ok(unfinished.then_some(claim))
+    }
+
+    /// Appends the transition returned by `next` for the latest state of a VOD, if any
+    ///
+    /// The journal stays locked in between, so that no other process writes a transition based
+    /// on an outdated state
+    async fn update(
+        &self,
+        vod_id: u64,
+        next: impl FnOnce(Option<&JobState>) -> Option<JobState> + Send,
+    ) -> Result<bool> {
+        let mut jobs = self.jobs.lock().await;
+        let lock = lock(&self.path).await?;
+        let journal = read_journal(&self.path).await?;
+        // Nothing else writes to the journal while it is locked, so the entry will never be
+        // completed
+        if journal.partial {
+            warn!("Ignoring the partially written last entry of the state journal");
+            compact(&self.path, &journal.jobs).await?;
+        }
+        *jobs = journal.jobs;
+
+        let Some(state) = next(jobs.get(&vod_id).map(|j| &j.state)) else {
+            return Ok(false);
+        };
+        let job = Job {
+            vod_id,
+            updated_at: Utc::now(),
+            state,
+        };
+        let mut line = serde_json::to_string(&job).context("Serializing state")?;
+        line.push('\n');
+
+        // Opened every time, as the journal file is replaced when compacted
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .context("Opening state journal")?;
+        file.write_all(line.as_bytes())
+            .await
+            .context("Writing state journal")?;
+        file.sync_data().await.context("Writing state journal")?;
+        drop(lock);
+        debug!("VOD {vod_id} is now {:?}", job.state);
+        jobs.insert(vod_id, job);
+        drop(jobs);
+
+        Ok(true)
+    }
+}
+
+/// Waits for the lock of the journal at `path`, released once the returned file is dropped
+///
+/// A separate lock file is used, as compacting replaces the journal file itself
+async fn lock(path: &Path) -> Result<std::fs::File> {
+    let lock_path = path.with_extension("lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .await
+        .context("Opening state journal lock")?
+        .into_std()
+        .await;
+
+    // Only ever held for a single write, so waiting on it is short
+    tokio::task::spawn_blocking(move || file.lock().map(|()| file))
+        .await
+        .context("Locking state journal")?
+        .context("Locking state journal")
+}
+
+/// Reads every complete entry of the journal at `path`
+///
+/// The last entry may be partially written when the journal is read while another process
+/// writes to it, or after a crash
+async fn read_journal(path: &Path) -> Result<Journal> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context("Reading state journal"),
+    };
+
+    let mut jobs = HashMap::new();
+    let mut entries = 0;
+    let complete = contents.rfind('\n').map_or("", |end| &contents[..=end]);
+    for line in complete.lines().filter(|l| !l.trim().is_empty()) {
+        let job = serde_json::from_str::<Job>(line)
+            .with_context(|| format!("Parsing state journal entry: {line}"))?;
+        jobs.insert(job.vod_id, job);
+        entries += 1;
+    }
+
+    Ok(Journal {
+        jobs,
+        entries,
+        partial: complete.len() < contents.len(),
+    })
+}
+
+/// Rewrites the journal with only the last state of every VOD
+///
+/// Must only be called while the journal is locked
+async fn compact(path: &Path, jobs: &HashMap<u64, Job>) -> Result<()> {
+    let mut contents = String::new();
+    let mut sorted = jobs.values().collect::<Vec<_>>();
+    sorted.sort_by_key(|j| j.updated_at);
+    for job in sorted {
+        contents.push_str(&serde_json::to_string(job).context("Serializing state")?);
+        contents.push('\n');
+    }
+
+    // Written next to the journal first, so a crash leaves either the old or the new one
+    let temp_path = path.with_extension("tmp");
+    tokio::fs::write(&temp_path, contents)
+        .await
+        .context("Writing compacted state journal")?;
+    tokio::fs::rename(&temp_path, path)
+        .await
+        .context("Replacing state journal")?;
+    info!("Compacted the state journal to {} entries", jobs.len());
+
+    Ok(())
+}
+
+/// Records a state transition when archival is tracked by a [`StateStore`]
+///
+/// # Errors
+/// Errors when the transition cannot be written to disk
+pub async fn record(store: Option<&StateStore>, vod_id: u64, state: JobState) -> Result<()> {
+    match store {
+        Some(store) => store.set(vod_id, state).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir;
+
+    fn failed() -> JobState {
+        JobState::Failed {
+            error: "Boom".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_the_journal() {
+        let path = test_dir("state-replay").join("state.jsonl");
+        let store = StateStore::open(&path).await.unwrap();
+        store.set(1, JobState::Queued).await.unwrap();
+        store.set(1, JobState::Downloading).await.unwrap();
+        store.set(2, JobState::Queued).await.unwrap();
+        let uploaded = JobState::Uploaded {
+            youtube_ids: vec!["abc".to_string()],
+        };
+        store.set(2, uploaded.clone()).await.unwrap();
+        drop(store);
+
+        let store = StateStore::open(&path).await.unwrap();
+        assert_eq!(store.get(1).await.unwrap(), Some(JobState::Downloading));
+        assert_eq!(store.get(2).await.unwrap(), Some(uploaded));
+        assert_eq!(store.get(3).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn enqueues_vods_once() {
+        let path = test_dir("state-enqueue").join("state.jsonl");
+        let store = StateStore::open(&path).await.unwrap();
+        let other = StateStore::open(&path).await.unwrap();
+
+        assert!(store.enqueue(1).await.unwrap());
+        assert!(!store.enqueue(1).await.unwrap());
+        // Written by another process sharing the journal
+        assert!(!other.enqueue(1).await.unwrap());
+        other.set(1, JobState::Concatenated).await.unwrap();
+        assert!(!store.enqueue(1).await.unwrap());
+        assert_eq!(store.get(1).await.unwrap(), Some(JobState::Concatenated));
+    }
+
+    #[tokio::test]
+    async fn requeues_failed_vods() {
+        let path = test_dir("state-requeue").join("state.jsonl");
+        let store = StateStore::open(&path).await.unwrap();
+        store.set(1, failed()).await.unwrap();
+
+        assert!(store.enqueue(1).await.unwrap());
+        assert_eq!(store.get(1).await.unwrap(), Some(JobState::Queued));
+    }
+
+    #[tokio::test]
+    async fn lists_unfinished_vods_oldest_first() {
+        let path = test_dir("state-unfinished").join("state.jsonl");
+        let store = StateStore::open(&path).await.unwrap();
+        store.set(3, JobState::Downloading).await.unwrap();
+        store.set(1, failed()).await.unwrap();
+        store.set(2, JobState::Queued).await.unwrap();
+        store
+            .set(
+                4,
+                JobState::Uploaded {
+                    youtube_ids: Vec::new(),
+                },
+            )
+            .await
+            .unwrap();
+        store.set(5, JobState::Uploading).await.unwrap();
+
+        let unfinished = store.unfinished().await.unwrap();
+        let ids = unfinished.iter().map(|j| j.vod_id).collect::<Vec<_>>();
+        assert_eq!(ids, [3, 2, 5]);
+    }
+
+    fn entry(vod_id: u64, state: JobState) -> String {
+        let job = Job {
+            vod_id,
+            updated_at: Utc::now(),
+            state,
+        };
+        serde_json::to_string(&job).unwrap() + "\n"
+    }
+
+    #[tokio::test]
+    async fn compacts_long_journals() {
+        let path = test_dir("state-compact").join("state.jsonl");
+        let mut contents = String::new();
+        for _ in 0..=COMPACTION_THRESHOLD / 2 {
+            contents.push_str(&entry(1, JobState::Queued));
+            contents.push_str(&entry(2, JobState::Downloading));
+        }
+        contents.push_str(&entry(2, JobState::Uploading));
+        std::fs::write(&path, contents).unwrap();
+
+        let store = StateStore::open(&path).await.unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert_eq!(store.get(1).await.unwrap(), Some(JobState::Queued));
+        assert_eq!(store.get(2).await.unwrap(), Some(JobState::Uploading));
+    }
+
+    #[tokio::test]
+    async fn drops_partially_written_entries() {
+        let path = test_dir("state-partial").join("state.jsonl");
+        let mut contents = entry(1, JobState::Queued);
+        contents.push_str(r#"{"vod_id":2,"#);
+        std::fs::write(&path, contents).unwrap();
+
+        let store = StateStore::open(&path).await.unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.ends_with('\n'));
+        assert_eq!(store.get(1).await.unwrap(), Some(JobState::Queued));
+        assert_eq!(store.get(2).await.unwrap(), None);
+        store.set(2, JobState::Queued).await.unwrap();
+        assert_eq!(store.get(2).await.unwrap(), Some(JobState::Queued));
+    }
+
+    #[tokio::test]
+    async fn claims_unfinished_vods_once() {
+        let path = test_dir("state-claim").join("state.jsonl");
+        let store = StateStore::open(&path).await.unwrap();
+        let other = StateStore::open(&path).await.unwrap();
+        store.enqueue(1).await.unwrap();
+
+        let claim = store.claim(1).await.unwrap();
+        assert!(claim.is_some());
+        assert!(other.claim(1).await.unwrap().is_none());
+        other.set(1, failed()).await.unwrap();
+        drop(claim);
+        // Kept around, so that the lock is always taken on the same file
+        assert!(path.with_extension("claims").join("1.lock").is_file());
+
+        // Finished by the other process
+        assert!(store.claim(1).await.unwrap().is_none());
+        // Never queued
+        assert!(store.claim(2).await.unwrap().is_none());
+        assert!(other.enqueue(1).await.unwrap());
+        assert!(store.claim(1).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,402 @@
+use std::{fmt::Write, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    ffmpeg,
+    twitch::chat::{ChatMessage, read_chat},
+};
+
+/// Name of the chat subtitle file muxed into downloaded videos, next to the video
+const MUX_SUBTITLE_FILE_NAME: &str = "chat.ass";
+
+/// Colors Twitch assigns to users who never picked a name color
+const DEFAULT_NAME_COLORS: [&str; 15] = [
+    "#FF0000", "#0000FF", "#00FF00", "#B22222", "#FF7F50", "#9ACD32", "#FF4500", "#2E8B57",
+    "#DAA520", "#D2691E", "#5F9EA0", "#1E90FF", "#FF69B4", "#8A2BE2", "#00FF7F",
+];
+
+/// Chat is drawn on the bottom left third of a 1920x1080 canvas, scaled to the video by players
+const ASS_HEADER: &str = "[Script Info]
+ScriptType: v4.00+
+PlayResX: 1920
+PlayResY: 1080
+WrapStyle: 0
+ScaledBorderAndShadow: yes
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Chat,Arial,36,&H00FFFFFF,&H00FFFFFF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,2,0,1,30,1200,30,1
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+";
+
+/// Subtitle formats chat replays can be rendered to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SubtitleFormat {
+    /// `SubRip`, plain text
+    Srt,
+    /// `WebVTT`, plain text
+    #[value(name = "vtt")]
+    WebVtt,
+    /// Advanced `SubStation` Alpha, with user colors
+    Ass,
+}
+
+impl SubtitleFormat {
+    /// Guesses the format from a file extension
+    #[must_use]
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "srt" => Some(Self::Srt),
+            "vtt" => Some(Self::WebVtt),
+            "ass" | "ssa" => Some(Self::Ass),
+            _ => None,
+        }
+    }
+}
+
+/// How chat replays look when rendered to subtitles
+#[derive(Debug, Clone, Copy, clap::Args)]
+pub struct ChatSubtitleOptions {
+    /// How long each chat message stays on screen when rendered to subtitles
+    #[arg(long = "chat-duration", default_value_t = 10, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub duration: u64,
+
+    /// The amount of chat messages shown at once when rendered to subtitles
+    #[arg(long = "chat-lines", default_value_t = 8, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    pub lines: u32,
+}
+
+/// Messages on screen from `start` to `end` (in seconds)
+struct Frame<'a> {
+    start: u64,
+    end: u64,
+    messages: &'a [ChatMessage],
+}
+
+/// Renders a chat replay into subtitles, like the scrolling chat box on Twitch: new messages
+/// appear at the bottom and push older ones up until they expire
+///
+/// `messages` must be sorted by their offset, see [`crate::twitch::chat::read_chat`]
+#[must_use]
+pub fn render_chat(
+    messages: &[ChatMessage],
+    format: SubtitleFormat,
+    options: ChatSubtitleOptions,
+) -> String {
+    let frames = frames(messages, options);
+
+    let mut out = String::new();
+    match format {
+        SubtitleFormat::Srt => {
+            for (i, frame) in frames.iter().enumerate() {
+                let lines = frame
+                    .messages
+                    .iter()
+                    .map(plain_line)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let _ = write!(
+                    out,
+                    "{}\n{},000 --> {},000\n{lines}\n\n",
+                    i + 1,
+                    timestamp(frame.start),
+                    timestamp(frame.end)
+                );
+            }
+        }
+        SubtitleFormat::WebVtt => {
+            out.push_str("WEBVTT\n\n");
+            for frame in &frames {
+                let lines = frame
+                    .messages
+                    .iter()
+                    .map(|m| escape_vtt(&plain_line(m)))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let _ = write!(
+                    out,
+                    "{}.000 --> {}.000 position:2% align:start size:35%\n{lines}\n\n",
+                    timestamp(frame.start),
+                    timestamp(frame.end)
+                );
+            }
+        }
+        SubtitleFormat::Ass => {
+            out.push_str(ASS_HEADER);
+            for frame in &frames {
+                let lines = frame
+                    .messages
+                    .iter()
+                    .map(ass_line)
+                    .collect::<Vec<_>>()
+                    .join("\\N");
+                let _ = writeln!(
+                    out,
+                    "Dialogue: 0,{},{},Chat,,0,0,0,,{lines}",
+                    ass_timestamp(frame.start),
+                    ass_timestamp(frame.end)
+                );
+            }
+        }
+    }
+
+    out
+}
+
+/// Renders a chat replay saved by [`crate::twitch::chat::download_chat`] into a subtitle file
+///
+/// # Errors
+/// Errors when the chat cannot be read or the subtitles cannot be written
+pub async fn write_chat_subtitles(
+    chat: &Path,
+    out_file: &Path,
+    format: SubtitleFormat,
+    options: ChatSubtitleOptions,
+) -> Result<()> {
+    let messages = read_chat(chat).await?;
+    tokio::fs::write(out_file, render_chat(&messages, format, options))
+        .await
+        .context("Writing subtitles")
+}
+
+/// Copies `video` into `out_file` with the chat replay added as a subtitle stream
+///
+/// # Errors
+/// Errors when the subtitles cannot be rendered or ffmpeg fails to mux them
+pub async fn mux_chat(
+    video: &Path,
+    chat: &Path,
+    out_file: &Path,
+    options: ChatSubtitleOptions,
+) -> Result<()> {
+    let subtitles = video.with_file_name(MUX_SUBTITLE_FILE_NAME);
+    write_chat_subtitles(chat, &subtitles, SubtitleFormat::Ass, options).await?;
+
+    ffmpeg::mux_subtitles(video, &subtitles, out_file, "Chat").await
+}
+
+/// Splits the replay into frames at every point a message appears or expires
+fn frames(messages: &[ChatMessage], options: ChatSubtitleOptions) -> Vec<Frame<'_>> {
+    let mut boundaries = messages
+        .iter()
+        .flat_map(|m| [m.offset, m.offset + options.duration])
+        .collect::<Vec<_>>();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut frames = Vec::new();
+    // Messages on screen are always a contiguous range, as they are sorted by offset
+    let (mut first, mut last) = (0, 0);
+    for window in boundaries.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        while last < messages.len() && messages[last].offset <= start {
+            last += 1;
+        }
+        while first < last && messages[first].offset + options.duration <= start {
+            first += 1;
+        }
+        if first == last {
+            continue;
+        }
+
+        let shown = first.max(last.saturating_sub(options.lines as usize));
+        frames.push(Frame {
+            start,
+            end,
+            messages: &messages[shown..last],
+        });
+    }
+
+    frames
+}
+
+/// `HH:MM:SS`
+fn timestamp(seconds: u64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+/// `H:MM:SS.cc`, ASS timestamps have a single hour digit and centiseconds
+fn ass_timestamp(seconds: u64) -> String {
+    format!(
+        "{}:{:02}:{:02}.00",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+fn author_name(message: &ChatMessage) -> &str {
+    message
+        .author
+        .as_ref()
+        .map_or("(deleted user)", |a| a.display_name.as_str())
+}
+
+/// `Name: message`, on a single line
+fn plain_line(message: &ChatMessage) -> String {
+    format!(
+        "{}: {}",
+        author_name(message),
+        message.message.replace(['\r', '\n'], " ")
+    )
+}
+
+/// Bold `Name` in the user's color, followed by the message
+fn ass_line(message: &ChatMessage) -> String {
+    let (r, g, b) = name_color(message);
+    format!(
+        "{{\\b1\\c&H{b:02X}{g:02X}{r:02X}&}}{}{{\\r}}: {}",
+        escape_ass(author_name(message)),
+        escape_ass(&message.message.replace(['\r', '\n'], " "))
+    )
+}
+
+/// The user's name color, or the one Twitch would assign them when they never picked one
+fn name_color(message: &ChatMessage) -> (u8, u8, u8) {
+    let author = message.author.as_ref();
+    let color = author
+        .and_then(|a| a.color.as_deref())
+        .filter(|c| c.len() == 7 && c.starts_with('#'))
+        .unwrap_or_else(|| {
+            let seed = author.map_or(0, |a| a.login.bytes().map(usize::from).sum());
+            DEFAULT_NAME_COLORS[seed % DEFAULT_NAME_COLORS.len()]
+        });
+
+    let channel =
+        |i: usize| u8::from_str_radix(color.get(i..i + 2).unwrap_or("FF"), 16).unwrap_or(0xFF);
+    (channel(1), channel(3), channel(5))
+}
+
+fn escape_vtt(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// ASS has no escaping. Braces would start override tags and backslashes line breaks, so they
+/// are swapped for look-alikes
+fn escape_ass(text: &str) -> String {
+    text.replace('\\', "\\\u{200B}")
+        .replace('{', "\u{FF5B}")
+        .replace('}', "\u{FF5D}")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::twitch::chat::ChatAuthor;
+
+    const OPTIONS: ChatSubtitleOptions = ChatSubtitleOptions {
+        duration: 5,
+        lines: 2,
+    };
+
+    fn message(id: &str, offset: u64, color: Option<&str>, text: &str) -> ChatMessage {
+        ChatMessage {
+            id: id.to_string(),
+            offset,
+            created_at: Utc::now(),
+            author: Some(ChatAuthor {
+                id: "1".to_string(),
+                login: id.to_lowercase(),
+                display_name: id.to_string(),
+                color: color.map(ToString::to_string),
+            }),
+            badges: Vec::new(),
+            emotes: Vec::new(),
+            message: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn frames_keep_the_latest_lines_until_they_expire() {
+        let messages = [
+            message("A", 0, None, "a"),
+            message("B", 1, None, "b"),
+            message("C", 1, None, "c"),
+            message("D", 20, None, "d"),
+        ];
+
+        let frames = frames(&messages, OPTIONS)
+            .iter()
+            .map(|f| {
+                let ids = f.messages.iter().map(|m| m.id.as_str()).collect::<Vec<_>>();
+                (f.start, f.end, ids)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            frames,
+            [
+                (0, 1, vec!["A"]),
+                // Only the latest 2 lines are shown
+                (1, 5, vec!["B", "C"]),
+                (5, 6, vec!["B", "C"]),
+                (20, 25, vec!["D"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_srt() {
+        let messages = [
+            message("A", 3661, None, "hello\nworld"),
+            message("B", 3662, None, "hi"),
+        ];
+
+        assert_eq!(
+            render_chat(&messages, SubtitleFormat::Srt, OPTIONS),
+            "1\n01:01:01,000 --> 01:01:02,000\nA: hello world\n\n\
+             2\n01:01:02,000 --> 01:01:06,000\nA: hello world\nB: hi\n\n\
+             3\n01:01:06,000 --> 01:01:07,000\nB: hi\n\n"
+        );
+    }
+
+    #[test]
+    fn renders_escaped_vtt() {
+        let mut deleted = message("A", 0, None, "<3 & more");
+        deleted.author = None;
+
+        assert_eq!(
+            render_chat(&[deleted], SubtitleFormat::WebVtt, OPTIONS),
+            "WEBVTT\n\n00:00:00.000 --> 00:00:05.000 position:2% align:start size:35%\n\
+             (deleted user): &lt;3 &amp; more\n\n"
+        );
+    }
+
+    #[test]
+    fn renders_colored_ass() {
+        let messages = [message("A", 0, Some("#FF8000"), "{\\b1}bold")];
+
+        let ass = render_chat(&messages, SubtitleFormat::Ass, OPTIONS);
+        assert!(ass.starts_with(ASS_HEADER));
+        assert_eq!(
+            &ass[ASS_HEADER.len()..],
+            "Dialogue: 0,0:00:00.00,0:00:05.00,Chat,,0,0,0,,\
+             {\\b1\\c&H0080FF&}A{\\r}: \u{FF5B}\\\u{200B}b1\u{FF5D}bold\n"
+        );
+    }
+
+    #[test]
+    fn picks_a_default_name_color() {
+        let messages = [
+            message("A", 0, None, "a"),
+            message("B", 0, Some("red"), "b"),
+        ];
+
+        for message in &messages {
+            let (r, g, b) = name_color(message);
+            let color = format!("#{r:02X}{g:02X}{b:02X}");
+            assert!(DEFAULT_NAME_COLORS.contains(&color.as_str()), "{color}");
+        }
+    }
+}
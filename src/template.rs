@@ -0,0 +1,521 @@
+use std::{collections::HashMap, fmt::Write as _, str::FromStr, time::Duration};
+
+use anyhow::{Result, bail, ensure};
+use chrono::{DateTime, Utc, format::StrftimeItems};
+use serde::Deserialize;
+
+use crate::{
+    twitch::{
+        cdn::MutedRange,
+        structs::{Chapter, VideoInfo},
+    },
+    util::format_timestamp,
+    youtube::MAX_TITLE_CHARS,
+};
+
+pub const DEFAULT_TITLE_TEMPLATE: &str =
+    "[{date:%Y-%m-%d}] {title}{?part} (Part {part}/{parts}){/part}";
+pub const DEFAULT_DESCRIPTION_TEMPLATE: &str = "Original stream title: {title}
+Streamed {date} @ https://twitch.tv/{channel_login}{?game}
+Game: {game}{/game}{?chapters}
+
+Chapters:
+{chapters}{/chapters}{?muted}
+
+Sections muted by Twitch:
+{muted}{/muted}";
+
+/// Every placeholder a template can use
+const PLACEHOLDERS: &[&str] = &[
+    "title",
+    "channel",
+    "channel_login",
+    "game",
+    "date",
+    "duration",
+    "vod_id",
+    "vod_url",
+    "part",
+    "parts",
+    "chapters",
+    "muted",
+];
+
+/// A title / description template
+///
+/// - `{name}` is replaced with a value, e.g. `{title}`, `{channel}` or `{game}`
+/// - `{date:%Y-%m-%d}` formats the stream date using `strftime` syntax
+/// - `{?name}...{/name}` is only kept when `name` has a value, `{!name}...{/name}` only when it
+///   doesn't
+/// - `{{` and `}}` are literal braces
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Literal(String),
+    Placeholder {
+        name: String,
+        format: Option<String>,
+    },
+    Section {
+        name: String,
+        /// Kept when the value is missing instead
+        inverted: bool,
+        nodes: Vec<Self>,
+    },
+}
+
+/// Values of a single archived video filled into templates
+#[derive(Debug, Clone)]
+pub struct TemplateValues {
+    pub title: String,
+    pub channel: String,
+    pub channel_login: String,
+    pub game: String,
+    pub date: DateTime<Utc>,
+    pub duration: Duration,
+    pub vod_id: String,
+    pub vod_url: String,
+    /// `(part, parts)` when the video is split into multiple uploads
+    pub part: Option<(usize, usize)>,
+    /// Timed from the start of the uploaded video
+    pub chapters: Vec<Chapter>,
+    pub muted_ranges: Vec<MutedRange>,
+}
+
+impl TemplateValues {
+    #[must_use]
+    pub fn new(video_info: &VideoInfo, chapters: &[Chapter], muted_ranges: &[MutedRange]) -> Self {
+        Self {
+            title: video_info.title.clone(),
+            channel: video_info.owner.display_name.clone(),
+            channel_login: video_info.owner.login.clone(),
+            game: video_info
+                .game
+                .as_ref()
+                .map(|g| g.display_name.clone())
+                .unwrap_or_default(),
+            date: video_info.created_at,
+            duration: Duration::from_secs(video_info.length_seconds),
+            vod_id: video_info.id.clone(),
+            vod_url: video_info.url(),
+            part: None,
+            chapters: chapters.to_vec(),
+            muted_ranges: muted_ranges.to_vec(),
+        }
+    }
+
+    fn get(&self, name: &str, format: Option<&str>) -> String {
+        match name {
+            "title" => self.title.clone(),
+            "channel" => self.channel.clone(),
+            "channel_login" => self.channel_login.clone(),
+            "game" => self.game.clone(),
+            "date" => format.map_or_else(
+                || self.date.to_string(),
+                |f| self.date.format(f).to_string(),
+            ),
+            "duration" => format_timestamp(self.duration),
+            "vod_id" => self.vod_id.clone(),
+            "vod_url" => self.vod_url.clone(),
+            "part" => self.part.map(|(p, _)| p.to_string()).unwrap_or_default(),
+            "parts" => self.part.map(|(_, p)| p.to_string()).unwrap_or_default(),
+            // A single chapter would only repeat the game
+            "chapters" if self.chapters.len() > 1 => self
+                .chapters
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            "muted" => self
+                .muted_ranges
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => String::new(),
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut stack: Vec<(String, bool, Vec<Node>)> = Vec::new();
+        let mut nodes = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => bail!("Unmatched `}}` in template, use `}}}}` for a literal brace"),
+                '{' => {
+                    let mut tag = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => tag.push(c),
+                            None => bail!(
+                                "Unclosed `{{{tag}` in template, use `{{{{` for a literal brace"
+                            ),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        nodes.push(Node::Literal(std::mem::take(&mut literal)));
+                    }
+
+                    if let Some(name) = tag.strip_prefix('/') {
+                        let Some((open_name, inverted, parent)) = stack.pop() else {
+                            bail!("`{{/{name}}}` closes a section which was never opened");
+                        };
+                        ensure!(
+                            open_name == name,
+                            "`{{/{name}}}` closes a section opened as `{open_name}`"
+                        );
+                        let section = Node::Section {
+                            name: open_name,
+                            inverted,
+                            nodes: std::mem::replace(&mut nodes, parent),
+                        };
+                        nodes.push(section);
+                    } else if let Some(name) = tag.strip_prefix(['?', '!']) {
+                        validate_name(name)?;
+                        stack.push((
+                            name.to_string(),
+                            tag.starts_with('!'),
+                            std::mem::take(&mut nodes),
+                        ));
+                    } else {
+                        let (name, format) = match tag.split_once(':') {
+                            Some((name, format)) => (name, Some(format)),
+                            None => (tag.as_str(), None),
+                        };
+                        validate_name(name)?;
+                        if let Some(format) = format {
+                            ensure!(name == "date", "Only `{{date}}` can be formatted");
+                            ensure!(
+                                StrftimeItems::new(format).parse().is_ok(),
+                                "`{format}` is not a valid date format"
+                            );
+                        }
+
+                        nodes.push(Node::Placeholder {
+                            name: name.to_string(),
+                            format: format.map(ToString::to_string),
+                        });
+                    }
+                }
+                c => literal.push(c),
+            }
+        }
+
+        if let Some((name, ..)) = stack.pop() {
+            bail!("Section `{name}` is never closed with `{{/{name}}}`");
+        }
+        if !literal.is_empty() {
+            nodes.push(Node::Literal(literal));
+        }
+
+        Ok(Self { nodes })
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    ensure!(
+        PLACEHOLDERS.contains(&name),
+        "Unknown template placeholder `{name}`. Available placeholders: {}",
+        PLACEHOLDERS.join(", ")
+    );
+    Ok(())
+}
+
+impl Template {
+    /// Fills in the template, without any length limit
+    #[must_use]
+    pub fn render(&self, values: &TemplateValues) -> String {
+        let mut out = String::new();
+        render_nodes(&self.nodes, values, &mut out);
+        sanitize(&out)
+    }
+
+    /// Renders a YouTube title, see [`Template::render_limited`]
+    ///
+    /// Parts of a split video always get a ` (Part n/N)` suffix when the template has no `{part}`,
+    /// so that their titles are not all the same
+    #[must_use]
+    pub fn render_title(&self, values: &TemplateValues) -> String {
+        let Some((part, parts)) = values.part.filter(|(_, parts)| *parts > 1) else {
+            return self.render_limited(values, MAX_TITLE_CHARS);
+        };
+        if uses_placeholder(&self.nodes, "part") {
+            return self.render_limited(values, MAX_TITLE_CHARS);
+        }
+
+        let suffix = format!(" (Part {part}/{parts})");
+        let title = self.render_limited(values, MAX_TITLE_CHARS - suffix.chars().count());
+        format!("{title}{suffix}")
+    }
+
+    /// Renders the template within `max_chars` characters, shortening the stream title so that
+    /// everything else in the template (e.g. the date and part number) still fits
+    #[must_use]
+    pub fn render_limited(&self, values: &TemplateValues, max_chars: usize) -> String {
+        let title = self.render(values);
+        let length = title.chars().count();
+        if length <= max_chars {
+            return title;
+        }
+
+        let title_length = values.title.chars().count();
+        let overflow = length - max_chars;
+        if overflow < title_length {
+            let mut values = values.clone();
+            values.title = truncate_chars(&values.title, title_length - overflow);
+
+            let title = self.render(&values);
+            if title.chars().count() <= max_chars {
+                return title;
+            }
+        }
+
+        truncate_chars(&title, max_chars)
+    }
+}
+
+fn render_nodes(nodes: &[Node], values: &TemplateValues, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Literal(text) => out.push_str(text),
+            Node::Placeholder { name, format } => {
+                let _ = write!(out, "{}", values.get(name, format.as_deref()));
+            }
+            Node::Section {
+                name,
+                inverted,
+                nodes,
+            } => {
+                if values.get(name, None).trim().is_empty() == *inverted {
+                    render_nodes(nodes, values, out);
+                }
+            }
+        }
+    }
+}
+
+/// Whether any of `nodes` is a `{name}` placeholder, including inside of sections
+fn uses_placeholder(nodes: &[Node], placeholder: &str) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Literal(_) => false,
+        Node::Placeholder { name, .. } => name == placeholder,
+        Node::Section { nodes, .. } => uses_placeholder(nodes, placeholder),
+    })
+}
+
+/// YouTube rejects titles and descriptions containing `<` or `>`
+fn sanitize(text: &str) -> String {
+    text.replace(['<', '>'], "")
+}
+
+/// Truncates a string to `max_chars` characters, ending it with `…` if it was truncated
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+
+    let mut truncated = text
+        .chars()
+        .take(max_chars.saturating_sub(1))
+        .collect::<String>()
+        .trim_end()
+        .to_string();
+    truncated.push('…');
+    truncated
+}
+
+/// Title and description templates, set with command line flags or the `[templates]` table of
+/// the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Templates {
+    pub title: Option<Template>,
+    pub description: Option<Template>,
+    /// Templates of specific channels by their login name, e.g. `[templates.channels.xqc]`
+    pub channels: HashMap<String, ChannelTemplates>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelTemplates {
+    pub title: Option<Template>,
+    pub description: Option<Template>,
+}
+
+impl Templates {
+    /// Picks the templates of a channel, falling back to the global then default templates
+    ///
+    /// # Panics
+    /// Will panic if the default templates are invalid
+    #[must_use]
+    pub fn for_channel(&self, channel_login: &str) -> (Template, Template) {
+        let channel = self
+            .channels
+            .iter()
+            .find(|(login, _)| login.eq_ignore_ascii_case(channel_login))
+            .map(|(_, t)| t);
+
+        let title = channel
+            .and_then(|c| c.title.clone())
+            .or_else(|| self.title.clone())
+            .unwrap_or_else(|| DEFAULT_TITLE_TEMPLATE.parse().unwrap());
+        let description = channel
+            .and_then(|c| c.description.clone())
+            .or_else(|| self.description.clone())
+            .unwrap_or_else(|| DEFAULT_DESCRIPTION_TEMPLATE.parse().unwrap());
+
+        (title, description)
+    }
+
+    /// Fills every template missing from `self` with the one from `other`
+    #[must_use]
+    pub fn or(mut self, other: Self) -> Self {
+        for (login, templates) in other.channels {
+            self.channels.entry(login).or_insert(templates);
+        }
+
+        Self {
+            title: self.title.or(other.title),
+            description: self.description.or(other.description),
+            channels: self.channels,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> TemplateValues {
+        TemplateValues {
+            title: "Speedrun".to_string(),
+            channel: "Streamer".to_string(),
+            channel_login: "streamer".to_string(),
+            game: String::new(),
+            date: DateTime::parse_from_rfc3339("2025-01-31T12:00:00Z")
+                .unwrap()
+                .to_utc(),
+            duration: Duration::from_hours(1),
+            vod_id: "123".to_string(),
+            vod_url: "https://www.twitch.tv/videos/123".to_string(),
+            part: None,
+            chapters: Vec::new(),
+            muted_ranges: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parses_default_templates() {
+        assert!(DEFAULT_TITLE_TEMPLATE.parse::<Template>().is_ok());
+        assert!(DEFAULT_DESCRIPTION_TEMPLATE.parse::<Template>().is_ok());
+    }
+
+    #[test]
+    fn renders_placeholders_and_braces() {
+        let template = "{{{channel}}} {date:%Y-%m-%d} {vod_url}"
+            .parse::<Template>()
+            .unwrap();
+        assert_eq!(
+            template.render(&values()),
+            "{Streamer} 2025-01-31 https://www.twitch.tv/videos/123"
+        );
+    }
+
+    #[test]
+    fn renders_sections_by_value() {
+        let template = "{title}{?game} playing {game}{/game}{!game} chatting{/game}"
+            .parse::<Template>()
+            .unwrap();
+        assert_eq!(template.render(&values()), "Speedrun chatting");
+
+        let mut values = values();
+        values.game = "Celeste".to_string();
+        assert_eq!(template.render(&values), "Speedrun playing Celeste");
+    }
+
+    #[test]
+    fn rejects_malformed_templates() {
+        for template in [
+            "{?game}unclosed",
+            "{title",
+            "title}",
+            "{/game}",
+            "{?game}{/title}",
+            "{unknown}",
+            "{title:%Y}",
+        ] {
+            assert!(
+                template.parse::<Template>().is_err(),
+                "`{template}` should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn numbers_parts_in_titles() {
+        let mut values = values();
+        values.part = Some((2, 3));
+
+        let default = DEFAULT_TITLE_TEMPLATE.parse::<Template>().unwrap();
+        assert_eq!(
+            default.render_title(&values),
+            "[2025-01-31] Speedrun (Part 2/3)"
+        );
+
+        let custom = "{channel}: {title}".parse::<Template>().unwrap();
+        assert_eq!(
+            custom.render_title(&values),
+            "Streamer: Speedrun (Part 2/3)"
+        );
+
+        let numbered = "{title} #{part}".parse::<Template>().unwrap();
+        assert_eq!(numbered.render_title(&values), "Speedrun #2");
+
+        // Only a section doesn't tell the parts apart
+        let marked = "{title}{?part} (split){/part}".parse::<Template>().unwrap();
+        assert_eq!(marked.render_title(&values), "Speedrun (split) (Part 2/3)");
+
+        values.part = Some((1, 1));
+        assert_eq!(custom.render_title(&values), "Streamer: Speedrun");
+    }
+
+    #[test]
+    fn keeps_part_numbers_of_long_titles() {
+        let mut values = values();
+        values.title = "a".repeat(200);
+        values.part = Some((2, 3));
+
+        let rendered = "{title}".parse::<Template>().unwrap().render_title(&values);
+        assert_eq!(rendered.chars().count(), MAX_TITLE_CHARS);
+        assert!(rendered.ends_with("a… (Part 2/3)"));
+    }
+}
@@ -1,17 +1,26 @@
+use std::time::Duration;
+
 use anyhow::{Context, Result, bail, ensure};
+use serde::Deserialize;
 use serde_json::{Value, json};
 use tracing::instrument;
 
-use crate::twitch::{AUTHENTICATED_PUBLIC_HTTP_CLIENT, structs::VideoInfo};
+use crate::twitch::{
+    AUTHENTICATED_PUBLIC_HTTP_CLIENT, ChannelRef,
+    structs::{BroadcastType, Chapter, ClipInfo, Game, LiveStream, VideoInfo},
+};
 
-/// Returns channel's past broadcast
+/// Returns channel's latest videos of the given type, e.g. past broadcasts
 ///
 /// Returns `None` if channel is not found
 ///
 /// # Errors
 /// Errors when there's a network error or when JSON response is invalid
 #[instrument]
-pub async fn list_channel_videos(channel_id: u64) -> Result<Option<Vec<VideoInfo>>> {
+pub async fn list_channel_videos(
+    channel_id: u64,
+    broadcast_type: BroadcastType,
+) -> Result<Option<Vec<VideoInfo>>> {
     let req = AUTHENTICATED_PUBLIC_HTTP_CLIENT
         .post("https://gql.twitch.tv/gql")
         .json(&json!({
@@ -27,15 +36,18 @@ pub async fn list_channel_videos(channel_id: u64) -> Result<Option<Vec<VideoInfo
                                 lengthSeconds
                                 viewCount
                                 status
+                                broadcastType
+                                previewThumbnailURL(width: 1280, height: 720)
                                 game { displayName }
-                                owner { login, displayName }
+                                owner { login, displayName, profileImageURL(width: 600), bannerImageURL }
                             }
                         }
                     }
                 }
             }",
             "variables": {
-                "id": channel_id.to_string()
+                "id": channel_id.to_string(),
+                "type": broadcast_type,
             }
         }))
         .send()
@@ -57,14 +69,150 @@ pub async fn list_channel_videos(channel_id: u64) -> Result<Option<Vec<VideoInfo
     let mut edges = user["videos"]["edges"].take();
     let videos = edges
         .as_array_mut()
-        .unwrap()
+        .context("Channel videos are missing from the response")?
         .iter_mut()
-        .map(|e| serde_json::from_value(e["node"].take()).unwrap())
-        .collect::<Vec<VideoInfo>>();
+        .map(|e| serde_json::from_value(e["node"].take()).context("Parsing channel video"))
+        .collect::<Result<Vec<VideoInfo>>>()?;
 
     Ok(Some(videos))
 }
 
+/// Returns the stream a channel is currently live with
+///
+/// Returns `None` if channel is offline or not found
+///
+/// # Errors
+/// Errors when there's a network error or when JSON response is invalid
+#[instrument]
+pub async fn get_live_stream(channel_id: u64) -> Result<Option<LiveStream>> {
+    let req = AUTHENTICATED_PUBLIC_HTTP_CLIENT
+        .post("https://gql.twitch.tv/gql")
+        .json(&json!({
+            "query": "query LiveStream($id: ID) {
+                user(id: $id) {
+                    stream { id, createdAt, archiveVideo { id } }
+                }
+            }",
+            "variables": {
+                "id": channel_id.to_string()
+            }
+        }))
+        .send()
+        .await
+        .context("Fetching live stream")?;
+
+    ensure!(req.status().is_success(), "Failed to get live stream");
+
+    let mut json = req
+        .json::<Value>()
+        .await
+        .context("Parsing live stream request")?;
+
+    serde_json::from_value::<Option<LiveStream>>(json["data"]["user"]["stream"].take())
+        .context("Parsing live stream data")
+}
+
+/// A page of a channel's videos of every type, newest first
+#[derive(Debug, Clone)]
+pub struct ChannelVideoPage {
+    pub videos: Vec<VideoInfo>,
+    /// Cursor of the next page, `None` on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// Returns a page of a channel's videos, starting after `cursor`
+///
+/// Returns `None` if channel is not found
+///
+/// # Errors
+/// Errors when there's a network error or when JSON response is invalid
+#[instrument]
+pub async fn get_channel_videos_page(
+    channel: &ChannelRef,
+    cursor: Option<&str>,
+) -> Result<Option<ChannelVideoPage>> {
+    let (id, login) = match channel {
+        ChannelRef::Id(id) => (Some(id.to_string()), None),
+        ChannelRef::Login(login) => (None, Some(login.as_str())),
+    };
+
+    let req = AUTHENTICATED_PUBLIC_HTTP_CLIENT
+        .post("https://gql.twitch.tv/gql")
+        .json(&json!({
+            "query": "query ChannelVideos($id: ID, $login: String, $after: Cursor) {
+                user(id: $id, login: $login) {
+                    videos(first: 100, after: $after, sort: TIME) {
+                        edges {
+                            cursor
+                            node {
+                                id
+                                title
+                                description
+                                createdAt
+                                lengthSeconds
+                                viewCount
+                                status
+                                broadcastType
+                                previewThumbnailURL(width: 1280, height: 720)
+                                game { displayName }
+                                owner { login, displayName, profileImageURL(width: 600), bannerImageURL }
+                            }
+                        }
+                        pageInfo { hasNextPage }
+                    }
+                }
+            }",
+            "variables": {
+                "id": id,
+                "login": login,
+                "after": cursor,
+            }
+        }))
+        .send()
+        .await
+        .context("Fetching channel videos")?;
+
+    ensure!(req.status().is_success(), "Failed to get channel videos");
+
+    let mut json = req
+        .json::<Value>()
+        .await
+        .context("Parsing channel videos request")?;
+
+    let mut videos = json["data"]["user"]["videos"].take();
+    if videos.is_null() {
+        return Ok(None);
+    }
+
+    let edges = videos["edges"]
+        .as_array_mut()
+        .map(std::mem::take)
+        .unwrap_or_default();
+    let next_cursor = if videos["pageInfo"]["hasNextPage"].as_bool().unwrap_or(false) {
+        edges
+            .last()
+            .and_then(|e| e["cursor"].as_str())
+            .map(ToString::to_string)
+    } else {
+        None
+    };
+    let videos = edges
+        .into_iter()
+        .map(|mut e| serde_json::from_value(e["node"].take()).context("Parsing channel video"))
+        .collect::<Result<Vec<VideoInfo>>>()?;
+
+    Ok(Some(ChannelVideoPage {
+        videos,
+        next_cursor,
+    }))
+}
+
+/// Fetches a video by its ID
+///
+/// Returns `None` if the video is not found
+///
+/// # Errors
+/// Errors when there's a network error or when JSON response is invalid
 #[instrument]
 pub async fn get_video_info(video_id: u64) -> Result<Option<VideoInfo>> {
     let req = AUTHENTICATED_PUBLIC_HTTP_CLIENT
@@ -79,8 +227,10 @@ pub async fn get_video_info(video_id: u64) -> Result<Option<VideoInfo>> {
                     lengthSeconds
                     viewCount
                     status
+                    broadcastType
+                    previewThumbnailURL(width: 1280, height: 720)
                     game { displayName }
-                    owner { login, displayName }
+                    owner { login, displayName, profileImageURL(width: 600), bannerImageURL }
                 }
             }",
             "variables": {
@@ -100,11 +250,134 @@ pub async fn get_video_info(video_id: u64) -> Result<Option<VideoInfo>> {
         .context("Parsing VOD info data")
 }
 
+/// Returns `None` if the clip is not found
+///
+/// # Errors
+/// Errors when there's a network error or when JSON response is invalid
+#[instrument]
+pub async fn get_clip_info(slug: &str) -> Result<Option<ClipInfo>> {
+    let req = AUTHENTICATED_PUBLIC_HTTP_CLIENT
+        .post("https://gql.twitch.tv/gql")
+        .json(&json!({
+            "query": "query ClipInfo($slug: ID!) {
+                clip(slug: $slug) {
+                    id
+                    slug
+                    title
+                    createdAt
+                    durationSeconds
+                    viewCount
+                    thumbnailURL
+                    game { displayName }
+                    broadcaster { login, displayName, profileImageURL(width: 600), bannerImageURL }
+                    videoQualities { quality, frameRate, sourceURL }
+                }
+            }",
+            "variables": {
+                "slug": slug
+            }
+        }))
+        .send()
+        .await
+        .context("Fetching clip info")?;
+
+    ensure!(req.status().is_success(), "Failed to get clip info");
+
+    let mut json = req
+        .json::<Value>()
+        .await
+        .context("Parsing clip info request")?;
+
+    serde_json::from_value::<Option<ClipInfo>>(json["data"]["clip"].take())
+        .context("Parsing clip info data")
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Moment {
+    description: String,
+    position_milliseconds: u64,
+    duration_milliseconds: u64,
+    details: Option<MomentDetails>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MomentDetails {
+    game: Option<Game>,
+}
+
+/// Returns the chapters of a video, one for every game / category change
+///
+/// Videos which stayed on a single game have no chapters
+///
+/// # Errors
+/// Errors when there's a network error or when JSON response is invalid
+#[instrument]
+pub async fn get_video_chapters(video_id: u64) -> Result<Vec<Chapter>> {
+    let req = AUTHENTICATED_PUBLIC_HTTP_CLIENT
+        .post("https://gql.twitch.tv/gql")
+        .json(&json!({
+            "query": "query VideoChapters($id: ID) {
+                video(id: $id) {
+                    moments(first: 100, momentRequestType: VIDEO_CHAPTER_MARKERS) {
+                        edges {
+                            node {
+                                description
+                                positionMilliseconds
+                                durationMilliseconds
+                                details {
+                                    ... on GameChangeMomentDetails { game { displayName } }
+                                }
+                            }
+                        }
+                    }
+                }
+            }",
+            "variables": {
+                "id": video_id.to_string()
+            }
+        }))
+        .send()
+        .await
+        .context("Fetching VOD chapters")?;
+
+    ensure!(req.status().is_success(), "Failed to get VOD chapters");
+
+    let mut json = req
+        .json::<Value>()
+        .await
+        .context("Parsing VOD chapters request")?;
+
+    let edges = json["data"]["video"]["moments"]["edges"].take();
+    let Some(edges) = edges.as_array() else {
+        return Ok(Vec::new());
+    };
+
+    edges
+        .iter()
+        .map(|e| {
+            let moment = Moment::deserialize(&e["node"]).context("Parsing VOD chapter")?;
+            let start = Duration::from_millis(moment.position_milliseconds);
+            Ok(Chapter {
+                start,
+                end: start + Duration::from_millis(moment.duration_milliseconds),
+                title: moment
+                    .details
+                    .and_then(|d| d.game)
+                    .map_or(moment.description, |g| g.display_name),
+            })
+        })
+        .collect()
+}
+
 //
 
 /// Fetches the access tokens used to access a VOD's m3u8 master playlist file
 ///
 /// Returns: `(token_value, token_signature)`
+///
+/// # Errors
+/// Errors when there's a network error or when JSON response is invalid
 #[instrument(skip(oauth_token))]
 pub async fn get_video_cdn_tokens(
     video_id: u64,
@@ -147,3 +420,42 @@ pub async fn get_video_cdn_tokens(
         token["signature"].as_str().unwrap().to_string(),
     ))
 }
+
+/// Fetches the access tokens used to download a clip's MP4 files
+///
+/// Returns: `(token_value, token_signature)`
+///
+/// # Errors
+/// Errors when there's a network error or when the clip does not exist
+#[instrument]
+pub async fn get_clip_cdn_tokens(slug: &str) -> Result<(String, String)> {
+    let req = AUTHENTICATED_PUBLIC_HTTP_CLIENT
+        .post("https://gql.twitch.tv/gql")
+        .json(&json!({
+            "query": "query GetClipAccessToken($slug: ID!) {
+                clip(slug: $slug) {
+                    playbackAccessToken(
+                        params: {platform: \"web\", playerBackend: \"mediaplayer\", playerType: \"site\"}
+                    ) {
+                        value
+                        signature
+                    }
+                }
+            }",
+            "variables": {
+                "slug": slug,
+            }
+        }))
+        .send()
+        .await
+        .context("Fetching clip tokens")?;
+
+    let res = req.json::<Value>().await.context("Parsing clip tokens")?;
+    let token = &res["data"]["clip"]["playbackAccessToken"];
+    let (Some(value), Some(signature)) = (token["value"].as_str(), token["signature"].as_str())
+    else {
+        bail!("`playbackAccessToken` does not exist. The clip might have been deleted!")
+    };
+
+    Ok((value.to_string(), signature.to_string()))
+}
@@ -1,12 +1,22 @@
-use anyhow::{Context, Result};
-use m3u8_rs::{MasterPlaylist, MediaPlaylist};
-use tracing::{info, instrument};
+use std::{fmt::Display, str::FromStr, time::Duration};
 
-use crate::twitch::AUTHENTICATED_PUBLIC_HTTP_CLIENT;
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use m3u8_rs::{MasterPlaylist, MediaPlaylist, MediaSegment, VariantStream};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    twitch::{AUTHENTICATED_PUBLIC_HTTP_CLIENT, structs::ClipQuality},
+    util::format_timestamp,
+};
 
 /// Fetches the stream's master .m3u8 file
 ///
 /// You should call `twitch::api::get_video_cdn_tokens` to get the signature values
+///
+/// # Errors
+/// Errors when there's a network error, when the VOD is not available (e.g. subscriber-only or
+/// deleted) or when the playlist is invalid
 #[instrument(skip(token_value, token_signature))]
 pub async fn get_video_playlist_file(
     video_id: u64,
@@ -27,19 +37,20 @@ pub async fn get_video_playlist_file(
         ])
         .send()
         .await
+        .and_then(reqwest::Response::error_for_status)
         .context("Fetching VOD playlist file")?;
 
     let body = req.text().await.context("Decoding VOD playlist file")?;
 
-    let (_, playlist) = m3u8_rs::parse_master_playlist(body.as_bytes()).unwrap();
+    // The parser error borrows the body, so it cannot be returned as is
+    let (_, playlist) = m3u8_rs::parse_master_playlist(body.as_bytes())
+        .map_err(|_| anyhow!("VOD playlist file is not a valid master playlist"))?;
     info!(
         "Available VOD quality: {}",
         playlist
             .variants
             .iter()
-            .map(|v| v
-                .resolution
-                .map_or("Unknown resolution".to_string(), |v| v.to_string()))
+            .map(describe_variant)
             .collect::<Vec<String>>()
             .join(", ")
     );
@@ -48,16 +59,611 @@ pub async fn get_video_playlist_file(
 }
 
 /// Fetches a stream variant media
+///
+/// Segments with audio muted by Twitch are listed out in the logs,
+/// see [`muted_ranges`] to get them.
+///
+/// # Errors
+/// Errors when there's a network error, when the media is not available or when the playlist is
+/// invalid
 #[instrument(skip(uri))]
 pub async fn get_video_media(uri: impl reqwest::IntoUrl) -> Result<MediaPlaylist> {
     let req = AUTHENTICATED_PUBLIC_HTTP_CLIENT
         .get(uri)
         .send()
         .await
+        .and_then(reqwest::Response::error_for_status)
         .context("Fetching VOD media file")?;
     let body = req.text().await.context("Decoding VOD media file")?;
 
-    let (_, playlist) = m3u8_rs::parse_media_playlist(body.as_bytes()).unwrap();
+    let (_, playlist) = m3u8_rs::parse_media_playlist(body.as_bytes())
+        .map_err(|_| anyhow!("VOD media file is not a valid media playlist"))?;
+
+    let muted_ranges = muted_ranges(playlist.segments.iter().map(|s| (s, is_muted_segment(s))));
+    if !muted_ranges.is_empty() {
+        info!(
+            "VOD has {} muted section(s): {}",
+            muted_ranges.len(),
+            muted_ranges
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
 
     Ok(playlist)
 }
+
+/// A section of the VOD where Twitch has muted the audio, usually due to copyrighted music
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MutedRange {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+impl Display for MutedRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} - {}",
+            format_timestamp(self.start),
+            format_timestamp(self.end)
+        )
+    }
+}
+
+/// URI of the initialization segment (`EXT-X-MAP`) of fMP4 variants (AV1 / HEVC), which has to be
+/// put in front of the media segments for them to be playable
+///
+/// MPEG-TS variants (H.264) have none
+#[must_use]
+pub fn init_segment_uri(segments: &[MediaSegment]) -> Option<&str> {
+    segments
+        .iter()
+        .find_map(|s| s.map.as_ref())
+        .map(|m| m.uri.as_str())
+}
+
+/// Checks whether Twitch has muted the audio of a segment, marked by a `-muted` suffix
+/// (e.g. `123-muted.ts`)
+#[must_use]
+pub fn is_muted_segment(segment: &MediaSegment) -> bool {
+    segment
+        .uri
+        .rsplit_once('.')
+        .map_or(segment.uri.as_str(), |(name, _)| name)
+        .ends_with("-muted")
+}
+
+/// URIs where the original audio of a muted segment might still be available, in order of
+/// likeliness
+#[must_use]
+pub fn unmuted_segment_uris(uri: &str) -> Vec<String> {
+    if !uri.contains("-muted") {
+        return Vec::new();
+    }
+
+    vec![uri.replace("-muted", "-unmuted"), uri.replace("-muted", "")]
+}
+
+/// URI of the muted copy of a segment, which Twitch sometimes replaces the original segment with
+/// after the VOD has been published (e.g. `123.ts` becoming `123-muted.ts`)
+///
+/// Returns `None` for segments that are already muted
+#[must_use]
+pub fn muted_segment_uri(uri: &str) -> Option<String> {
+    if uri.contains("-muted") {
+        return None;
+    }
+
+    Some(match uri.rsplit_once('.') {
+        Some((name, extension)) => format!("{name}-muted.{extension}"),
+        None => format!("{uri}-muted"),
+    })
+}
+
+/// Computes the time ranges of muted segments using their `#EXTINF` durations
+///
+/// Takes every segment of the media playlist in order, along with whether it is muted.
+/// Consecutive muted segments are merged into a single range.
+pub fn muted_ranges<'a>(
+    segments: impl IntoIterator<Item = (&'a MediaSegment, bool)>,
+) -> Vec<MutedRange> {
+    let mut ranges: Vec<MutedRange> = Vec::new();
+    let mut offset = Duration::ZERO;
+
+    for (segment, muted) in segments {
+        let duration = Duration::from_secs_f32(segment.duration.max(0.0));
+        if muted {
+            match ranges.last_mut() {
+                Some(last) if last.end == offset => last.end = offset + duration,
+                _ => ranges.push(MutedRange {
+                    start: offset,
+                    end: offset + duration,
+                }),
+            }
+        }
+        offset += duration;
+    }
+
+    ranges
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Av1,
+    H265,
+    H264,
+}
+
+impl Codec {
+    /// Codec preference used when none is specified
+    ///
+    /// H.264 (MPEG-TS) is the most compatible, AV1 / HEVC (fMP4) are handled badly by many
+    /// players and editors so they have to be opted into
+    pub const DEFAULT_PREFERENCE: [Self; 3] = [Self::H264, Self::H265, Self::Av1];
+
+    /// Detects the video codec out of a variant's `CODECS` attribute
+    #[must_use]
+    pub fn from_codecs_attribute(codecs: &str) -> Option<Self> {
+        codecs.split(',').find_map(|c| {
+            let c = c.trim();
+            if c.starts_with("av01") {
+                Some(Self::Av1)
+            } else if c.starts_with("hvc1") || c.starts_with("hev1") {
+                Some(Self::H265)
+            } else if c.starts_with("avc1") {
+                Some(Self::H264)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "av1" => Ok(Self::Av1),
+            "h265" | "hevc" => Ok(Self::H265),
+            "h264" | "avc" => Ok(Self::H264),
+            other => bail!("Unknown codec `{other}`"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Best,
+    Worst,
+    AudioOnly,
+    /// A specific resolution (by height) and optionally a framerate, e.g. `1080p60` / `720p`
+    Exact {
+        height: u64,
+        framerate: Option<u64>,
+    },
+}
+
+/// Which VOD variant to download, parsed from a comma separated list of a quality and codecs
+/// in order of preference (e.g. `best`, `720p`, `1080p60,av1,h264`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QualityPreference {
+    pub quality: Quality,
+    /// Only variants using one of these codecs are picked. When empty, H.264 variants are
+    /// picked unless the VOD has none
+    pub codecs: Vec<Codec>,
+}
+
+impl Default for QualityPreference {
+    fn default() -> Self {
+        Self {
+            quality: Quality::Best,
+            codecs: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for QualityPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut quality = None;
+        let mut codecs = Vec::new();
+
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let parsed_quality = match part.to_ascii_lowercase().as_str() {
+                "best" | "source" => Some(Quality::Best),
+                "worst" => Some(Quality::Worst),
+                "audio_only" | "audio" => Some(Quality::AudioOnly),
+                p => p.split_once('p').and_then(|(height, framerate)| {
+                    Some(Quality::Exact {
+                        height: height.parse().ok()?,
+                        framerate: if framerate.is_empty() {
+                            None
+                        } else {
+                            Some(framerate.parse().ok()?)
+                        },
+                    })
+                }),
+            };
+
+            match parsed_quality {
+                Some(_) if quality.is_some() => bail!("Only one quality can be specified"),
+                Some(q) => quality = Some(q),
+                None => codecs.push(
+                    part.parse::<Codec>()
+                        .context(format!("`{part}` is not a valid quality or codec"))?,
+                ),
+            }
+        }
+
+        Ok(Self {
+            quality: quality.unwrap_or(Quality::Best),
+            codecs,
+        })
+    }
+}
+
+impl QualityPreference {
+    /// Picks the variant matching this preference out of a VOD's master playlist
+    ///
+    /// Variants are ranked by resolution, framerate, codec preference and bandwidth.
+    /// An exact quality falls back to the best variant below it when it's not available.
+    ///
+    /// # Errors
+    /// Errors when no variant uses any of the preferred codecs or when audio only is requested
+    /// but not available
+    ///
+    /// AV1 / HEVC variants are only picked when the codecs are specified or when the VOD has no
+    /// H.264 variant
+    pub fn select<'a>(&self, playlist: &'a MasterPlaylist) -> Result<&'a VariantStream> {
+        if self.quality == Quality::AudioOnly {
+            return playlist
+                .variants
+                .iter()
+                .find(|v| is_audio_only(v))
+                .context("VOD does not have an audio only variant");
+        }
+
+        let codec_preference = if self.codecs.is_empty() {
+            Codec::DEFAULT_PREFERENCE.as_slice()
+        } else {
+            self.codecs.as_slice()
+        };
+        let rank = |v: &VariantStream| {
+            let codec = v.codecs.as_deref().and_then(Codec::from_codecs_attribute);
+            let codec_rank = codec
+                .and_then(|c| codec_preference.iter().position(|p| *p == c))
+                .map_or(0, |p| codec_preference.len() - p);
+            (
+                v.resolution.map_or(0, |r| r.height),
+                variant_framerate(v),
+                codec_rank,
+                v.bandwidth,
+            )
+        };
+
+        let uses_codec = |v: &VariantStream, codecs: &[Codec]| {
+            v.codecs
+                .as_deref()
+                .and_then(Codec::from_codecs_attribute)
+                .is_some_and(|c| codecs.contains(&c))
+        };
+        let videos = playlist.variants.iter().filter(|v| !is_audio_only(v));
+        let candidates = if self.codecs.is_empty() {
+            let h264 = videos
+                .clone()
+                .filter(|v| uses_codec(v, &[Codec::H264]))
+                .collect::<Vec<_>>();
+            if h264.is_empty() {
+                videos.collect()
+            } else {
+                h264
+            }
+        } else {
+            videos
+                .filter(|v| uses_codec(v, &self.codecs))
+                .collect::<Vec<_>>()
+        };
+        ensure!(
+            !candidates.is_empty(),
+            "VOD does not have any variant with the preferred codecs"
+        );
+
+        let selected = match self.quality {
+            Quality::Worst => candidates.into_iter().min_by_key(|v| rank(v)),
+            Quality::Exact { height, framerate } => {
+                let exact = candidates
+                    .iter()
+                    .filter(|v| v.resolution.is_some_and(|r| r.height == height))
+                    .filter(|v| framerate.is_none_or(|f| variant_framerate(v) == f))
+                    .max_by_key(|v| rank(v));
+
+                if exact.is_some() {
+                    exact.copied()
+                } else {
+                    warn!(
+                        "Quality {height}p{} is not available, falling back to the next best quality",
+                        framerate.map(|f| f.to_string()).unwrap_or_default()
+                    );
+                    let lower = candidates
+                        .iter()
+                        .filter(|v| v.resolution.is_some_and(|r| r.height <= height))
+                        .max_by_key(|v| rank(v))
+                        .copied();
+                    lower.or_else(|| candidates.into_iter().min_by_key(|v| rank(v)))
+                }
+            }
+            Quality::Best | Quality::AudioOnly => candidates.into_iter().max_by_key(|v| rank(v)),
+        };
+
+        // SAFETY: Candidates are ensured to not be empty
+        Ok(selected.unwrap())
+    }
+}
+
+impl QualityPreference {
+    /// Picks the rendition of a clip matching this preference, the same way as [`Self::select`]
+    ///
+    /// Clips are always H.264 and have no audio only rendition
+    ///
+    /// # Errors
+    /// Errors when the clip has no renditions, when H.264 is not a preferred codec or when
+    /// audio only is requested
+    pub fn select_clip<'a>(&self, qualities: &'a [ClipQuality]) -> Result<&'a ClipQuality> {
+        ensure!(
+            self.quality != Quality::AudioOnly,
+            "Clips do not have an audio only variant"
+        );
+        ensure!(
+            self.codecs.is_empty() || self.codecs.contains(&Codec::H264),
+            "Clips are only available as H.264"
+        );
+
+        let rank = |q: &ClipQuality| (clip_height(q), clip_framerate(q));
+        let selected = match self.quality {
+            Quality::Worst => qualities.iter().min_by_key(|q| rank(q)),
+            Quality::Exact { height, framerate } => {
+                let exact = qualities
+                    .iter()
+                    .filter(|q| clip_height(q) == height)
+                    .filter(|q| framerate.is_none_or(|f| clip_framerate(q) == f))
+                    .max_by_key(|q| rank(q));
+
+                if exact.is_some() {
+                    exact
+                } else {
+                    warn!(
+                        "Quality {height}p{} is not available, falling back to the next best quality",
+                        framerate.map(|f| f.to_string()).unwrap_or_default()
+                    );
+                    let lower = qualities
+                        .iter()
+                        .filter(|q| clip_height(q) <= height)
+                        .max_by_key(|q| rank(q));
+                    lower.or_else(|| qualities.iter().min_by_key(|q| rank(q)))
+                }
+            }
+            Quality::Best | Quality::AudioOnly => qualities.iter().max_by_key(|q| rank(q)),
+        };
+
+        selected.context("Clip does not have any variant")
+    }
+}
+
+/// Describes a variant for logging, e.g. `1080p60 (1920x1080, avc1.64002A, 8534 kbps)`
+#[must_use]
+pub fn describe_variant(variant: &VariantStream) -> String {
+    format!(
+        "{} ({}, {}, {} kbps)",
+        variant.video.as_deref().unwrap_or("Unknown"),
+        variant
+            .resolution
+            .map_or_else(|| "No video".to_string(), |r| r.to_string()),
+        variant.codecs.as_deref().unwrap_or("Unknown codec"),
+        variant.bandwidth / 1000
+    )
+}
+
+fn is_audio_only(variant: &VariantStream) -> bool {
+    variant.video.as_deref() == Some("audio_only")
+        || (variant.resolution.is_none()
+            && variant
+                .codecs
+                .as_deref()
+                .and_then(Codec::from_codecs_attribute)
+                .is_none())
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn variant_framerate(variant: &VariantStream) -> u64 {
+    variant.frame_rate.map_or(0, |f| f.round() as u64)
+}
+
+fn clip_height(quality: &ClipQuality) -> u64 {
+    quality.quality.parse().unwrap_or(0)
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+const fn clip_framerate(quality: &ClipQuality) -> u64 {
+    quality.frame_rate.round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(height: u64, codecs: &str, bandwidth: u64) -> VariantStream {
+        VariantStream {
+            uri: format!("{height}-{codecs}.m3u8"),
+            bandwidth,
+            codecs: Some(codecs.to_string()),
+            resolution: Some(m3u8_rs::Resolution {
+                width: height * 16 / 9,
+                height,
+            }),
+            frame_rate: Some(60.0),
+            ..Default::default()
+        }
+    }
+
+    fn playlist() -> MasterPlaylist {
+        MasterPlaylist {
+            variants: vec![
+                variant(1440, "av01.0.12M.08,mp4a.40.2", 9_000_000),
+                variant(1080, "hvc1.1.2.L123.90,mp4a.40.2", 7_000_000),
+                variant(1080, "avc1.64002A,mp4a.40.2", 8_000_000),
+                variant(720, "avc1.4D401F,mp4a.40.2", 3_000_000),
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn segment(uri: &str, duration: f32) -> MediaSegment {
+        MediaSegment {
+            uri: uri.to_string(),
+            duration,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_quality_and_codecs() {
+        let preference = "1080p60, av1,H264".parse::<QualityPreference>().unwrap();
+        assert_eq!(
+            preference.quality,
+            Quality::Exact {
+                height: 1080,
+                framerate: Some(60)
+            }
+        );
+        assert_eq!(preference.codecs, vec![Codec::Av1, Codec::H264]);
+
+        let preference = "720p".parse::<QualityPreference>().unwrap();
+        assert_eq!(
+            preference.quality,
+            Quality::Exact {
+                height: 720,
+                framerate: None
+            }
+        );
+        assert!(preference.codecs.is_empty());
+    }
+
+    #[test]
+    fn parses_quality_aliases() {
+        assert_eq!(
+            "source".parse::<QualityPreference>().unwrap().quality,
+            Quality::Best
+        );
+        assert_eq!(
+            "audio".parse::<QualityPreference>().unwrap().quality,
+            Quality::AudioOnly
+        );
+        // Only codecs keeps the default quality
+        let preference = "hevc".parse::<QualityPreference>().unwrap();
+        assert_eq!(preference.quality, Quality::Best);
+        assert_eq!(preference.codecs, vec![Codec::H265]);
+    }
+
+    #[test]
+    fn rejects_invalid_quality() {
+        assert!("1080p,720p".parse::<QualityPreference>().is_err());
+        assert!("1080pxx".parse::<QualityPreference>().is_err());
+        assert!("vp9".parse::<QualityPreference>().is_err());
+    }
+
+    #[test]
+    fn selects_h264_by_default() {
+        let playlist = playlist();
+        let selected = QualityPreference::default().select(&playlist).unwrap();
+        assert_eq!(selected.uri, "1080-avc1.64002A,mp4a.40.2.m3u8");
+
+        let worst = "worst".parse::<QualityPreference>().unwrap();
+        assert_eq!(
+            worst.select(&playlist).unwrap().uri,
+            "720-avc1.4D401F,mp4a.40.2.m3u8"
+        );
+    }
+
+    #[test]
+    fn selects_other_codecs_when_opted_in() {
+        let playlist = playlist();
+        let av1 = "best,av1".parse::<QualityPreference>().unwrap();
+        assert_eq!(
+            av1.select(&playlist).unwrap().uri,
+            "1440-av01.0.12M.08,mp4a.40.2.m3u8"
+        );
+
+        let hevc = "1080p60,hevc,h264".parse::<QualityPreference>().unwrap();
+        assert_eq!(
+            hevc.select(&playlist).unwrap().uri,
+            "1080-hvc1.1.2.L123.90,mp4a.40.2.m3u8"
+        );
+    }
+
+    #[test]
+    fn selects_any_codec_without_h264_variants() {
+        let mut playlist = playlist();
+        playlist.variants.retain(|v| !v.uri.contains("avc1"));
+        let selected = QualityPreference::default().select(&playlist).unwrap();
+        assert_eq!(selected.uri, "1440-av01.0.12M.08,mp4a.40.2.m3u8");
+    }
+
+    #[test]
+    fn detects_muted_segments() {
+        assert!(is_muted_segment(&segment("12-muted.ts", 10.0)));
+        assert!(!is_muted_segment(&segment("12-unmuted.ts", 10.0)));
+        assert!(!is_muted_segment(&segment("12.ts", 10.0)));
+        assert_eq!(
+            unmuted_segment_uris("12-muted.ts"),
+            vec!["12-unmuted.ts".to_string(), "12.ts".to_string()]
+        );
+        assert!(unmuted_segment_uris("12-unmuted.ts").is_empty());
+    }
+
+    #[test]
+    fn finds_muted_copy_of_missing_segments() {
+        assert_eq!(muted_segment_uri("12.ts"), Some("12-muted.ts".to_string()));
+        assert_eq!(
+            muted_segment_uri("12.mp4"),
+            Some("12-muted.mp4".to_string())
+        );
+        assert_eq!(muted_segment_uri("12-muted.ts"), None);
+    }
+
+    #[test]
+    fn merges_consecutive_muted_ranges() {
+        let segments = [
+            segment("0.ts", 10.0),
+            segment("1-muted.ts", 10.0),
+            segment("2-muted.ts", 5.0),
+            segment("3.ts", 10.0),
+            segment("4-muted.ts", 10.0),
+        ];
+
+        let ranges = muted_ranges(segments.iter().map(|s| (s, is_muted_segment(s))));
+        assert_eq!(
+            ranges,
+            vec![
+                MutedRange {
+                    start: Duration::from_secs(10),
+                    end: Duration::from_secs(25),
+                },
+                MutedRange {
+                    start: Duration::from_secs(35),
+                    end: Duration::from_secs(45),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unmuted_segments_are_not_muted_ranges() {
+        let segments = [segment("0-muted.ts", 10.0), segment("1.ts", 10.0)];
+        assert!(muted_ranges(segments.iter().map(|s| (s, false))).is_empty());
+    }
+}
@@ -0,0 +1,494 @@
+use std::{collections::HashSet, io::ErrorKind, path::Path, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    select,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    download::{RetryPolicy, SegmentError, is_retriable_status},
+    twitch::AUTHENTICATED_PUBLIC_HTTP_CLIENT,
+};
+
+/// A single chat message of a VOD's chat replay, stored as one line of a JSON lines file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: String,
+    /// Seconds since the start of the VOD
+    pub offset: u64,
+    pub created_at: DateTime<Utc>,
+    /// `None` when the account has been deleted
+    pub author: Option<ChatAuthor>,
+    pub badges: Vec<ChatBadge>,
+    pub emotes: Vec<ChatEmote>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatAuthor {
+    pub id: String,
+    pub login: String,
+    pub display_name: String,
+    /// Name color picked by the user, e.g. `#FF4500`
+    pub color: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatBadge {
+    /// e.g. `subscriber` or `moderator`
+    pub set_id: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatEmote {
+    pub id: String,
+    /// Text the emote replaces, e.g. `Kappa`
+    pub name: String,
+    /// Character index of the emote in the message
+    pub begin: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CommentNode {
+    id: String,
+    content_offset_seconds: u64,
+    created_at: DateTime<Utc>,
+    commenter: Option<Commenter>,
+    message: Option<CommentMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Commenter {
+    id: String,
+    login: String,
+    display_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CommentMessage {
+    #[serde(default)]
+    fragments: Vec<CommentFragment>,
+    #[serde(default)]
+    user_badges: Vec<CommentBadge>,
+    user_color: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommentFragment {
+    text: String,
+    emote: Option<CommentEmote>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommentEmote {
+    #[serde(rename = "emoteID")]
+    emote_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommentBadge {
+    #[serde(rename = "setID")]
+    set_id: String,
+    version: String,
+}
+
+struct CommentPage {
+    messages: Vec<ChatMessage>,
+    has_next_page: bool,
+}
+
+/// Where a previous chat download stopped
+#[derive(Debug, Default)]
+struct ChatProgress {
+    /// Offset of the last saved message
+    offset: u64,
+    /// Messages saved at [`ChatProgress::offset`], which will be returned again by Twitch
+    ids: HashSet<String>,
+    count: usize,
+}
+
+impl ChatProgress {
+    /// Records a message as saved
+    ///
+    /// Returns `false` when the message has already been saved, as pages overlap
+    fn record(&mut self, id: &str, offset: u64) -> bool {
+        if offset < self.offset || self.ids.contains(id) {
+            return false;
+        }
+        if offset > self.offset {
+            self.offset = offset;
+            self.ids.clear();
+        }
+        self.ids.insert(id.to_string());
+        self.count += 1;
+
+        true
+    }
+}
+
+/// Downloads the chat replay of a VOD into a JSON lines file, one [`ChatMessage`] per line
+///
+/// Twitch returns chat messages in pages starting from a content offset (in seconds). An
+/// existing file is resumed from its last message, so interrupted downloads can be restarted
+/// by running the same command again. Failing pages (e.g. when rate limited) are retried
+/// following `retry_policy`.
+///
+/// # Errors
+/// Errors when the file cannot be read / written, or when a page keeps failing after running out
+/// of retries
+#[instrument(skip(ct, retry_policy))]
+pub async fn download_chat(
+    ct: &CancellationToken,
+    vod_id: u64,
+    length: Duration,
+    path: &Path,
+    retry_policy: &RetryPolicy,
+) -> Result<()> {
+    let mut progress = resume(path).await?;
+    if progress.count > 0 {
+        info!(
+            "Resuming the chat download from {} saved messages",
+            progress.count
+        );
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .context("Opening chat file")?;
+
+    let pb = indicatif::ProgressBar::new(length.as_secs());
+    pb.set_style(
+        indicatif::ProgressStyle::with_template(
+            "[{elapsed_precise}] [{wide_bar}] Chat {pos}/{len}s ({msg} messages)",
+        )
+        .unwrap(),
+    );
+    pb.set_position(progress.offset);
+    pb.set_message(progress.count.to_string());
+
+    let mut offset = progress.offset;
+    loop {
+        let page = select! {
+            () = ct.cancelled() => break,
+            page = fetch_page_with_retries(vod_id, offset, retry_policy) => page?,
+        };
+
+        let mut lines = String::new();
+        for message in page.messages {
+            if !progress.record(&message.id, message.offset) {
+                continue;
+            }
+
+            lines.push_str(&serde_json::to_string(&message).context("Serializing chat message")?);
+            lines.push('\n');
+        }
+
+        if !lines.is_empty() {
+            file.write_all(lines.as_bytes())
+                .await
+                .context("Writing chat messages")?;
+            // The same second is requested again, as it might have more messages than a page
+            offset = progress.offset;
+        } else if page.has_next_page && offset < length.as_secs() {
+            // Every message of this second has been saved
+            offset += 1;
+        } else {
+            break;
+        }
+
+        pb.set_position(offset.min(length.as_secs()));
+        pb.set_message(progress.count.to_string());
+    }
+    pb.finish_and_clear();
+
+    file.flush().await.context("Writing chat messages")?;
+    if ct.is_cancelled() {
+        info!("Chat download progress is saved. Run the same command again to resume it");
+    } else {
+        info!("Saved {} chat messages into {path:?}", progress.count);
+    }
+
+    Ok(())
+}
+
+/// Reads a chat replay saved by [`download_chat`], sorted by offset
+///
+/// # Errors
+/// Errors when the file cannot be read or has malformed messages
+pub async fn read_chat(path: &Path) -> Result<Vec<ChatMessage>> {
+    let file = tokio::fs::read_to_string(path)
+        .await
+        .context("Reading chat file")?;
+
+    let mut messages = file
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            serde_json::from_str::<ChatMessage>(l)
+                .with_context(|| format!("Parsing saved chat message: {l}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    messages.sort_by_key(|m| m.offset);
+
+    Ok(messages)
+}
+
+/// Reads where a previous chat download has stopped, dropping a partially written last line
+async fn resume(path: &Path) -> Result<ChatProgress> {
+    /// Only the fields needed to resume
+    #[derive(Deserialize)]
+    struct SavedMessage {
+        id: String,
+        offset: u64,
+    }
+
+    let file = match File::options().read(true).write(true).open(path).await {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(ChatProgress::default()),
+        Err(e) => return Err(e).context("Opening chat file"),
+    };
+
+    let mut progress = ChatProgress::default();
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    let mut complete_length = 0;
+    loop {
+        line.clear();
+        let read = reader
+            .read_line(&mut line)
+            .await
+            .context("Reading chat file")?;
+        if read == 0 || !line.ends_with('\n') {
+            break;
+        }
+        complete_length += read as u64;
+
+        let message = serde_json::from_str::<SavedMessage>(&line)
+            .with_context(|| format!("Parsing saved chat message: {line}"))?;
+        progress.record(&message.id, message.offset);
+    }
+
+    if !line.is_empty() {
+        warn!("Dropping the partially saved last chat message");
+        reader
+            .into_inner()
+            .set_len(complete_length)
+            .await
+            .context("Truncating chat file")?;
+    }
+
+    Ok(progress)
+}
+
+async fn fetch_page_with_retries(
+    vod_id: u64,
+    offset: u64,
+    retry_policy: &RetryPolicy,
+) -> Result<CommentPage> {
+    let mut attempt = 0;
+    loop {
+        match fetch_page(vod_id, offset).await {
+            Ok(page) => return Ok(page),
+            Err(e) if e.is_retriable() && attempt < retry_policy.max_retries => {
+                attempt += 1;
+                let delay = retry_policy.backoff(attempt);
+                warn!(
+                    "Fetching chat messages failed, retrying in {delay:.1?} ({attempt}/{}): {e}",
+                    retry_policy.max_retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(anyhow!("Fetching chat messages failed: {e}")),
+        }
+    }
+}
+
+/// Fetches the chat messages of a VOD starting from `offset` seconds
+async fn fetch_page(vod_id: u64, offset: u64) -> Result<CommentPage, SegmentError> {
+    let res = AUTHENTICATED_PUBLIC_HTTP_CLIENT
+        .post("https://gql.twitch.tv/gql")
+        .json(&json!({
+            "query": "query VideoComments($id: ID!, $offset: Int) {
+                video(id: $id) {
+                    comments(contentOffsetSeconds: $offset) {
+                        edges {
+                            node {
+                                id
+                                contentOffsetSeconds
+                                createdAt
+                                commenter { id login displayName }
+                                message {
+                                    fragments { text emote { emoteID } }
+                                    userBadges { setID version }
+                                    userColor
+                                }
+                            }
+                        }
+                        pageInfo { hasNextPage }
+                    }
+                }
+            }",
+            "variables": {
+                "id": vod_id.to_string(),
+                "offset": offset,
+            }
+        }))
+        .send()
+        .await
+        .map_err(|e| SegmentError::from_reqwest(e, "Fetching chat messages"))?;
+
+    let status = res.status();
+    if !status.is_success() {
+        let error = anyhow!("Twitch responded with {status}");
+        return Err(if is_retriable_status(status) {
+            SegmentError::Retriable(error)
+        } else {
+            SegmentError::Fatal(error)
+        });
+    }
+
+    let mut json = res
+        .json::<Value>()
+        .await
+        .map_err(|e| SegmentError::from_reqwest(e, "Parsing chat messages"))?;
+
+    let mut comments = json["data"]["video"]["comments"].take();
+    if comments.is_null() {
+        // Twitch reports rate limits and service hiccups as GraphQL errors
+        if let Some(errors) = json.get("errors") {
+            return Err(SegmentError::Retriable(anyhow!(
+                "Twitch responded with errors: {errors}"
+            )));
+        }
+        return Err(SegmentError::Fatal(anyhow!(
+            "VOD {vod_id} has no chat replay or is inaccessible"
+        )));
+    }
+
+    let has_next_page = comments["pageInfo"]["hasNextPage"]
+        .as_bool()
+        .unwrap_or(false);
+    let messages = comments["edges"]
+        .as_array_mut()
+        .map(|edges| {
+            edges
+                .iter_mut()
+                .map(|e| CommentNode::deserialize(e["node"].take()).map(ChatMessage::from))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()
+        .map_err(|e| SegmentError::Fatal(anyhow!(e).context("Parsing chat messages")))?
+        .unwrap_or_default();
+    debug!("Fetched {} chat messages at {offset}s", messages.len());
+
+    Ok(CommentPage {
+        messages,
+        has_next_page,
+    })
+}
+
+impl From<CommentNode> for ChatMessage {
+    fn from(node: CommentNode) -> Self {
+        let mut message = String::new();
+        let mut emotes = Vec::new();
+        let (badges, color) = node.message.map_or_else(Default::default, |comment| {
+            for fragment in comment.fragments {
+                if let Some(emote) = fragment.emote {
+                    emotes.push(ChatEmote {
+                        id: emote.emote_id,
+                        name: fragment.text.clone(),
+                        begin: message.chars().count(),
+                    });
+                }
+                message.push_str(&fragment.text);
+            }
+
+            let badges = comment
+                .user_badges
+                .into_iter()
+                .map(|b| ChatBadge {
+                    set_id: b.set_id,
+                    version: b.version,
+                })
+                .collect();
+            (badges, comment.user_color)
+        });
+
+        Self {
+            id: node.id,
+            offset: node.content_offset_seconds,
+            created_at: node.created_at,
+            author: node.commenter.map(|c| ChatAuthor {
+                id: c.id,
+                login: c.login,
+                display_name: c.display_name,
+                color,
+            }),
+            badges,
+            emotes,
+            message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir;
+
+    #[test]
+    fn skips_already_saved_messages() {
+        let mut progress = ChatProgress::default();
+        assert!(progress.record("a", 1));
+        assert!(progress.record("b", 1));
+        assert!(!progress.record("a", 1));
+        assert!(progress.record("c", 2));
+        // Older seconds are not requested again
+        assert!(!progress.record("d", 1));
+        assert!(!progress.record("c", 2));
+
+        assert_eq!(progress.offset, 2);
+        assert_eq!(progress.count, 3);
+    }
+
+    #[tokio::test]
+    async fn resumes_from_last_saved_message() {
+        let dir = test_dir("chat-resume");
+        let path = dir.join("chat.jsonl");
+        assert_eq!(resume(&path).await.unwrap().count, 0);
+
+        let saved = concat!(
+            r#"{"id":"a","offset":1}"#,
+            "\n",
+            r#"{"id":"b","offset":3}"#,
+            "\n",
+            r#"{"id":"c","offset":3}"#,
+            "\n",
+        );
+        std::fs::write(&path, format!(r#"{saved}{{"id":"d","off"#)).unwrap();
+
+        let progress = resume(&path).await.unwrap();
+        assert_eq!(progress.count, 3);
+        assert_eq!(progress.offset, 3);
+        assert_eq!(
+            progress.ids,
+            HashSet::from(["b".to_string(), "c".to_string()])
+        );
+        // The partially written message is dropped
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), saved);
+    }
+}
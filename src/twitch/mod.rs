@@ -4,4 +4,5 @@ pub use utils::*;
 
 pub mod api;
 pub mod cdn;
+pub mod chat;
 pub mod structs;
@@ -1,6 +1,10 @@
+use std::{fmt::Display, time::Duration};
+
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
+use crate::util::format_timestamp;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VideoInfo {
@@ -11,21 +15,134 @@ pub struct VideoInfo {
     pub length_seconds: u64,
     pub view_count: u64,
     pub status: Status,
-    pub game: Game,
+    pub broadcast_type: BroadcastType,
+    /// Preview image of the VOD, a placeholder while the VOD is still being processed
+    #[serde(rename = "previewThumbnailURL")]
+    pub preview_thumbnail_url: Option<String>,
+    /// `None` when the stream had no game / category set
+    pub game: Option<Game>,
     pub owner: Channel,
 }
 
+impl VideoInfo {
+    /// Link to the video on Twitch
+    #[must_use]
+    pub fn url(&self) -> String {
+        match self.broadcast_type {
+            BroadcastType::Clip => format!("https://clips.twitch.tv/{}", self.id),
+            _ => format!("https://www.twitch.tv/videos/{}", self.id),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Status {
     RECORDED,
     RECORDING,
 }
 
+/// A live stream of a channel, saved as a past broadcast once it ends
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LiveStream {
+    pub id: String,
+    #[serde(alias = "createdAt")]
+    pub started_at: chrono::DateTime<Utc>,
+    /// VOD the stream is being recorded into, `None` when VODs are disabled or when the stream
+    /// comes from an `EventSub` notification
+    #[serde(default, alias = "archiveVideo")]
+    pub archive_video: Option<ArchiveVideo>,
+}
+
+/// The VOD of a live stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveVideo {
+    pub id: String,
+}
+
+/// Kind of a Twitch video
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum BroadcastType {
+    /// Past broadcast, saved automatically while streaming
+    Archive,
+    /// Section of a past broadcast kept by the streamer
+    Highlight,
+    /// Video uploaded by the streamer
+    Upload,
+    /// Uploaded video which was premiered as a stream
+    PastPremiere,
+    /// Not a Twitch broadcast type, used by clips converted into a [`VideoInfo`]
+    #[value(skip)]
+    Clip,
+    /// Any type added by Twitch since, e.g. `PREMIERE_UPLOAD`
+    #[value(skip)]
+    #[serde(other)]
+    Unknown,
+}
+
+/// A Twitch clip, downloaded as a single MP4 file instead of an HLS playlist
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipInfo {
+    pub id: String,
+    /// Identifies the clip in its URL, e.g. `https://clips.twitch.tv/<slug>`
+    pub slug: String,
+    pub title: String,
+    pub created_at: chrono::DateTime<Utc>,
+    pub duration_seconds: u64,
+    pub view_count: u64,
+    #[serde(rename = "thumbnailURL")]
+    pub thumbnail_url: Option<String>,
+    /// `None` when the stream had no game / category set
+    pub game: Option<Game>,
+    pub broadcaster: Channel,
+    pub video_qualities: Vec<ClipQuality>,
+}
+
+impl ClipInfo {
+    /// The clip as a video, so it goes through the same templates / metadata as VODs
+    ///
+    /// The slug is used as the video ID
+    #[must_use]
+    pub fn to_video_info(&self) -> VideoInfo {
+        VideoInfo {
+            id: self.slug.clone(),
+            title: self.title.clone(),
+            description: None,
+            created_at: self.created_at,
+            length_seconds: self.duration_seconds,
+            view_count: self.view_count,
+            status: Status::RECORDED,
+            broadcast_type: BroadcastType::Clip,
+            preview_thumbnail_url: self.thumbnail_url.clone(),
+            game: self.game.clone(),
+            owner: self.broadcaster.clone(),
+        }
+    }
+}
+
+/// A rendition of a clip
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipQuality {
+    /// Height of the video, e.g. `1080`
+    pub quality: String,
+    pub frame_rate: f64,
+    /// MP4 file of the clip, which needs a clip access token
+    #[serde(rename = "sourceURL")]
+    pub source_url: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Channel {
     pub login: String,
     pub display_name: String,
+    #[serde(rename = "profileImageURL")]
+    pub profile_image_url: Option<String>,
+    /// Banner shown on top of the channel page, `None` when the streamer never set one
+    #[serde(rename = "bannerImageURL")]
+    pub banner_image_url: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,3 +150,18 @@ pub struct Channel {
 pub struct Game {
     pub display_name: String,
 }
+
+/// A section of a VOD, starting whenever the streamer changed the game / category
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chapter {
+    pub start: Duration,
+    pub end: Duration,
+    pub title: String,
+}
+
+impl Display for Chapter {
+    /// Formatted as a YouTube chapter line, e.g. `01:23:45 Just Chatting`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", format_timestamp(self.start), self.title)
+    }
+}
@@ -1,11 +1,11 @@
 // TODO: Swap this to use thiserror, this technically is a library
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
 use regex::Regex;
 use std::sync::LazyLock;
 
 // https://github.com/SuperSonicHub1/twitch-graphql-api#getting-your-client-id
 pub const AUTHENTICATED_PUBLIC_CLIENT_ID: &str = "kimne78kx3ncx6brgo4mv6wki5h1ko";
-pub const AUTHENTICATED_PUBLIC_HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+pub static AUTHENTICATED_PUBLIC_HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert(
         "Client-ID",
@@ -27,8 +27,8 @@ pub const AUTHENTICATED_PUBLIC_HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock
         .unwrap()
 });
 
-pub const VIDEO_ID_REGEX: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"https:?//(?:www\.)twitch.tv/videos/(\d+)").unwrap());
+pub static VIDEO_ID_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"https?://(?:www\.|m\.)?twitch\.tv/videos/(\d+)").unwrap());
 
 /// Extracts a video ID out from a user-inputted URL string
 /// # Errors
@@ -45,3 +45,110 @@ pub fn extract_video_id(input: &str) -> Result<u64> {
 
     bail!("Unable to parse Twitch Video URL / ID");
 }
+
+pub static CLIP_URL_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^https?://(?:(?:www\.|m\.)?twitch\.tv/\w+/clip/|clips\.twitch\.tv/)([\w-]+)")
+        .unwrap()
+});
+
+/// A Twitch video, either a VOD by its ID or a clip by its slug
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VideoRef {
+    Video(u64),
+    Clip(String),
+}
+
+/// Extracts a video ID / clip slug out from a user-inputted URL string
+/// # Errors
+/// Error when unable to parse twitch video ID or clip URL
+pub fn extract_video(input: &str) -> Result<VideoRef> {
+    if let Some(c) = CLIP_URL_REGEX.captures(input) {
+        return Ok(VideoRef::Clip(c[1].to_string()));
+    }
+
+    extract_video_id(input)
+        .map(VideoRef::Video)
+        .map_err(|_| anyhow!("Unable to parse Twitch Video / Clip URL / ID"))
+}
+
+pub static CHANNEL_URL_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^https?://(?:www\.|m\.)?twitch\.tv/(\w+)/?$").unwrap());
+pub static CHANNEL_LOGIN_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\w{1,25}$").unwrap());
+
+/// A Twitch channel, looked up either by its ID or login name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelRef {
+    Id(u64),
+    Login(String),
+}
+
+/// Extracts a channel ID / login out from a user-inputted URL string
+/// # Errors
+/// Error when the input is neither a channel ID, login nor URL
+pub fn extract_channel(input: &str) -> Result<ChannelRef> {
+    if let Ok(i) = input.parse::<u64>() {
+        return Ok(ChannelRef::Id(i));
+    }
+
+    if let Some(c) = CHANNEL_URL_REGEX.captures(input) {
+        return Ok(ChannelRef::Login(c[1].to_lowercase()));
+    }
+
+    if CHANNEL_LOGIN_REGEX.is_match(input) {
+        return Ok(ChannelRef::Login(input.to_lowercase()));
+    }
+
+    bail!("Unable to parse Twitch channel URL / login / ID");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_video_ids() {
+        for input in [
+            "2345678901",
+            "https://www.twitch.tv/videos/2345678901",
+            "https://twitch.tv/videos/2345678901",
+            "https://m.twitch.tv/videos/2345678901?t=1h2m3s",
+            "http://www.twitch.tv/videos/2345678901",
+        ] {
+            assert_eq!(extract_video_id(input).unwrap(), 2_345_678_901, "{input}");
+        }
+
+        assert!(extract_video_id("https://www.twitch.tv/somestreamer").is_err());
+    }
+
+    #[test]
+    fn extracts_clips() {
+        for input in [
+            "https://clips.twitch.tv/FunnyClipSlug-AbC123",
+            "https://www.twitch.tv/somestreamer/clip/FunnyClipSlug-AbC123",
+            "https://m.twitch.tv/somestreamer/clip/FunnyClipSlug-AbC123",
+        ] {
+            assert_eq!(
+                extract_video(input).unwrap(),
+                VideoRef::Clip("FunnyClipSlug-AbC123".to_string()),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn extracts_channels() {
+        assert_eq!(extract_channel("12345").unwrap(), ChannelRef::Id(12345));
+        for input in [
+            "https://www.twitch.tv/SomeStreamer",
+            "https://m.twitch.tv/somestreamer/",
+            "SomeStreamer",
+        ] {
+            assert_eq!(
+                extract_channel(input).unwrap(),
+                ChannelRef::Login("somestreamer".to_string()),
+                "{input}"
+            );
+        }
+    }
+}
@@ -1,7 +1,8 @@
-use std::time::Duration;
+use std::{io, time::Duration};
 
 use reqwest::header::{HeaderMap, HeaderValue};
 use rlimit::Resource;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 
@@ -38,7 +39,7 @@ pub fn warn_ulimit() {
     let (limit, _) = rlimit::getrlimit(Resource::NOFILE).unwrap();
     if limit <= 2048 {
         warn!(
-            "Your file limit is very low which may introduce an error while processing long videos. Consider raising your file limit via `ulimit -n 10240`"
+            "Your file limit is very low which may introduce an error while concatenating long videos using the concat protocol. Consider raising your file limit via `ulimit -n 10240` or using the concat demuxer"
         );
     }
 }
@@ -72,3 +73,48 @@ pub fn spawn_ct_watcher(ct: CancellationToken) {
         ct.cancel();
     });
 }
+
+/// Formats a duration as a `HH:MM:SS` timestamp
+#[must_use]
+pub fn format_timestamp(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+/// Reads from `reader` until `buf` is full or the reader reaches EOF
+///
+/// Returns the amount of bytes read, which is only less than `buf`'s length on EOF
+///
+/// # Errors
+/// Errors when reading fails
+pub async fn read_full(reader: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = reader.read(&mut buf[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+
+    Ok(filled)
+}
+
+/// Creates an empty directory for a test to write into, unique to the test process
+#[cfg(test)]
+#[must_use]
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "{}-test-{}-{name}",
+        env!("CARGO_PKG_NAME"),
+        std::process::id()
+    ));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
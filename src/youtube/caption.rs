@@ -0,0 +1,89 @@
+use anyhow::Result;
+use reqwest::{
+    Client,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+};
+use serde_json::json;
+use tokio::sync::watch;
+use tracing::instrument;
+
+use super::{UploadedVideo, send, upload::bearer};
+
+/// Adds a caption track (e.g. `SubRip` or `WebVTT`) to an uploaded video
+///
+/// See: <https://developers.google.com/youtube/v3/docs/captions/insert>
+///
+/// # Errors
+/// Errors on network errors or when YouTube rejects the caption track
+#[instrument(skip(client, access_token, video, captions), fields(id = video.id))]
+pub async fn insert_caption(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    video: &UploadedVideo,
+    name: &str,
+    language: &str,
+    captions: String,
+) -> Result<()> {
+    let snippet = json!({
+        "snippet": {
+            "videoId": video.id,
+            "language": language,
+            "name": name,
+        }
+    });
+    let (boundary, body) = multipart_body(&snippet.to_string(), &captions);
+
+    send(
+        client
+            .post("https://www.googleapis.com/upload/youtube/v3/captions")
+            .header(AUTHORIZATION, bearer(access_token)?)
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/related; boundary={boundary}"),
+            )
+            .query(&[("part", "snippet"), ("uploadType", "multipart")])
+            .body(body),
+        "Uploading captions",
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Builds the `multipart/related` body sending the caption resource and the caption file
+/// together, returning it along with its boundary
+///
+/// The boundary is picked at random, again until it does not appear in the captions
+fn multipart_body(resource: &str, captions: &str) -> (String, String) {
+    let boundary = std::iter::repeat_with(|| {
+        let suffix = std::iter::repeat_with(fastrand::alphanumeric)
+            .take(24)
+            .collect::<String>();
+        format!("vod-squirrel-{suffix}")
+    })
+    .find(|b| !resource.contains(b.as_str()) && !captions.contains(b.as_str()))
+    .unwrap();
+
+    let body = format!(
+        "--{boundary}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{resource}\r\n\
+         --{boundary}\r\nContent-Type: application/octet-stream\r\n\r\n{captions}\r\n\
+         --{boundary}--"
+    );
+    (boundary, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_a_boundary_missing_from_the_captions() {
+        let captions = "1\r\n00:00:01,000 --> 00:00:02,000\r\nchatter: --vod-squirrel-caption\r\n";
+        let (boundary, body) = multipart_body("{}", captions);
+
+        assert!(!captions.contains(&boundary));
+        assert_eq!(body.matches(&format!("--{boundary}")).count(), 3);
+        assert!(body.ends_with(&format!("\r\n--{boundary}--")));
+        assert_ne!(multipart_body("{}", captions).0, boundary);
+    }
+}
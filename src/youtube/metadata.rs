@@ -0,0 +1,165 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// YouTube titles can be up to 100 characters
+pub const MAX_TITLE_CHARS: usize = 100;
+/// YouTube descriptions can be up to 5000 bytes
+pub const MAX_DESCRIPTION_BYTES: usize = 5000;
+/// YouTube limits the total length of every tag combined
+const MAX_TAGS_LENGTH: usize = 500;
+/// YouTube thumbnails can be up to 2 MB
+pub const MAX_THUMBNAIL_BYTES: usize = 2 * 1000 * 1000;
+
+/// Metadata applied to every archived video, set with command line flags or the `[youtube]`
+/// table of the config file
+///
+/// See: <https://developers.google.com/youtube/v3/docs/videos#resource>
+#[derive(Debug, Clone, Default, clap::Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoMetadata {
+    /// Privacy of archived videos [default: unlisted]
+    #[arg(long, value_enum, value_name = "PRIVACY")]
+    pub privacy: Option<PrivacyStatus>,
+
+    /// YouTube video category ID of archived videos (e.g. `20` for Gaming)
+    #[arg(long, value_name = "ID")]
+    pub category_id: Option<String>,
+
+    /// Tag added to every archived video, can be repeated
+    #[arg(long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+
+    /// Add the channel and game name as tags [default: true]
+    #[arg(long, value_name = "BOOL")]
+    pub auto_tags: Option<bool>,
+
+    /// Language of the title and description (e.g. `en`)
+    #[arg(long, value_name = "LANGUAGE")]
+    pub default_language: Option<String>,
+
+    /// Language spoken in archived videos (e.g. `en`)
+    #[arg(long, value_name = "LANGUAGE")]
+    pub default_audio_language: Option<String>,
+
+    #[arg(long, value_enum, value_name = "LICENSE")]
+    pub license: Option<License>,
+
+    /// Allow archived videos to be embedded on other websites
+    #[arg(long, value_name = "BOOL")]
+    pub embeddable: Option<bool>,
+
+    /// Show the view count on archived videos
+    #[arg(long, value_name = "BOOL")]
+    pub public_stats_viewable: Option<bool>,
+
+    /// Publish archived videos at the given time (RFC 3339, e.g. `2025-01-01T12:00:00Z`).
+    /// Videos are kept private until then
+    #[arg(long, value_name = "TIME")]
+    pub publish_at: Option<DateTime<Utc>>,
+
+    /// Thumbnail of archived videos [default: twitch]
+    #[arg(long, value_enum, value_name = "SOURCE")]
+    pub thumbnail: Option<ThumbnailSource>,
+
+    /// Timestamp of the frame used by `--thumbnail frame`, in seconds [default: middle of the
+    /// video]
+    #[arg(long, value_name = "SECONDS")]
+    pub thumbnail_timestamp: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivacyStatus {
+    Private,
+    #[default]
+    Unlisted,
+    Public,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "camelCase")]
+pub enum License {
+    /// Standard YouTube license
+    Youtube,
+    /// Creative Commons - Attribution
+    CreativeCommon,
+}
+
+/// Where the custom thumbnail of archived videos comes from
+///
+/// Custom thumbnails are only available to verified YouTube channels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailSource {
+    /// Twitch's preview thumbnail of the VOD, or a frame when it is not available yet
+    #[default]
+    Twitch,
+    /// A frame of the video at `--thumbnail-timestamp`
+    Frame,
+    /// Keep the frame picked by YouTube
+    Auto,
+}
+
+impl VideoMetadata {
+    /// Fills every setting missing from `self` with the one from `other`
+    #[must_use]
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            privacy: self.privacy.or(other.privacy),
+            category_id: self.category_id.or(other.category_id),
+            tags: if self.tags.is_empty() {
+                other.tags
+            } else {
+                self.tags
+            },
+            auto_tags: self.auto_tags.or(other.auto_tags),
+            default_language: self.default_language.or(other.default_language),
+            default_audio_language: self.default_audio_language.or(other.default_audio_language),
+            license: self.license.or(other.license),
+            embeddable: self.embeddable.or(other.embeddable),
+            public_stats_viewable: self.public_stats_viewable.or(other.public_stats_viewable),
+            publish_at: self.publish_at.or(other.publish_at),
+            thumbnail: self.thumbnail.or(other.thumbnail),
+            thumbnail_timestamp: self.thumbnail_timestamp.or(other.thumbnail_timestamp),
+        }
+    }
+
+    /// YouTube only allows scheduling private videos
+    #[must_use]
+    pub fn privacy_status(&self) -> PrivacyStatus {
+        if self.publish_at.is_some() {
+            PrivacyStatus::Private
+        } else {
+            self.privacy.unwrap_or_default()
+        }
+    }
+}
+
+/// Cleans up tags to be accepted by YouTube
+///
+/// Removes `<` / `>`, empty and duplicate tags, then drops tags past YouTube's total length limit.
+/// Tags containing spaces count 2 extra characters as they are quoted.
+#[must_use]
+pub fn sanitize_tags(tags: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut sanitized: Vec<String> = Vec::new();
+    let mut length = 0;
+
+    for tag in tags {
+        let tag = tag.replace(['<', '>'], "").trim().to_string();
+        if tag.is_empty() || sanitized.iter().any(|t| t.eq_ignore_ascii_case(&tag)) {
+            continue;
+        }
+
+        let tag_length = tag.chars().count() + if tag.contains(' ') { 2 } else { 0 };
+        // Tags are separated by commas
+        let separator = usize::from(!sanitized.is_empty());
+        if length + separator + tag_length > MAX_TAGS_LENGTH {
+            break;
+        }
+
+        length += separator + tag_length;
+        sanitized.push(tag);
+    }
+
+    sanitized
+}
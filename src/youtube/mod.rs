@@ -0,0 +1,95 @@
+mod caption;
+mod metadata;
+mod playlist;
+mod structs;
+mod thumbnail;
+mod update;
+mod upload;
+
+pub use caption::*;
+pub use metadata::*;
+pub use playlist::*;
+pub use structs::*;
+pub use thumbnail::*;
+pub use update::*;
+pub use upload::*;
+
+use anyhow::{Context, Result, bail};
+use reqwest::RequestBuilder;
+use serde_json::{Value, json};
+
+use crate::util::truncate_string;
+
+#[derive(Debug, Clone)]
+pub struct VideoDetail<'a> {
+    pub title: &'a str,
+    pub description: &'a str,
+    /// Already sanitized, see [`sanitize_tags`]
+    pub tags: &'a [String],
+    pub metadata: &'a VideoMetadata,
+}
+
+impl VideoDetail<'_> {
+    /// The `snippet` part of a video resource
+    ///
+    /// See: <https://developers.google.com/youtube/v3/docs/videos#snippet>
+    fn snippet(&self) -> Value {
+        let footer = format!(
+            "\n\nAutomatically archived using VOD Squirrel {}: https://github.com/angeloanan/vod-squirrel",
+            env!("CARGO_PKG_VERSION")
+        );
+        let description = format!(
+            "{}{footer}",
+            truncate_string(&self.description, MAX_DESCRIPTION_BYTES - footer.len())
+        );
+
+        without_nulls(json!({
+            "title": self.title,
+            "description": description,
+            "tags": self.tags,
+            "categoryId": self.metadata.category_id,
+            "defaultLanguage": self.metadata.default_language,
+            "defaultAudioLanguage": self.metadata.default_audio_language,
+        }))
+    }
+
+    /// The `status` part of a video resource
+    ///
+    /// See: <https://developers.google.com/youtube/v3/docs/videos#status>
+    fn status(&self) -> Value {
+        let metadata = self.metadata;
+        without_nulls(json!({
+            "privacyStatus": metadata.privacy_status(),
+            "selfDeclaredMadeForKids": false,
+            "license": metadata.license,
+            "embeddable": metadata.embeddable,
+            "publicStatsViewable": metadata.public_stats_viewable,
+            "publishAt": metadata.publish_at,
+        }))
+    }
+}
+
+/// Unset settings are left out to use the channel's defaults
+fn without_nulls(mut part: Value) -> Value {
+    if let Some(part) = part.as_object_mut() {
+        part.retain(|_, v| !v.is_null());
+    }
+    part
+}
+
+/// Sends a YouTube Data API request, turning error statuses into errors with YouTube's response
+///
+/// Returns the JSON response body
+async fn send(req: RequestBuilder, action: &'static str) -> Result<Value> {
+    let res = req.send().await.context(action)?;
+
+    let status = res.status();
+    if !status.is_success() {
+        let body = res.text().await.unwrap_or_default();
+        bail!("{action} failed, YouTube responded with {status}: {body}");
+    }
+
+    res.json::<Value>()
+        .await
+        .with_context(|| format!("Parsing response of {}", action.to_lowercase()))
+}
@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use reqwest::{Client, header::AUTHORIZATION};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::watch;
+use tracing::{info, instrument};
+
+use super::{PrivacyStatus, send, upload::bearer};
+
+/// YouTube playlist titles can be up to 150 characters
+pub const MAX_PLAYLIST_TITLE_CHARS: usize = 150;
+
+const PLAYLISTS_URL: &str = "https://www.googleapis.com/youtube/v3/playlists";
+const PLAYLIST_ITEMS_URL: &str = "https://www.googleapis.com/youtube/v3/playlistItems";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistPage {
+    #[serde(default)]
+    items: Vec<Playlist>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Playlist {
+    id: String,
+    snippet: PlaylistSnippet,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaylistSnippet {
+    title: String,
+}
+
+/// Returns the ID of the channel's playlist named `title`, creating the playlist if there is none
+///
+/// # Errors
+/// Errors on network errors or when YouTube rejects the request
+#[instrument(skip(client, access_token))]
+pub async fn find_or_create_playlist(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    title: &str,
+    privacy: PrivacyStatus,
+) -> Result<String> {
+    if let Some(id) = find_playlist(client, access_token, title).await? {
+        return Ok(id);
+    }
+
+    info!("Creating playlist {title:?}");
+    let res = send(
+        client
+            .post(PLAYLISTS_URL)
+            .header(AUTHORIZATION, bearer(access_token)?)
+            .query(&[("part", "snippet,status")])
+            .json(&json!({
+                "snippet": { "title": title },
+                "status": { "privacyStatus": privacy },
+            })),
+        "Creating playlist",
+    )
+    .await?;
+
+    res["id"]
+        .as_str()
+        .map(ToString::to_string)
+        .context("YouTube did not return the created playlist's ID")
+}
+
+/// Pages through the channel's playlists, looking for one named `title`
+async fn find_playlist(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    title: &str,
+) -> Result<Option<String>> {
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut req = client
+            .get(PLAYLISTS_URL)
+            .header(AUTHORIZATION, bearer(access_token)?)
+            .query(&[("part", "snippet"), ("mine", "true"), ("maxResults", "50")]);
+        if let Some(page_token) = &page_token {
+            req = req.query(&[("pageToken", page_token)]);
+        }
+
+        let page = serde_json::from_value::<PlaylistPage>(send(req, "Listing playlists").await?)
+            .context("Parsing playlists")?;
+        if let Some(playlist) = page.items.into_iter().find(|p| p.snippet.title == title) {
+            return Ok(Some(playlist.id));
+        }
+
+        match page.next_page_token {
+            Some(token) => page_token = Some(token),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Adds a video to the end of a playlist
+///
+/// # Errors
+/// Errors on network errors or when YouTube rejects the request
+#[instrument(skip(client, access_token))]
+pub async fn insert_playlist_item(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    playlist_id: &str,
+    video_id: &str,
+) -> Result<()> {
+    send(
+        client
+            .post(PLAYLIST_ITEMS_URL)
+            .header(AUTHORIZATION, bearer(access_token)?)
+            .query(&[("part", "snippet")])
+            .json(&json!({
+                "snippet": {
+                    "playlistId": playlist_id,
+                    "resourceId": {
+                        "kind": "youtube#video",
+                        "videoId": video_id,
+                    },
+                },
+            })),
+        "Adding video to playlist",
+    )
+    .await?;
+
+    Ok(())
+}
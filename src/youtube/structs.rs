@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+/// The video resource YouTube responds with once an upload is finished
+///
+/// See: <https://developers.google.com/youtube/v3/docs/videos#resource>
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadedVideo {
+    pub id: String,
+    pub snippet: Option<VideoSnippet>,
+    #[serde(default)]
+    pub status: VideoStatus,
+}
+
+impl UploadedVideo {
+    #[must_use]
+    pub fn url(&self) -> String {
+        format!("https://youtu.be/{}", self.id)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoSnippet {
+    pub title: Option<String>,
+    /// Category picked by YouTube when none was given
+    pub category_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoStatus {
+    pub upload_status: Option<UploadState>,
+    /// Only set when [`VideoStatus::upload_status`] is [`UploadState::Failed`]
+    pub failure_reason: Option<String>,
+    /// Only set when [`VideoStatus::upload_status`] is [`UploadState::Rejected`]
+    pub rejection_reason: Option<String>,
+    pub privacy_status: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum UploadState {
+    Uploaded,
+    Processed,
+    Failed,
+    Rejected,
+    Deleted,
+    #[serde(other)]
+    Unknown,
+}
@@ -0,0 +1,43 @@
+use anyhow::{Result, ensure};
+use reqwest::{
+    Client,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+};
+use tokio::sync::watch;
+use tracing::instrument;
+
+use super::{MAX_THUMBNAIL_BYTES, UploadedVideo, send, upload::bearer};
+
+/// Sets a JPEG image as the custom thumbnail of an uploaded video
+///
+/// See: <https://developers.google.com/youtube/v3/docs/thumbnails/set>
+///
+/// # Errors
+/// Errors on network errors or when YouTube rejects the thumbnail, e.g. when the channel is not
+/// verified to use custom thumbnails
+#[instrument(skip_all, fields(id = video.id))]
+pub async fn set_thumbnail(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    video: &UploadedVideo,
+    image: Vec<u8>,
+) -> Result<()> {
+    ensure!(
+        image.len() <= MAX_THUMBNAIL_BYTES,
+        "Thumbnail is larger than {}",
+        indicatif::DecimalBytes(MAX_THUMBNAIL_BYTES as u64)
+    );
+
+    send(
+        client
+            .post("https://www.googleapis.com/upload/youtube/v3/thumbnails/set")
+            .header(AUTHORIZATION, bearer(access_token)?)
+            .header(CONTENT_TYPE, "image/jpeg")
+            .query(&[("videoId", video.id.as_str()), ("uploadType", "media")])
+            .body(image),
+        "Uploading thumbnail",
+    )
+    .await?;
+
+    Ok(())
+}
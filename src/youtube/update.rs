@@ -0,0 +1,48 @@
+use anyhow::{Context, Result};
+use reqwest::{Client, header::AUTHORIZATION};
+use serde_json::json;
+use tokio::sync::watch;
+use tracing::instrument;
+
+use super::{UploadedVideo, VideoDetail, send, upload::bearer};
+
+/// Replaces the title, description and tags of an uploaded video
+///
+/// See: <https://developers.google.com/youtube/v3/docs/videos/update>
+///
+/// # Errors
+/// Errors on network errors or when YouTube rejects the update
+#[instrument(skip_all, fields(id = video.id))]
+pub async fn update_video(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    video: &UploadedVideo,
+    video_detail: VideoDetail<'_>,
+) -> Result<()> {
+    let mut snippet = video_detail.snippet();
+    // Updating a snippet requires a category, which YouTube picked when none was given
+    if snippet.get("categoryId").is_none() {
+        snippet["categoryId"] = json!(
+            video
+                .snippet
+                .as_ref()
+                .and_then(|s| s.category_id.as_deref())
+                .context("Uploaded video has no category")?
+        );
+    }
+
+    send(
+        client
+            .put("https://www.googleapis.com/youtube/v3/videos")
+            .header(AUTHORIZATION, bearer(access_token)?)
+            .query(&[("part", "snippet")])
+            .json(&json!({
+                "id": video.id,
+                "snippet": snippet,
+            })),
+        "Updating video",
+    )
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,664 @@
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures_util::stream;
+use reqwest::{
+    Body, Client, Response, StatusCode,
+    header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, LOCATION, RANGE},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncSeekExt},
+    select,
+    sync::watch,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, instrument, warn};
+
+use super::{UploadState, UploadedVideo, VideoDetail};
+use crate::{
+    download::{RetryPolicy, SegmentError, is_retriable_status},
+    ratelimit::RateLimiter,
+    util::read_full,
+};
+
+/// Every chunk except the last one must be a multiple of this size
+pub const UPLOAD_CHUNK_ALIGNMENT: usize = 256 * 1024;
+/// Size of the pieces chunks are split into when applying the upload rate limit
+const RATE_LIMIT_PIECE_SIZE: usize = 64 * 1024;
+/// YouTube keeps resumable upload sessions for about a week, anything older is started over
+const UPLOAD_SESSION_LIFETIME: TimeDelta = TimeDelta::days(6);
+
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    /// Size of each uploaded chunk, must be a multiple of [`UPLOAD_CHUNK_ALIGNMENT`]
+    pub chunk_size: usize,
+    pub retry_policy: RetryPolicy,
+    pub rate_limiter: Arc<RateLimiter>,
+}
+
+/// Video to be uploaded
+pub enum VideoSource {
+    /// A finished video file. Its upload session is saved next to it, so an interrupted upload
+    /// can be resumed by a restarted process
+    File(PathBuf),
+    /// A video of unknown length which is still being written (e.g. streamed from Twitch).
+    /// Can only be resumed while the process is running
+    Stream(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+/// A resumable upload session, stored as `<video file>.upload.json`
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadSession {
+    upload_url: String,
+    content_length: u64,
+    /// Modification time of the video file, so a re-created video starts a new upload
+    modified: SystemTime,
+    created_at: DateTime<Utc>,
+}
+
+enum UploadStatus {
+    /// Upload is not finished, along with the amount of bytes YouTube has received
+    Incomplete(u64),
+    Complete(Response),
+}
+
+/// Uploads a video to YouTube using the resumable upload protocol
+///
+/// Returns the uploaded video, or `None` when cancelled before the upload is finished
+///
+/// The video is uploaded in [`UploadOptions::chunk_size`] chunks. Failing chunks are retried
+/// from the last byte YouTube has acknowledged.\
+/// See: <https://developers.google.com/youtube/v3/guides/using_resumable_upload_protocol>
+///
+/// # Errors
+/// Errors on network errors after running out of retries, when YouTube rejects the upload or
+/// when `video` cannot be read
+#[instrument(skip_all)]
+pub async fn upload_video(
+    ct: CancellationToken,
+    client: Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    video_detail: VideoDetail<'_>,
+    video: VideoSource,
+    options: &UploadOptions,
+) -> Result<Option<UploadedVideo>> {
+    match video {
+        VideoSource::File(path) => {
+            upload_file(&ct, &client, access_token, video_detail, &path, options).await
+        }
+        VideoSource::Stream(video) => {
+            let upload_url = init_upload(&client, access_token, video_detail, None).await?;
+            let pb = progress_bar(None);
+            let res = upload_chunks(
+                &ct,
+                &client,
+                access_token,
+                &upload_url,
+                video,
+                0,
+                None,
+                options,
+                &pb,
+            )
+            .await;
+            pb.finish_and_clear();
+
+            let Some(res) = res? else {
+                warn!(
+                    "Cancellation token caught in the middle of a video upload! Video is not fully uploaded to YouTube!"
+                );
+                return Ok(None);
+            };
+            parse_uploaded_video(res).await.map(Some)
+        }
+    }
+}
+
+async fn upload_file(
+    ct: &CancellationToken,
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    video_detail: VideoDetail<'_>,
+    path: &Path,
+    options: &UploadOptions,
+) -> Result<Option<UploadedVideo>> {
+    let mut file = File::open(path).await.context("Opening video file")?;
+    let metadata = file.metadata().await.context("Reading video metadata")?;
+    let content_length = metadata.len();
+    let modified = metadata
+        .modified()
+        .context("Reading video modification time")?;
+
+    let session_path = session_file_path(path);
+    let previous_session = UploadSession::load(&session_path)
+        .await
+        .filter(|s| s.is_resumable(content_length, modified));
+
+    let mut offset = 0;
+    let mut upload_url = None;
+    if let Some(session) = previous_session {
+        match query_status(
+            client,
+            access_token,
+            &session.upload_url,
+            Some(content_length),
+        )
+        .await
+        {
+            Ok(UploadStatus::Incomplete(acknowledged)) => {
+                info!(
+                    "Resuming the previous upload from {}",
+                    indicatif::DecimalBytes(acknowledged)
+                );
+                offset = acknowledged;
+                upload_url = Some(session.upload_url);
+            }
+            Ok(UploadStatus::Complete(res)) => {
+                info!("Video has already been uploaded");
+                UploadSession::remove(&session_path).await;
+                return parse_uploaded_video(res).await.map(Some);
+            }
+            Err(e) => warn!("Unable to resume the previous upload, starting over: {e}"),
+        }
+    }
+
+    let upload_url = if let Some(url) = upload_url {
+        url
+    } else {
+        let upload_url =
+            init_upload(client, access_token, video_detail, Some(content_length)).await?;
+        UploadSession {
+            upload_url: upload_url.clone(),
+            content_length,
+            modified,
+            created_at: Utc::now(),
+        }
+        .save(&session_path)
+        .await?;
+        upload_url
+    };
+
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .context("Seeking video file")?;
+
+    let pb = progress_bar(Some(content_length));
+    pb.set_position(offset);
+    let res = upload_chunks(
+        ct,
+        client,
+        access_token,
+        &upload_url,
+        file,
+        offset,
+        Some(content_length),
+        options,
+        &pb,
+    )
+    .await;
+    pb.finish_and_clear();
+
+    let Some(res) = res? else {
+        warn!("Cancellation token caught in the middle of a video upload!");
+        info!("Upload progress is saved. Run the same command again to resume uploading");
+        return Ok(None);
+    };
+
+    UploadSession::remove(&session_path).await;
+    parse_uploaded_video(res).await.map(Some)
+}
+
+/// Reads the video resource YouTube responds with after the last chunk
+///
+/// # Errors
+/// Errors when the response is not a video resource or when YouTube failed / rejected the video
+async fn parse_uploaded_video(res: Response) -> Result<UploadedVideo> {
+    let body = res.text().await.context("Reading upload response")?;
+    let video = serde_json::from_str::<UploadedVideo>(&body)
+        .with_context(|| format!("Parsing upload response: {body}"))?;
+    debug!("Uploaded video: {video:?}");
+
+    match video.status.upload_status {
+        Some(UploadState::Failed) => bail!(
+            "YouTube failed to process video {}: {}",
+            video.id,
+            video
+                .status
+                .failure_reason
+                .as_deref()
+                .unwrap_or("Unknown reason")
+        ),
+        Some(UploadState::Rejected) => bail!(
+            "YouTube rejected video {}: {}",
+            video.id,
+            video
+                .status
+                .rejection_reason
+                .as_deref()
+                .unwrap_or("Unknown reason")
+        ),
+        Some(UploadState::Deleted) => bail!("Video {} has been deleted", video.id),
+        _ => Ok(video),
+    }
+}
+
+/// Starts a resumable upload session, returning the session's upload URL
+async fn init_upload(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    video_detail: VideoDetail<'_>,
+    content_length: Option<u64>,
+) -> Result<String> {
+    // Streamed uploads start right away, possibly before the first access token is generated
+    access_token
+        .clone()
+        .wait_for(Option::is_some)
+        .await
+        .context("Waiting for Google access token")?;
+
+    let mut init_upload_req = client
+        .post("https://www.googleapis.com/upload/youtube/v3/videos")
+        .header(AUTHORIZATION, bearer(access_token)?)
+        .header("X-Upload-Content-Type", "video/*")
+        .query(&[("uploadType", "resumable"), ("part", "snippet,status")]);
+    if let Some(content_length) = content_length {
+        init_upload_req = init_upload_req.header("X-Upload-Content-Length", content_length);
+    }
+
+    let resource = json!({
+        "snippet": video_detail.snippet(),
+        "status": video_detail.status(),
+    });
+
+    let init_upload_req = init_upload_req
+        .json(&resource)
+        .send()
+        .await
+        .context("Initializing upload")?;
+
+    if !init_upload_req.status().is_success() {
+        error!(
+            "Unable to initialize YouTube upload. Status {}",
+            init_upload_req.status()
+        );
+        let body = init_upload_req.text().await.unwrap_or_default();
+        error!(body);
+        bail!("Unable to initialize YouTube upload");
+    }
+
+    Ok(init_upload_req
+        .headers()
+        .get(LOCATION)
+        .context("YouTube did not return an upload URL")?
+        .to_str()
+        .context("Decoding upload URL")?
+        .to_string())
+}
+
+/// Uploads `video` in chunks into a resumable upload session, finalizing the upload once
+/// `video` reaches EOF
+///
+/// `video` must already be positioned at `start` bytes into the video. Returns the response of
+/// the final chunk, or `None` when cancelled.
+#[allow(clippy::too_many_arguments)]
+async fn upload_chunks<R>(
+    ct: &CancellationToken,
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    upload_url: &str,
+    mut video: R,
+    start: u64,
+    content_length: Option<u64>,
+    options: &UploadOptions,
+    pb: &indicatif::ProgressBar,
+) -> Result<Option<Response>>
+where
+    R: AsyncRead + Unpin,
+{
+    let retry_policy = &options.retry_policy;
+    let mut buf = vec![0; options.chunk_size];
+    let mut offset = start;
+
+    loop {
+        let filled = select! {
+            () = ct.cancelled() => return Ok(None),
+            filled = read_full(&mut video, &mut buf) => filled.context("Reading video data")?,
+        };
+
+        let chunk = Bytes::copy_from_slice(&buf[..filled]);
+        let chunk_end = offset + filled as u64;
+        // A partially filled chunk means that the video has ended
+        let is_last = filled < buf.len() || content_length == Some(chunk_end);
+        let total = if is_last {
+            Some(chunk_end)
+        } else {
+            content_length
+        };
+
+        // First byte of the chunk YouTube has not received, `None` when it has to be queried
+        // after a failed request
+        let mut position = Some(offset);
+        let mut attempt = 0;
+        loop {
+            let piece_start = position.unwrap_or(chunk_end);
+            #[allow(clippy::cast_possible_truncation)]
+            let piece = chunk.slice((piece_start - offset) as usize..);
+            let content_range = content_range(piece_start, chunk_end, total);
+            debug!("Uploading chunk {content_range}");
+
+            let res = select! {
+                () = ct.cancelled() => return Ok(None),
+                res = send_chunk(client, access_token, upload_url, content_range, piece, &options.rate_limiter) => res,
+            };
+
+            match res {
+                Ok(UploadStatus::Complete(res)) => {
+                    pb.set_position(chunk_end);
+                    return Ok(Some(res));
+                }
+                Ok(UploadStatus::Incomplete(acknowledged)) => {
+                    if acknowledged < offset {
+                        bail!(
+                            "YouTube lost already uploaded data (acknowledged {acknowledged} of {offset} bytes)"
+                        );
+                    }
+                    pb.set_position(acknowledged);
+
+                    if acknowledged >= chunk_end && !is_last {
+                        break;
+                    }
+                    // Some of the chunk was not received, only the rest of it is sent again
+                    if position.is_some_and(|p| acknowledged <= p) {
+                        attempt += 1;
+                        if attempt > retry_policy.max_retries {
+                            bail!("YouTube is not accepting the uploaded video chunk");
+                        }
+                    }
+                    position = Some(acknowledged);
+                }
+                Err(e) if e.is_retriable() && attempt < retry_policy.max_retries => {
+                    attempt += 1;
+                    let delay = retry_policy.backoff(attempt);
+                    warn!(
+                        "Uploading video chunk failed, retrying in {delay:.1?} ({attempt}/{}): {e}",
+                        retry_policy.max_retries
+                    );
+                    position = None;
+
+                    select! {
+                        () = ct.cancelled() => return Ok(None),
+                        () = tokio::time::sleep(delay) => {}
+                    }
+                }
+                Err(e) => return Err(anyhow!("Uploading video chunk failed: {e}")),
+            }
+        }
+
+        offset = chunk_end;
+    }
+}
+
+/// Asks YouTube how much of the video it has received
+async fn query_status(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    upload_url: &str,
+    content_length: Option<u64>,
+) -> Result<UploadStatus, SegmentError> {
+    send_chunk(
+        client,
+        access_token,
+        upload_url,
+        content_range(0, 0, content_length),
+        Bytes::new(),
+        &Arc::default(),
+    )
+    .await
+}
+
+async fn send_chunk(
+    client: &Client,
+    access_token: &watch::Receiver<Option<Box<str>>>,
+    upload_url: &str,
+    content_range: String,
+    chunk: Bytes,
+    rate_limiter: &Arc<RateLimiter>,
+) -> Result<UploadStatus, SegmentError> {
+    // Split the chunk so that the rate limit is applied smoothly throughout the request
+    let length = chunk.len();
+    let pieces = (0..length)
+        .step_by(RATE_LIMIT_PIECE_SIZE)
+        .map(|start| {
+            Ok::<_, std::io::Error>(chunk.slice(start..(start + RATE_LIMIT_PIECE_SIZE).min(length)))
+        })
+        .collect::<Vec<_>>();
+
+    let res = client
+        .put(upload_url)
+        .header(
+            AUTHORIZATION,
+            bearer(access_token).map_err(SegmentError::Fatal)?,
+        )
+        .header(CONTENT_RANGE, content_range)
+        .header(CONTENT_LENGTH, length)
+        .body(Body::wrap_stream(
+            rate_limiter.throttle(stream::iter(pieces)),
+        ))
+        .send()
+        .await
+        .map_err(|e| SegmentError::from_reqwest(e, "Uploading video chunk"))?;
+
+    let status = res.status();
+    if status.is_success() {
+        return Ok(UploadStatus::Complete(res));
+    }
+
+    // YouTube responds with `308 Resume Incomplete` until the last chunk is received
+    if status == StatusCode::PERMANENT_REDIRECT {
+        let range = res.headers().get(RANGE).and_then(|r| r.to_str().ok());
+        return Ok(UploadStatus::Incomplete(acknowledged_length(range)));
+    }
+
+    let body = res.text().await.unwrap_or_default();
+    let error = anyhow!("YouTube responded with {status}: {body}");
+    // An expired access token will be refreshed in the background
+    if is_retriable_status(status) || status == StatusCode::UNAUTHORIZED {
+        Err(SegmentError::Retriable(error))
+    } else if status == StatusCode::NOT_FOUND {
+        Err(SegmentError::Fatal(
+            error.context("Upload session has expired"),
+        ))
+    } else {
+        Err(SegmentError::Fatal(error))
+    }
+}
+
+/// Formats the `Content-Range` of the bytes from `start` up to `end` (exclusive) of a video
+/// `total` bytes long, `None` when the length is not known yet
+///
+/// An empty range only asks YouTube for the upload status
+fn content_range(start: u64, end: u64, total: Option<u64>) -> String {
+    let total = total.map_or_else(|| "*".to_string(), |t| t.to_string());
+    if start == end {
+        format!("bytes */{total}")
+    } else {
+        format!("bytes {start}-{}/{total}", end - 1)
+    }
+}
+
+/// Reads the amount of bytes YouTube has received from the `Range` header of a
+/// `308 Resume Incomplete` response
+///
+/// e.g. `bytes=0-1048575`, missing when nothing has been received yet
+fn acknowledged_length(range: Option<&str>) -> u64 {
+    range
+        .and_then(|r| r.strip_prefix("bytes=0-"))
+        .and_then(|end| end.parse::<u64>().ok())
+        .map_or(0, |end| end + 1)
+}
+
+pub(super) fn bearer(access_token: &watch::Receiver<Option<Box<str>>>) -> Result<String> {
+    access_token
+        .borrow()
+        .as_deref()
+        .map(|t| format!("Bearer {t}"))
+        .context("Google access token is not available yet")
+}
+
+fn progress_bar(content_length: Option<u64>) -> indicatif::ProgressBar {
+    let pb = content_length.map_or_else(
+        indicatif::ProgressBar::no_length,
+        indicatif::ProgressBar::new,
+    );
+    pb.set_style(
+        indicatif::ProgressStyle::with_template(
+            "[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({decimal_bytes_per_sec} {eta} left)",
+        )
+        .unwrap(),
+    );
+    pb
+}
+
+fn session_file_path(video_path: &Path) -> PathBuf {
+    let mut file_name = video_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".upload.json");
+    video_path.with_file_name(file_name)
+}
+
+impl UploadSession {
+    /// Whether the session belongs to the same video file and has not expired yet
+    fn is_resumable(&self, content_length: u64, modified: SystemTime) -> bool {
+        self.content_length == content_length
+            && self.modified == modified
+            && Utc::now() - self.created_at < UPLOAD_SESSION_LIFETIME
+    }
+
+    async fn load(path: &Path) -> Option<Self> {
+        let data = match tokio::fs::read(path).await {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Unable to read the previous upload session: {e}");
+                return None;
+            }
+        };
+
+        serde_json::from_slice(&data)
+            .inspect_err(|e| warn!("Previous upload session is corrupted, ignoring it: {e}"))
+            .ok()
+    }
+
+    async fn save(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec(self).context("Serializing upload session")?;
+        tokio::fs::write(path, data)
+            .await
+            .context("Writing upload session")
+    }
+
+    async fn remove(path: &Path) {
+        if let Err(e) = tokio::fs::remove_file(path).await
+            && e.kind() != ErrorKind::NotFound
+        {
+            warn!("Unable to remove the finished upload session: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir;
+
+    #[test]
+    fn formats_content_range() {
+        assert_eq!(
+            content_range(0, 262_144, Some(1_000_000)),
+            "bytes 0-262143/1000000"
+        );
+        assert_eq!(
+            content_range(262_144, 524_288, None),
+            "bytes 262144-524287/*"
+        );
+        assert_eq!(
+            content_range(524_288, 600_000, Some(600_000)),
+            "bytes 524288-599999/600000"
+        );
+        assert_eq!(content_range(0, 0, Some(1_000_000)), "bytes */1000000");
+        assert_eq!(content_range(600_000, 600_000, None), "bytes */*");
+    }
+
+    #[test]
+    fn parses_acknowledged_range() {
+        assert_eq!(acknowledged_length(Some("bytes=0-1048575")), 1_048_576);
+        assert_eq!(acknowledged_length(Some("bytes=0-0")), 1);
+        assert_eq!(acknowledged_length(None), 0);
+        assert_eq!(acknowledged_length(Some("bytes=0-")), 0);
+        assert_eq!(acknowledged_length(Some("garbage")), 0);
+    }
+
+    #[test]
+    fn stores_session_next_to_the_video() {
+        assert_eq!(
+            session_file_path(Path::new("/videos/123.mp4")),
+            Path::new("/videos/123.mp4.upload.json")
+        );
+    }
+
+    #[tokio::test]
+    async fn saves_and_loads_upload_session() {
+        let dir = test_dir("upload-session");
+        let path = session_file_path(&dir.join("123.mp4"));
+        assert!(UploadSession::load(&path).await.is_none());
+
+        let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        UploadSession {
+            upload_url: "https://upload/session".to_string(),
+            content_length: 1_000_000,
+            modified,
+            created_at: Utc::now(),
+        }
+        .save(&path)
+        .await
+        .unwrap();
+
+        let loaded = UploadSession::load(&path).await.unwrap();
+        assert_eq!(loaded.upload_url, "https://upload/session");
+        assert!(loaded.is_resumable(1_000_000, modified));
+        assert!(!loaded.is_resumable(999_999, modified));
+        assert!(!loaded.is_resumable(1_000_000, SystemTime::UNIX_EPOCH));
+
+        UploadSession::remove(&path).await;
+        assert!(!path.exists());
+        UploadSession::remove(&path).await;
+    }
+
+    #[test]
+    fn expires_old_sessions() {
+        let session = UploadSession {
+            upload_url: "https://upload/session".to_string(),
+            content_length: 1,
+            modified: SystemTime::UNIX_EPOCH,
+            created_at: Utc::now() - TimeDelta::days(7),
+        };
+        assert!(!session.is_resumable(1, SystemTime::UNIX_EPOCH));
+    }
+
+    #[tokio::test]
+    async fn ignores_corrupted_upload_session() {
+        let dir = test_dir("upload-session-corrupted");
+        let path = dir.join("123.mp4.upload.json");
+        std::fs::write(&path, "{\"upload_url\": \"https://up").unwrap();
+
+        assert!(UploadSession::load(&path).await.is_none());
+    }
+}